@@ -0,0 +1,451 @@
+use crate::object::Object;
+use crate::parameters::Parameters;
+use crate::symbol::{self, Symbol};
+
+/// A variable reference resolved ahead of time: slot `index` in the frame
+/// `depth` environments up from the one it is evaluated in. The name is
+/// kept for error messages, and for looking the variable up the slow way
+/// while its slot is still empty, e.g. an internal definition that has not
+/// run yet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Local {
+    pub depth: usize,
+    pub index: usize,
+    pub name: Symbol,
+}
+
+/// A reference to a variable that none of the `depth` environments the
+/// evaluator creates around it binds, such as a global or a builtin. Looking
+/// it up by name starts past them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Global {
+    pub depth: usize,
+    pub name: Symbol,
+}
+
+/// An environment that the evaluator will create around a piece of code.
+/// Lambda applications store their variables in slots; the environments of
+/// `guard` clauses, restarts and `shift` bodies are looked up by name, but
+/// still count towards the depth of references that reach past them.
+struct Scope {
+    names: Vec<Symbol>,
+    slots: bool,
+}
+
+/// The slots of a lambda's frame: its parameters in order, then the names
+/// its defaults and body define.
+pub fn frame_layout(parameters: &Parameters, body: &[Object]) -> Vec<Symbol> {
+    let mut names = parameters.required.clone();
+    names.extend(parameters.optional.iter().map(|(name, _)| *name));
+    names.extend(parameters.keys.iter().map(|(name, _)| *name));
+    let defaults = parameters.optional.iter().chain(parameters.keys.iter());
+    for exp in defaults.map(|(_, default)| default).chain(body) {
+        scan_definitions(exp, &mut names);
+    }
+    names
+}
+
+/// Collects the names that `exp` defines in the environment it is
+/// evaluated in, skipping the parts that run in an environment of their own.
+fn scan_definitions(exp: &Object, names: &mut Vec<Symbol>) {
+    let items = match exp {
+        Object::List(items) => items,
+        _ => return,
+    };
+
+    let rest = match items.first() {
+        Some(Object::Symbol(form)) => match *form {
+            symbol::QUOTE | symbol::LAMBDA | symbol::SHIFT => return,
+            symbol::DEFINE => {
+                if let Some(Object::Symbol(name)) = items.get(1) {
+                    if !names.contains(name) {
+                        names.push(*name);
+                    }
+                }
+                items.get(2..).unwrap_or(&[])
+            }
+            symbol::GUARD => items.get(2..).unwrap_or(&[]),
+            symbol::RESTART_CASE => items.get(1..2).unwrap_or(&[]),
+            _ => &items[..],
+        },
+        _ => &items[..],
+    };
+    for item in rest {
+        scan_definitions(item, names);
+    }
+}
+
+/// Resolves references to lambda parameters and internal definitions in
+/// `exp` to `Object::Local`s, so that evaluating them indexes into a frame
+/// instead of searching the environment chain by name, and references to
+/// globals within a lambda to `Object::Global`s, so that they skip its
+/// frames. Everything else is still looked up by name.
+pub fn analyze(exp: Object) -> Object {
+    match exp {
+        Object::Symbol(_) | Object::List(_) => resolve(&exp, &mut Vec::new()),
+        other => other,
+    }
+}
+
+fn resolve(exp: &Object, scopes: &mut Vec<Scope>) -> Object {
+    match exp {
+        Object::Symbol(name) => resolve_symbol(*name, scopes),
+        Object::List(items) => resolve_list(items, scopes),
+        other => other.clone(),
+    }
+}
+
+fn resolve_symbol(name: Symbol, scopes: &[Scope]) -> Object {
+    for (depth, scope) in scopes.iter().rev().enumerate() {
+        if let Some(index) = scope.names.iter().position(|n| *n == name) {
+            if scope.slots {
+                return Object::Local(Local { depth, index, name });
+            }
+            return Object::Symbol(name);
+        }
+    }
+    match scopes.len() {
+        0 => Object::Symbol(name),
+        depth => Object::Global(Global { depth, name }),
+    }
+}
+
+/// Keeps the first `keep` items as they are and resolves the rest.
+fn resolve_after(items: &[Object], keep: usize, scopes: &mut Vec<Scope>) -> Object {
+    let keep = keep.min(items.len());
+    let mut resolved = items[..keep].to_vec();
+    resolved.extend(items[keep..].iter().map(|item| resolve(item, scopes)));
+    Object::List(resolved)
+}
+
+fn resolve_list(items: &[Object], scopes: &mut Vec<Scope>) -> Object {
+    let form = match items.first() {
+        Some(Object::Symbol(form)) => *form,
+        _ => return resolve_after(items, 0, scopes),
+    };
+
+    match form {
+        symbol::QUOTE => Object::List(items.to_vec()),
+        symbol::DEFINE | symbol::SET => resolve_after(items, 2, scopes),
+        symbol::LAMBDA => resolve_lambda(items, scopes),
+        symbol::GUARD => resolve_guard(items, scopes),
+        symbol::HANDLER_BIND => resolve_handler_bind(items, scopes),
+        symbol::RESTART_CASE => resolve_restart_case(items, scopes),
+        symbol::SHIFT => resolve_shift(items, scopes),
+        symbol::IF
+        | symbol::BEGIN
+        | symbol::RESET
+        | symbol::DELAY
+        | symbol::DELAY_FORCE
+        | symbol::STREAM_CONS => resolve_after(items, 1, scopes),
+        _ => resolve_after(items, 0, scopes),
+    }
+}
+
+/// Resolves `body` in a new scope binding `names` plus whatever the body
+/// defines.
+fn resolve_in_scope(
+    body: &[Object],
+    mut names: Vec<Symbol>,
+    slots: bool,
+    scopes: &mut Vec<Scope>,
+) -> Vec<Object> {
+    for exp in body {
+        scan_definitions(exp, &mut names);
+    }
+    scopes.push(Scope { names, slots });
+    let resolved = body.iter().map(|exp| resolve(exp, scopes)).collect();
+    scopes.pop();
+    resolved
+}
+
+/// `(lambda params body...)`. Defaults are evaluated in the new frame, so
+/// they are resolved along with the body. Malformed lambdas are left for
+/// the evaluator to report.
+fn resolve_lambda(items: &[Object], scopes: &mut Vec<Scope>) -> Object {
+    let list = match items.get(1) {
+        Some(Object::List(list)) => list,
+        _ => return Object::List(items.to_vec()),
+    };
+    let parameters = match Parameters::parse(list) {
+        Ok(parameters) => parameters,
+        Err(_) => return Object::List(items.to_vec()),
+    };
+
+    scopes.push(Scope {
+        names: frame_layout(&parameters, &items[2..]),
+        slots: true,
+    });
+    let list = list
+        .iter()
+        .map(|p| match p {
+            Object::List(pair) => Object::List(vec![pair[0].clone(), resolve(&pair[1], scopes)]),
+            other => other.clone(),
+        })
+        .collect();
+    let mut resolved = vec![items[0].clone(), Object::List(list)];
+    resolved.extend(items[2..].iter().map(|exp| resolve(exp, scopes)));
+    scopes.pop();
+    Object::List(resolved)
+}
+
+/// `(guard (var clause...) body...)`. The clauses run in an environment
+/// binding `var`; `else` and `=>` are syntax, not references.
+fn resolve_guard(items: &[Object], scopes: &mut Vec<Scope>) -> Object {
+    let (var, clauses) = match items.get(1) {
+        Some(Object::List(spec)) => match spec.split_first() {
+            Some((Object::Symbol(var), clauses)) => (*var, clauses),
+            _ => return Object::List(items.to_vec()),
+        },
+        _ => return Object::List(items.to_vec()),
+    };
+
+    let mut names = vec![var];
+    for clause in clauses {
+        scan_definitions(clause, &mut names);
+    }
+    scopes.push(Scope {
+        names,
+        slots: false,
+    });
+    let mut spec = vec![Object::Symbol(var)];
+    for clause in clauses {
+        spec.push(match clause {
+            Object::List(clause) => Object::List(
+                clause
+                    .iter()
+                    .enumerate()
+                    .map(|(i, exp)| match (i, exp) {
+                        (0, Object::Symbol(symbol::ELSE)) | (1, Object::Symbol(symbol::ARROW)) => {
+                            exp.clone()
+                        }
+                        _ => resolve(exp, scopes),
+                    })
+                    .collect(),
+            ),
+            other => other.clone(),
+        });
+    }
+    scopes.pop();
+
+    let mut resolved = vec![items[0].clone(), Object::List(spec)];
+    resolved.extend(items[2..].iter().map(|exp| resolve(exp, scopes)));
+    Object::List(resolved)
+}
+
+/// `(handler-bind ((type handler)...) body...)`
+fn resolve_handler_bind(items: &[Object], scopes: &mut Vec<Scope>) -> Object {
+    let bindings = match items.get(1) {
+        Some(Object::List(bindings)) => bindings,
+        _ => return Object::List(items.to_vec()),
+    };
+
+    let bindings = bindings
+        .iter()
+        .map(|binding| match binding {
+            Object::List(binding) => resolve_after(binding, 1, scopes),
+            other => other.clone(),
+        })
+        .collect();
+    let mut resolved = vec![items[0].clone(), Object::List(bindings)];
+    resolved.extend(items[2..].iter().map(|exp| resolve(exp, scopes)));
+    Object::List(resolved)
+}
+
+/// `(restart-case expr (name (params...) body...)...)`. Each body runs in
+/// an environment binding its parameters.
+fn resolve_restart_case(items: &[Object], scopes: &mut Vec<Scope>) -> Object {
+    let mut resolved = vec![items[0].clone()];
+    if let Some(expr) = items.get(1) {
+        resolved.push(resolve(expr, scopes));
+    }
+
+    for clause in items.iter().skip(2) {
+        let (clause, parameters) = match clause {
+            Object::List(clause) if clause.len() >= 2 => match &clause[1] {
+                Object::List(parameters) => (clause, parameters),
+                _ => return Object::List(items.to_vec()),
+            },
+            _ => return Object::List(items.to_vec()),
+        };
+        let names = parameters
+            .iter()
+            .filter_map(|p| match p {
+                Object::Symbol(p) => Some(*p),
+                _ => None,
+            })
+            .collect();
+
+        let mut resolved_clause = clause[..2].to_vec();
+        resolved_clause.extend(resolve_in_scope(&clause[2..], names, false, scopes));
+        resolved.push(Object::List(resolved_clause));
+    }
+    Object::List(resolved)
+}
+
+/// `(shift k body...)`. The body runs in an environment binding `k`.
+fn resolve_shift(items: &[Object], scopes: &mut Vec<Scope>) -> Object {
+    let k = match items.get(1) {
+        Some(Object::Symbol(k)) => *k,
+        _ => return Object::List(items.to_vec()),
+    };
+
+    let mut resolved = items[..2].to_vec();
+    resolved.extend(resolve_in_scope(&items[2..], vec![k], false, scopes));
+    Object::List(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::{eval, eval_dynamic};
+    use crate::object::Environment;
+    use crate::reader;
+
+    fn analyze_str(code: &str) -> Object {
+        analyze(reader::read(code).unwrap().remove(0))
+    }
+
+    fn local(depth: usize, index: usize, name: &str) -> Object {
+        Object::Local(Local {
+            depth,
+            index,
+            name: Symbol::intern(name),
+        })
+    }
+
+    fn global(depth: usize, name: &str) -> Object {
+        Object::Global(Global {
+            depth,
+            name: Symbol::intern(name),
+        })
+    }
+
+    #[test]
+    fn test_resolves_parameters_and_definitions() {
+        let lambda = analyze_str("(lambda (a) (define b a) (lambda (c) (list a b c d)))");
+        let inner = match &lambda {
+            Object::List(items) => items[3].clone(),
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            inner,
+            Object::List(vec![
+                Object::symbol("lambda"),
+                Object::List(vec![Object::symbol("c")]),
+                Object::List(vec![
+                    global(2, "list"),
+                    local(1, 0, "a"),
+                    local(1, 1, "b"),
+                    local(0, 0, "c"),
+                    global(2, "d"),
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_scopes_looked_up_by_name() {
+        let lambda = analyze_str("(lambda (e x) (guard (e (else (list e x))) x))");
+        let guard = match &lambda {
+            Object::List(items) => items[2].clone(),
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            guard,
+            Object::List(vec![
+                Object::symbol("guard"),
+                Object::List(vec![
+                    Object::symbol("e"),
+                    Object::List(vec![
+                        Object::symbol("else"),
+                        Object::List(vec![
+                            global(2, "list"),
+                            Object::symbol("e"),
+                            local(1, 1, "x"),
+                        ]),
+                    ]),
+                ]),
+                local(0, 1, "x"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_quoted_data_is_untouched() {
+        let lambda = analyze_str("(lambda (a) '(a b))");
+        assert_eq!(
+            lambda,
+            Object::List(vec![
+                Object::symbol("lambda"),
+                Object::List(vec![Object::symbol("a")]),
+                Object::List(vec![
+                    Object::symbol("quote"),
+                    Object::List(vec![Object::symbol("a"), Object::symbol("b")]),
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_globals_are_looked_up_where_the_code_runs() {
+        let global = Environment::new();
+        let child = Environment::new_child(global.clone());
+        let code = "(define x 'child) (define f (lambda () (lambda () x))) ((f))";
+        let mut result = Ok(Object::Nil);
+        for exp in reader::read(code).unwrap() {
+            result = eval(exp, child.clone());
+        }
+        assert_eq!(result, Ok(Object::symbol("child")));
+
+        let redefined = reader::read("(define x 'again)").unwrap().remove(0);
+        eval(redefined, global.clone()).unwrap();
+        let call = reader::read("((f))").unwrap().remove(0);
+        assert_eq!(eval(call, child), Ok(Object::symbol("child")));
+    }
+
+    #[test]
+    fn test_same_results_as_dynamic_lookup() {
+        let programs = [
+            "(define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))
+             (fib 15)",
+            "(define f (lambda (x) (define g (lambda () y)) (define y (+ x 1)) (g)))
+             (f 1)",
+            "(define y 'global)
+             (define f (lambda () (define g (lambda () y)) (g)))
+             (f)",
+            "(define f (lambda (x &optional (y (+ x 1)) &key (z (* y 2))) (list x y z)))
+             (list (f 1) (f 1 2) (f 1 2 :z 0))",
+            "(define f (lambda (x)
+               (guard (e (#t (define x (list 'caught e)) x))
+                 (raise x))))
+             (f 1)",
+            "(define f (lambda (x) (restart-case (invoke-restart 'use-value x) (use-value (v) (list v x)))))
+             (f 3)",
+            "(define f (lambda (x) (reset (+ 1 (shift k (k (k x)))))))
+             (f 5)",
+            "(define make-counter (lambda () (define n 0) (lambda () (set! n (+ n 1)) n)))
+             (define c (make-counter))
+             (c) (c)",
+            "(define f (lambda (x) (delay (+ x 1))))
+             (force (f 41))",
+            "(define f (lambda (x) (g x)))
+             (f 1)",
+            "(define f (lambda (&optional (x (begin (define y 1) (+ y 1)))) (list x y)))
+             (f)",
+            "(define y 'global)
+             (define f (lambda (x) (guard (e (#t (define y x) (list y e))) (raise 'oops))))
+             (list (f 1) y)",
+        ];
+
+        for program in programs.iter() {
+            let (resolved, dynamic) = (Environment::new(), Environment::new());
+            let (mut a, mut b) = (Ok(Object::Nil), Ok(Object::Nil));
+            for exp in reader::read(program).unwrap() {
+                a = eval(exp.clone(), resolved.clone());
+                b = eval_dynamic(exp, dynamic.clone());
+            }
+            assert_eq!(a, b, "{}", program);
+        }
+    }
+}
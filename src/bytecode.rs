@@ -0,0 +1,698 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::analysis;
+use crate::error::Span;
+use crate::object::Object;
+use crate::parameters::Parameters;
+use crate::reader::SpanTree;
+use crate::symbol::{self, Symbol};
+
+/// The instructions of the VM. Each is one byte, followed by its operands:
+/// a byte for the argument count of calls and two bytes, little-endian, for
+/// everything else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Op {
+    /// Pushes `constants[i]`.
+    Constant,
+    /// Pushes the global named by the symbol `constants[i]`.
+    GetGlobal,
+    SetGlobal,
+    DefineGlobal,
+    /// Pushes local `i`, which is not shared with any closure.
+    GetLocal,
+    /// Pops a value into local `i`, or into its cell. Binds defaults.
+    BindLocal,
+    /// Moves local `i` into a cell that closures can share.
+    MakeCell,
+    GetCell,
+    SetCell,
+    DefineCell,
+    GetUpvalue,
+    SetUpvalue,
+    /// Pushes a closure of `protos[i]`, capturing its upvalues.
+    Closure,
+    Pop,
+    Jump,
+    JumpIfFalse,
+    /// Jumps if local `i` has a value: a default that need not be evaluated.
+    JumpIfBound,
+    Call,
+    /// A call whose value the caller returns. Calling a closure or a lambda
+    /// replaces the caller's frame; anything else is an ordinary call.
+    TailCall,
+    Return,
+}
+
+const OPS: [Op; 20] = [
+    Op::Constant,
+    Op::GetGlobal,
+    Op::SetGlobal,
+    Op::DefineGlobal,
+    Op::GetLocal,
+    Op::BindLocal,
+    Op::MakeCell,
+    Op::GetCell,
+    Op::SetCell,
+    Op::DefineCell,
+    Op::GetUpvalue,
+    Op::SetUpvalue,
+    Op::Closure,
+    Op::Pop,
+    Op::Jump,
+    Op::JumpIfFalse,
+    Op::JumpIfBound,
+    Op::Call,
+    Op::TailCall,
+    Op::Return,
+];
+
+impl Op {
+    pub fn from_byte(byte: u8) -> Option<Op> {
+        OPS.get(byte as usize).copied()
+    }
+
+    /// The sizes in bytes of the operands that follow the instruction.
+    pub fn operands(self) -> &'static [usize] {
+        match self {
+            Op::Pop | Op::Return => &[],
+            Op::Call | Op::TailCall => &[1],
+            Op::JumpIfBound => &[2, 2],
+            _ => &[2],
+        }
+    }
+
+    /// The name of the instruction in disassembly listings.
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Constant => "constant",
+            Op::GetGlobal => "get-global",
+            Op::SetGlobal => "set-global",
+            Op::DefineGlobal => "define-global",
+            Op::GetLocal => "get-local",
+            Op::BindLocal => "bind-local",
+            Op::MakeCell => "make-cell",
+            Op::GetCell => "get-cell",
+            Op::SetCell => "set-cell",
+            Op::DefineCell => "define-cell",
+            Op::GetUpvalue => "get-upvalue",
+            Op::SetUpvalue => "set-upvalue",
+            Op::Closure => "closure",
+            Op::Pop => "pop",
+            Op::Jump => "jump",
+            Op::JumpIfFalse => "jump-if-false",
+            Op::JumpIfBound => "jump-if-bound",
+            Op::Call => "call",
+            Op::TailCall => "tail-call",
+            Op::Return => "return",
+        }
+    }
+}
+
+/// Where a closure finds an upvalue when it is created: a boxed local of
+/// the function creating it, or one of that function's own upvalues.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capture {
+    Local(u16),
+    Upvalue(u16),
+}
+
+/// A compiled lambda, or a compiled top-level form, which takes no
+/// arguments. Every closure made from it shares it.
+#[derive(Debug, PartialEq)]
+pub struct Proto {
+    pub parameters: Parameters,
+    /// The parameters, then the internal definitions, laid out as in
+    /// `analysis::frame_layout`.
+    pub locals: Vec<Symbol>,
+    pub upvalues: Vec<(Symbol, Capture)>,
+    pub constants: Vec<Object>,
+    pub protos: Vec<Rc<Proto>>,
+    pub code: Vec<u8>,
+    /// Where in the source the instructions from each offset on were
+    /// compiled from, in order of offset. Empty when it is not known.
+    pub spans: Vec<(usize, Span)>,
+    pub toplevel: bool,
+}
+
+impl Proto {
+    fn new(parameters: Parameters, locals: Vec<Symbol>, toplevel: bool) -> Proto {
+        Proto {
+            parameters,
+            locals,
+            upvalues: Vec::new(),
+            constants: Vec::new(),
+            protos: Vec::new(),
+            code: Vec::new(),
+            spans: Vec::new(),
+            toplevel,
+        }
+    }
+
+    /// Where the instruction at `offset` was compiled from: the start of
+    /// the innermost list it belongs to.
+    pub fn span_at(&self, offset: usize) -> Option<Span> {
+        let run = self.spans.partition_point(|(start, _)| *start <= offset);
+        run.checked_sub(1).map(|run| self.spans[run].1)
+    }
+
+    /// The source line that the instruction at `offset` was compiled from.
+    pub fn line_at(&self, offset: usize) -> Option<usize> {
+        self.span_at(offset).map(|span| span.line)
+    }
+}
+
+/// Compiles a top-level form, or returns `None` if it uses a form that only
+/// the evaluator implements (`guard`, `handler-bind`, `restart-case`,
+/// `reset`, `shift`, `delay` and `stream-cons`) or is malformed, in which
+/// case the evaluator runs it and reports the error.
+pub fn compile(exp: &Object) -> Option<Rc<Proto>> {
+    compile_spans(exp, HashMap::new())
+}
+
+/// Like `compile`, but records where in the source each instruction comes
+/// from, given where `exp` and the lists within it were read.
+pub fn compile_with_spans(exp: &Object, spans: &SpanTree) -> Option<Rc<Proto>> {
+    let mut starts = HashMap::new();
+    index_spans(exp, spans, &mut starts);
+    compile_spans(exp, starts)
+}
+
+/// Maps each list in `exp` to where it starts, by the address of its items,
+/// which stays put while `exp` is borrowed.
+fn index_spans(exp: &Object, spans: &SpanTree, starts: &mut HashMap<*const Object, Span>) {
+    if let Object::List(items) = exp {
+        if !items.is_empty() {
+            starts.insert(items.as_ptr(), spans.span);
+        }
+        for (item, spans) in items.iter().zip(&spans.items) {
+            index_spans(item, spans, starts);
+        }
+    }
+}
+
+fn compile_spans(exp: &Object, starts: HashMap<*const Object, Span>) -> Option<Rc<Proto>> {
+    let mut compiler = Compiler {
+        functions: vec![Function {
+            proto: Proto::new(Parameters::default(), Vec::new(), true),
+            boxed: Vec::new(),
+        }],
+        starts,
+        span: None,
+    };
+    compiler.compile(exp, true)?;
+    compiler.emit(Op::Return);
+    compiler
+        .functions
+        .pop()
+        .map(|function| Rc::new(function.proto))
+}
+
+/// A function being compiled. Its locals that closures capture or that are
+/// assigned to are boxed in cells, so that every closure sees the same
+/// variable; internal definitions are boxed because they start out empty.
+struct Function {
+    proto: Proto,
+    boxed: Vec<bool>,
+}
+
+enum Variable {
+    Global,
+    Local(u16, bool),
+    Upvalue(u16),
+}
+
+struct Compiler {
+    /// The function being compiled and the ones enclosing it, outermost
+    /// (the top-level form) first.
+    functions: Vec<Function>,
+    starts: HashMap<*const Object, Span>,
+    /// Where the innermost list being compiled starts, if known.
+    span: Option<Span>,
+}
+
+impl Compiler {
+    fn current(&mut self) -> &mut Function {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op) {
+        let span = self.span;
+        let proto = &mut self.current().proto;
+        if let Some(span) = span {
+            if proto.spans.last().map(|(_, last)| *last) != Some(span) {
+                proto.spans.push((proto.code.len(), span));
+            }
+        }
+        proto.code.push(op as u8);
+    }
+
+    fn emit_with(&mut self, op: Op, operand: usize) -> Option<()> {
+        let operand = u16::try_from(operand).ok()?;
+        self.emit(op);
+        self.current()
+            .proto
+            .code
+            .extend_from_slice(&operand.to_le_bytes());
+        Some(())
+    }
+
+    /// Emits a jump to be patched later, returning where its target goes.
+    fn emit_jump(&mut self, op: Op) -> usize {
+        self.emit(op);
+        self.emit_operand()
+    }
+
+    /// Emits a placeholder operand for `patch` to fill in.
+    fn emit_operand(&mut self) -> usize {
+        let code = &mut self.current().proto.code;
+        code.extend_from_slice(&[0, 0]);
+        code.len() - 2
+    }
+
+    /// Points the jump whose target is at `at` to the next instruction.
+    fn patch(&mut self, at: usize) -> Option<()> {
+        let code = &mut self.current().proto.code;
+        let target = u16::try_from(code.len()).ok()?;
+        code[at..at + 2].copy_from_slice(&target.to_le_bytes());
+        Some(())
+    }
+
+    fn constant(&mut self, value: Object) -> usize {
+        let constants = &mut self.current().proto.constants;
+        if let Object::Symbol(_) = value {
+            if let Some(index) = constants.iter().position(|c| *c == value) {
+                return index;
+            }
+        }
+        constants.push(value);
+        constants.len() - 1
+    }
+
+    /// Finds `name` in the function at `level`, capturing it as an upvalue
+    /// of every function in between.
+    fn resolve(&mut self, level: usize, name: Symbol) -> Variable {
+        let function = &self.functions[level];
+        if let Some(index) = function.proto.locals.iter().position(|n| *n == name) {
+            return Variable::Local(index as u16, function.boxed[index]);
+        }
+        if level == 0 {
+            return Variable::Global;
+        }
+
+        let capture = match self.resolve(level - 1, name) {
+            Variable::Global => return Variable::Global,
+            Variable::Local(index, _) => Capture::Local(index),
+            Variable::Upvalue(index) => Capture::Upvalue(index),
+        };
+        let upvalues = &mut self.functions[level].proto.upvalues;
+        let index = match upvalues.iter().position(|(_, c)| *c == capture) {
+            Some(index) => index,
+            None => {
+                upvalues.push((name, capture));
+                upvalues.len() - 1
+            }
+        };
+        Variable::Upvalue(index as u16)
+    }
+
+    fn resolve_current(&mut self, name: Symbol) -> Variable {
+        self.resolve(self.functions.len() - 1, name)
+    }
+
+    fn compile(&mut self, exp: &Object, tail: bool) -> Option<()> {
+        match exp {
+            Object::Symbol(name) => self.compile_variable(*name),
+            Object::List(items) => self.compile_list(items, tail),
+            Object::Local(_) | Object::Global(_) => None,
+            other => {
+                let index = self.constant(other.clone());
+                self.emit_with(Op::Constant, index)
+            }
+        }
+    }
+
+    fn compile_variable(&mut self, name: Symbol) -> Option<()> {
+        match self.resolve_current(name) {
+            Variable::Global => {
+                let index = self.constant(Object::Symbol(name));
+                self.emit_with(Op::GetGlobal, index)
+            }
+            Variable::Local(index, false) => self.emit_with(Op::GetLocal, index as usize),
+            Variable::Local(index, true) => self.emit_with(Op::GetCell, index as usize),
+            Variable::Upvalue(index) => self.emit_with(Op::GetUpvalue, index as usize),
+        }
+    }
+
+    fn compile_list(&mut self, items: &[Object], tail: bool) -> Option<()> {
+        let enclosing = self.span;
+        if let Some(&span) = self.starts.get(&items.as_ptr()) {
+            self.span = Some(span);
+        }
+        let compiled = self.compile_form(items, tail);
+        self.span = enclosing;
+        compiled
+    }
+
+    fn compile_form(&mut self, items: &[Object], tail: bool) -> Option<()> {
+        let form = match items.first()? {
+            Object::Symbol(form) => *form,
+            _ => return self.compile_call(items, tail),
+        };
+
+        match form {
+            symbol::QUOTE if items.len() == 2 => {
+                let index = self.constant(items[1].clone());
+                self.emit_with(Op::Constant, index)
+            }
+            symbol::DEFINE => self.compile_define(items),
+            symbol::SET => self.compile_set(items),
+            symbol::LAMBDA => self.compile_lambda(items),
+            symbol::IF => self.compile_if(items, tail),
+            symbol::BEGIN => self.compile_body(&items[1..], tail),
+            symbol::QUOTE
+            | symbol::GUARD
+            | symbol::HANDLER_BIND
+            | symbol::RESTART_CASE
+            | symbol::RESET
+            | symbol::SHIFT
+            | symbol::DELAY
+            | symbol::DELAY_FORCE
+            | symbol::STREAM_CONS => None,
+            _ => self.compile_call(items, tail),
+        }
+    }
+
+    fn compile_body(&mut self, body: &[Object], tail: bool) -> Option<()> {
+        if body.is_empty() {
+            let index = self.constant(Object::Nil);
+            return self.emit_with(Op::Constant, index);
+        }
+
+        for (i, exp) in body.iter().enumerate() {
+            let last = i + 1 == body.len();
+            self.compile(exp, tail && last)?;
+            if !last {
+                self.emit(Op::Pop);
+            }
+        }
+        Some(())
+    }
+
+    fn compile_define(&mut self, items: &[Object]) -> Option<()> {
+        let name = match items {
+            [_, Object::Symbol(name), _] => *name,
+            _ => return None,
+        };
+        self.compile(&items[2], false)?;
+
+        if self.functions.len() == 1 {
+            let index = self.constant(Object::Symbol(name));
+            return self.emit_with(Op::DefineGlobal, index);
+        }
+        // Every name a body defines has a slot, and is boxed.
+        let index = self
+            .current()
+            .proto
+            .locals
+            .iter()
+            .position(|n| *n == name)?;
+        self.emit_with(Op::DefineCell, index)
+    }
+
+    fn compile_set(&mut self, items: &[Object]) -> Option<()> {
+        let name = match items {
+            [_, Object::Symbol(name), _] => *name,
+            _ => return None,
+        };
+        self.compile(&items[2], false)?;
+
+        match self.resolve_current(name) {
+            Variable::Global => {
+                let index = self.constant(Object::Symbol(name));
+                self.emit_with(Op::SetGlobal, index)
+            }
+            Variable::Local(index, true) => self.emit_with(Op::SetCell, index as usize),
+            Variable::Local(_, false) => None,
+            Variable::Upvalue(index) => self.emit_with(Op::SetUpvalue, index as usize),
+        }
+    }
+
+    fn compile_if(&mut self, items: &[Object], tail: bool) -> Option<()> {
+        if items.len() != 3 && items.len() != 4 {
+            return None;
+        }
+
+        self.compile(&items[1], false)?;
+        let otherwise = self.emit_jump(Op::JumpIfFalse);
+        self.compile(&items[2], tail)?;
+        let end = self.emit_jump(Op::Jump);
+        self.patch(otherwise)?;
+        match items.get(3) {
+            Some(alternative) => self.compile(alternative, tail)?,
+            None => {
+                let index = self.constant(Object::Nil);
+                self.emit_with(Op::Constant, index)?;
+            }
+        }
+        self.patch(end)
+    }
+
+    fn compile_call(&mut self, items: &[Object], tail: bool) -> Option<()> {
+        let argc = u8::try_from(items.len() - 1).ok()?;
+        for item in items {
+            self.compile(item, false)?;
+        }
+        self.emit(if tail { Op::TailCall } else { Op::Call });
+        self.current().proto.code.push(argc);
+        Some(())
+    }
+
+    fn compile_lambda(&mut self, items: &[Object]) -> Option<()> {
+        if items.len() < 3 {
+            return None;
+        }
+        let parameters = match &items[1] {
+            Object::List(args) => Parameters::parse(args).ok()?,
+            _ => return None,
+        };
+        let body = &items[2..];
+        let locals = analysis::frame_layout(&parameters, body);
+        if locals.len() > u16::MAX as usize {
+            return None;
+        }
+
+        let defaults: Vec<Object> = parameters
+            .optional
+            .iter()
+            .chain(parameters.keys.iter())
+            .map(|(_, default)| default.clone())
+            .collect();
+        let mut assigned = Vec::new();
+        let mut captured = Vec::new();
+        for exp in defaults.iter().chain(body) {
+            scan_scope(exp, &mut assigned, &mut captured);
+        }
+        let parameter_count = parameters.required.len() + defaults.len();
+        let boxed: Vec<bool> = locals
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                i >= parameter_count || assigned.contains(name) || captured.contains(name)
+            })
+            .collect();
+
+        let required = parameters.required.len();
+        self.functions.push(Function {
+            proto: Proto::new(parameters, locals, false),
+            boxed,
+        });
+        let compiled = self.compile_function(required, &defaults, body);
+        let function = self.functions.pop()?;
+        compiled?;
+
+        let protos = &mut self.current().proto.protos;
+        protos.push(Rc::new(function.proto));
+        let index = protos.len() - 1;
+        self.emit_with(Op::Closure, index)
+    }
+
+    /// Boxes the locals that need it, then evaluates the defaults of the
+    /// parameters that got no argument, in order, then the body.
+    fn compile_function(
+        &mut self,
+        required: usize,
+        defaults: &[Object],
+        body: &[Object],
+    ) -> Option<()> {
+        let boxed = self.current().boxed.clone();
+        for (index, _) in boxed.iter().enumerate().filter(|(_, boxed)| **boxed) {
+            self.emit_with(Op::MakeCell, index)?;
+        }
+
+        for (i, default) in defaults.iter().enumerate() {
+            let slot = u16::try_from(required + i).ok()?;
+            self.emit(Op::JumpIfBound);
+            self.current()
+                .proto
+                .code
+                .extend_from_slice(&slot.to_le_bytes());
+            let skip = self.emit_operand();
+            self.compile(default, false)?;
+            self.emit_with(Op::BindLocal, slot as usize)?;
+            self.patch(skip)?;
+        }
+
+        self.compile_body(body, true)?;
+        self.emit(Op::Return);
+        Some(())
+    }
+}
+
+/// Collects the names that `exp` assigns to or defines in the function it
+/// is compiled in, and the free variables of the lambdas within it.
+fn scan_scope(exp: &Object, assigned: &mut Vec<Symbol>, captured: &mut Vec<Symbol>) {
+    let items = match exp {
+        Object::List(items) => items,
+        _ => return,
+    };
+
+    match items.first() {
+        Some(Object::Symbol(symbol::QUOTE)) => {}
+        Some(Object::Symbol(symbol::LAMBDA)) => lambda_free_variables(items, captured),
+        Some(Object::Symbol(symbol::DEFINE)) | Some(Object::Symbol(symbol::SET)) => {
+            if let Some(Object::Symbol(name)) = items.get(1) {
+                assigned.push(*name);
+            }
+            for item in items.iter().skip(2) {
+                scan_scope(item, assigned, captured);
+            }
+        }
+        _ => {
+            for item in items {
+                scan_scope(item, assigned, captured);
+            }
+        }
+    }
+}
+
+/// Collects the variables that `exp` refers to, or assigns to, and that are
+/// not in `bound`.
+fn free_variables(exp: &Object, bound: &[Symbol], free: &mut Vec<Symbol>) {
+    let items = match exp {
+        Object::Symbol(name) => {
+            if !bound.contains(name) && !free.contains(name) {
+                free.push(*name);
+            }
+            return;
+        }
+        Object::List(items) => items,
+        _ => return,
+    };
+
+    let rest = match items.first() {
+        Some(Object::Symbol(symbol::QUOTE)) => return,
+        Some(Object::Symbol(symbol::LAMBDA)) => {
+            let mut inner = Vec::new();
+            lambda_free_variables(items, &mut inner);
+            for name in inner {
+                if !bound.contains(&name) && !free.contains(&name) {
+                    free.push(name);
+                }
+            }
+            return;
+        }
+        Some(Object::Symbol(symbol::DEFINE))
+        | Some(Object::Symbol(symbol::SET))
+        | Some(Object::Symbol(symbol::IF))
+        | Some(Object::Symbol(symbol::BEGIN)) => &items[1..],
+        _ => &items[..],
+    };
+    for item in rest {
+        free_variables(item, bound, free);
+    }
+}
+
+fn lambda_free_variables(items: &[Object], free: &mut Vec<Symbol>) {
+    let parameters = match items.get(1) {
+        Some(Object::List(args)) => match Parameters::parse(args) {
+            Ok(parameters) => parameters,
+            Err(_) => return,
+        },
+        _ => return,
+    };
+    let body = items.get(2..).unwrap_or(&[]);
+    let locals = analysis::frame_layout(&parameters, body);
+
+    let defaults = parameters.optional.iter().chain(parameters.keys.iter());
+    for (_, default) in defaults {
+        free_variables(default, &locals, free);
+    }
+    for exp in body {
+        free_variables(exp, &locals, free);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader;
+
+    fn compile_str(code: &str) -> Option<Rc<Proto>> {
+        compile(&reader::read(code).unwrap().remove(0))
+    }
+
+    #[test]
+    fn test_captured_and_assigned_locals_are_boxed() {
+        let proto = compile_str("(lambda (a b c) (set! b 1) (lambda () c))").unwrap();
+        let lambda = &proto.protos[0];
+        assert_eq!(lambda.code[0], Op::MakeCell as u8);
+        assert_eq!(&lambda.code[1..3], &1u16.to_le_bytes());
+        assert_eq!(lambda.code[3], Op::MakeCell as u8);
+        assert_eq!(&lambda.code[4..6], &2u16.to_le_bytes());
+        assert_eq!(
+            lambda.protos[0].upvalues,
+            vec![(Symbol::intern("c"), Capture::Local(2))]
+        );
+    }
+
+    #[test]
+    fn test_upvalues_are_threaded_through_enclosing_functions() {
+        let proto = compile_str("(lambda (x) (lambda () (lambda () x)))").unwrap();
+        let middle = &proto.protos[0].protos[0];
+        assert_eq!(
+            middle.upvalues,
+            vec![(Symbol::intern("x"), Capture::Local(0))]
+        );
+        assert_eq!(
+            middle.protos[0].upvalues,
+            vec![(Symbol::intern("x"), Capture::Upvalue(0))]
+        );
+    }
+
+    #[test]
+    fn test_instructions_map_to_source_lines() {
+        let (exp, spans) = reader::read_with_span_trees("(f 1\n   (g 2)\n   3)")
+            .unwrap()
+            .remove(0);
+        let proto = compile_with_spans(&exp, &spans).unwrap();
+        // Instructions take the line of the innermost list they come from,
+        // so the `3` and the outer call count as line 1.
+        assert_eq!(proto.line_at(0), Some(1));
+        assert_eq!(proto.line_at(6), Some(2));
+        assert_eq!(proto.line_at(proto.code.len() - 1), Some(1));
+        assert_eq!(proto.span_at(6), Some(Span { line: 2, column: 4 }));
+        assert!(compile(&exp).unwrap().spans.is_empty());
+    }
+
+    #[test]
+    fn test_forms_left_to_the_evaluator() {
+        assert!(compile_str("(define f (lambda (n) (if (< n 2) n (f (- n 1)))))").is_some());
+        assert!(compile_str("(lambda () (guard (e (#t 0)) (raise 1)))").is_none());
+        assert!(compile_str("(reset (shift k 1))").is_none());
+        assert!(compile_str("(if)").is_none());
+        assert!(compile_str("()").is_none());
+    }
+}
@@ -0,0 +1,334 @@
+use std::rc::Rc;
+
+use crate::analysis::{self, Global, Local};
+use crate::condition::{self, RestartClause};
+use crate::error::ErrorKind;
+use crate::exception;
+use crate::object::Object;
+use crate::parameters::Parameters;
+use crate::symbol::{self, Symbol};
+
+/// Compiled code. Frames and closures share it instead of cloning forms.
+pub type Code = Rc<Node>;
+
+/// A form whose syntax has been checked and whose special form has been
+/// picked out once, so that the evaluator neither inspects nor copies the
+/// source each time it runs it.
+#[derive(Debug, PartialEq)]
+pub enum Node {
+    Constant(Object),
+    /// A variable looked up by name.
+    Variable(Symbol),
+    Local(Local),
+    Global(Global),
+    Define(Symbol, Code),
+    Set(Symbol, Code),
+    If(Code, Code, Option<Code>),
+    Lambda(Rc<Template>),
+    Sequence(Rc<[Code]>),
+    /// The operator, then the operands.
+    Call(Rc<[Code]>),
+    Guard {
+        var: Symbol,
+        clauses: Rc<[GuardClause]>,
+        body: Rc<[Code]>,
+    },
+    HandlerBind {
+        types: Vec<String>,
+        handlers: Rc<[Code]>,
+        body: Rc<[Code]>,
+    },
+    RestartCase {
+        expr: Code,
+        clauses: Rc<[RestartClause]>,
+    },
+    Reset(Rc<[Code]>),
+    Shift(Symbol, Rc<[Code]>),
+    Delay {
+        exp: Code,
+        chained: bool,
+    },
+    StreamCons(Code, Code),
+    /// A malformed form. Its error is raised when it is evaluated, not when
+    /// it is compiled, so a mistake in a branch that never runs is harmless.
+    Error(Object),
+}
+
+/// What a lambda expression compiles to; every closure it makes shares it.
+#[derive(Debug, PartialEq)]
+pub struct Template {
+    pub parameters: Parameters,
+    /// The defaults of the optional parameters, then of the key parameters.
+    pub defaults: Vec<Code>,
+    pub slots: Rc<[Symbol]>,
+    pub body: Rc<[Code]>,
+}
+
+/// A `guard` clause: `(test body...)`, `(test => receiver)` or
+/// `(else body...)`, whose test is `None`. With no body, the clause yields
+/// the value of its test.
+#[derive(Debug, PartialEq)]
+pub struct GuardClause {
+    pub test: Option<Code>,
+    pub receiver: Option<Code>,
+    pub body: Rc<[Code]>,
+}
+
+pub fn compile(exp: &Object) -> Code {
+    Rc::new(match exp {
+        Object::Symbol(name) => Node::Variable(*name),
+        Object::Local(local) => Node::Local(*local),
+        Object::Global(global) => Node::Global(*global),
+        Object::List(items) => compile_list(items).unwrap_or_else(Node::Error),
+        other => Node::Constant(other.clone()),
+    })
+}
+
+pub fn compile_body(body: &[Object]) -> Rc<[Code]> {
+    body.iter().map(compile).collect()
+}
+
+fn compile_list(items: &[Object]) -> Result<Node, Object> {
+    let form = match items.first() {
+        None => {
+            return Err(Object::new_error(
+                ErrorKind::Syntax,
+                "cannot evaluate empty list",
+            ))
+        }
+        Some(Object::Symbol(form)) => *form,
+        Some(_) => return Ok(Node::Call(compile_body(items))),
+    };
+
+    match form {
+        symbol::QUOTE => compile_quote(items),
+        symbol::DEFINE => {
+            compile_binding(items, "define").map(|(name, value)| Node::Define(name, value))
+        }
+        symbol::SET => compile_binding(items, "set!").map(|(name, value)| Node::Set(name, value)),
+        symbol::LAMBDA => compile_lambda(items),
+        symbol::IF => compile_if(items),
+        symbol::BEGIN => Ok(Node::Sequence(compile_body(&items[1..]))),
+        symbol::GUARD => compile_guard(items),
+        symbol::HANDLER_BIND => compile_handler_bind(items),
+        symbol::RESTART_CASE => compile_restart_case(items),
+        symbol::RESET => Ok(Node::Reset(compile_body(&items[1..]))),
+        symbol::SHIFT => compile_shift(items),
+        symbol::DELAY | symbol::DELAY_FORCE => compile_delay(items),
+        symbol::STREAM_CONS => compile_stream_cons(items),
+        _ => Ok(Node::Call(compile_body(items))),
+    }
+}
+
+fn compile_quote(exps: &[Object]) -> Result<Node, Object> {
+    if exps.len() != 2 {
+        return Err(Object::new_error(
+            ErrorKind::Syntax,
+            "quote expects exactly one argument",
+        ));
+    }
+
+    Ok(Node::Constant(exps[1].clone()))
+}
+
+/// `(define name value)` and `(set! name value)`
+fn compile_binding(exps: &[Object], form: &str) -> Result<(Symbol, Code), Object> {
+    if exps.len() != 3 {
+        return Err(Object::new_error(
+            ErrorKind::Syntax,
+            &format!("{} expects a name and a value", form),
+        ));
+    }
+
+    match exps[1] {
+        Object::Symbol(name) => Ok((name, compile(&exps[2]))),
+        ref other => Err(Object::type_error("argument has wrong type", other)),
+    }
+}
+
+fn compile_lambda(exps: &[Object]) -> Result<Node, Object> {
+    if exps.len() < 3 {
+        return Err(Object::new_error(ErrorKind::Syntax, "lambda without body"));
+    }
+
+    let parameters = match &exps[1] {
+        Object::List(args) => Parameters::parse(args)?,
+        other => return Err(Object::type_error("arguments are not a list", other)),
+    };
+
+    let defaults = parameters
+        .optional
+        .iter()
+        .chain(parameters.keys.iter())
+        .map(|(_, default)| compile(default))
+        .collect();
+    let template = Template {
+        slots: Rc::from(analysis::frame_layout(&parameters, &exps[2..])),
+        defaults,
+        parameters,
+        body: compile_body(&exps[2..]),
+    };
+    Ok(Node::Lambda(Rc::new(template)))
+}
+
+fn compile_if(exps: &[Object]) -> Result<Node, Object> {
+    if exps.len() != 3 && exps.len() != 4 {
+        return Err(Object::new_error(
+            ErrorKind::Syntax,
+            "if expects a test, a consequent and an optional alternative",
+        ));
+    }
+
+    Ok(Node::If(
+        compile(&exps[1]),
+        compile(&exps[2]),
+        exps.get(3).map(compile),
+    ))
+}
+
+fn compile_guard(exps: &[Object]) -> Result<Node, Object> {
+    let (var, clauses) = exception::parse_guard(exps)?;
+    let clauses = clauses.iter().map(compile_guard_clause).collect();
+    Ok(Node::Guard {
+        var,
+        clauses,
+        body: compile_body(&exps[2..]),
+    })
+}
+
+/// A malformed clause only raises its error once a condition reaches it.
+fn compile_guard_clause(clause: &Object) -> GuardClause {
+    let clause = match clause {
+        Object::List(clause) if !clause.is_empty() => clause,
+        _ => {
+            let error = Object::new_error(ErrorKind::Syntax, "guard: malformed clause");
+            return GuardClause {
+                test: Some(Rc::new(Node::Error(error))),
+                receiver: None,
+                body: Rc::from(Vec::new()),
+            };
+        }
+    };
+
+    let test = match clause[0].has_symbol_value(symbol::ELSE) {
+        Some(true) => None,
+        _ => Some(compile(&clause[0])),
+    };
+    if clause.len() == 3 && clause[1].has_symbol_value(symbol::ARROW) == Some(true) {
+        return GuardClause {
+            test,
+            receiver: Some(compile(&clause[2])),
+            body: Rc::from(Vec::new()),
+        };
+    }
+
+    GuardClause {
+        test,
+        receiver: None,
+        body: compile_body(&clause[1..]),
+    }
+}
+
+fn compile_handler_bind(exps: &[Object]) -> Result<Node, Object> {
+    let (types, handlers) = condition::parse_handler_bind(exps)?;
+    Ok(Node::HandlerBind {
+        types,
+        handlers: compile_body(&handlers),
+        body: compile_body(&exps[2..]),
+    })
+}
+
+fn compile_restart_case(exps: &[Object]) -> Result<Node, Object> {
+    let (expr, clauses) = condition::parse_restart_case(exps)?;
+    Ok(Node::RestartCase {
+        expr: compile(&expr),
+        clauses: Rc::from(clauses),
+    })
+}
+
+fn compile_shift(exps: &[Object]) -> Result<Node, Object> {
+    if exps.len() < 3 {
+        return Err(Object::new_error(
+            ErrorKind::Syntax,
+            "shift expects a name and a body",
+        ));
+    }
+
+    match exps[1] {
+        Object::Symbol(name) => Ok(Node::Shift(name, compile_body(&exps[2..]))),
+        ref other => Err(Object::type_error("argument has wrong type", other)),
+    }
+}
+
+fn compile_delay(exps: &[Object]) -> Result<Node, Object> {
+    if exps.len() != 2 {
+        return Err(Object::new_error(
+            ErrorKind::Syntax,
+            &format!("{} expects exactly one argument", exps[0]),
+        ));
+    }
+
+    Ok(Node::Delay {
+        exp: compile(&exps[1]),
+        chained: exps[0].has_symbol_value(symbol::DELAY_FORCE) == Some(true),
+    })
+}
+
+/// `(stream-cons head tail)` builds the pair right away, with the head
+/// delayed and the tail, itself a stream, chained with `delay-force`.
+fn compile_stream_cons(exps: &[Object]) -> Result<Node, Object> {
+    if exps.len() != 3 {
+        return Err(Object::new_error(
+            ErrorKind::Syntax,
+            "stream-cons expects a head and a tail",
+        ));
+    }
+
+    Ok(Node::StreamCons(compile(&exps[1]), compile(&exps[2])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader;
+
+    fn compile_str(code: &str) -> Code {
+        compile(&reader::read(code).unwrap().remove(0))
+    }
+
+    #[test]
+    fn test_special_forms_are_picked_out() {
+        assert_eq!(
+            *compile_str("(if x 1)"),
+            Node::If(
+                Rc::new(Node::Variable(Symbol::intern("x"))),
+                Rc::new(Node::Constant(Object::Integer(1))),
+                None
+            )
+        );
+        assert_eq!(
+            *compile_str("'(if x)"),
+            Node::Constant(Object::List(vec![
+                Object::symbol("if"),
+                Object::symbol("x")
+            ]))
+        );
+        assert!(matches!(*compile_str("(f 1 2)"), Node::Call(ref exps) if exps.len() == 3));
+    }
+
+    #[test]
+    fn test_malformed_forms_compile_to_errors() {
+        assert_eq!(
+            *compile_str("(if)"),
+            Node::Error(Object::new_error(
+                ErrorKind::Syntax,
+                "if expects a test, a consequent and an optional alternative"
+            ))
+        );
+        let lambda = compile_str("(lambda () 1 (define))");
+        match &*lambda {
+            Node::Lambda(template) => assert!(matches!(*template.body[1], Node::Error(_))),
+            other => panic!("expected a lambda, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::rc::Rc;
+
+use crate::compiler::{self, Code};
+use crate::error::ErrorKind;
+use crate::object::Object;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RestartInfo {
+    pub name: String,
+    pub parameters: Vec<String>,
+}
+
+/// Called with an unhandled condition and the active restarts, innermost
+/// first. Returning the index of a restart and its arguments invokes it,
+/// returning `None` lets the condition unwind to the top level.
+pub type Debugger = Rc<dyn Fn(&Object, &[RestartInfo]) -> Option<(usize, Vec<Object>)>>;
+
+pub(crate) fn condition_matches(type_name: &str, condition: &Object) -> bool {
+    match type_name {
+        "t" | "condition" => true,
+        "error" => matches!(condition, Object::Error(_)),
+        name => match condition {
+            Object::Error(e) => e.kind.to_string() == name,
+            _ => false,
+        },
+    }
+}
+
+/// Splits `(handler-bind ((type handler)...) body...)` into the condition
+/// types and the unevaluated handler expressions.
+pub(crate) fn parse_handler_bind(exps: &[Object]) -> Result<(Vec<String>, Vec<Object>), Object> {
+    let bindings = match exps.get(1) {
+        Some(Object::List(bindings)) => bindings,
+        _ => {
+            return Err(Object::new_error(
+                ErrorKind::Syntax,
+                "handler-bind: malformed bindings",
+            ))
+        }
+    };
+
+    let mut types = Vec::new();
+    let mut handlers = Vec::new();
+    for binding in bindings.iter() {
+        match binding {
+            Object::List(binding) if binding.len() == 2 => match &binding[0] {
+                Object::Symbol(type_name) => {
+                    types.push(type_name.to_string());
+                    handlers.push(binding[1].clone());
+                }
+                other => {
+                    return Err(Object::type_error(
+                        "handler-bind: condition type is not a symbol",
+                        other,
+                    ))
+                }
+            },
+            _ => {
+                return Err(Object::new_error(
+                    ErrorKind::Syntax,
+                    "handler-bind: malformed binding",
+                ))
+            }
+        }
+    }
+
+    Ok((types, handlers))
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RestartClause {
+    pub info: RestartInfo,
+    pub body: Rc<[Code]>,
+}
+
+fn parse_restart_clause(clause: &Object) -> Result<RestartClause, Object> {
+    let malformed = || Object::new_error(ErrorKind::Syntax, "restart-case: malformed clause");
+
+    let clause = match clause {
+        Object::List(clause) if clause.len() >= 2 => clause,
+        _ => return Err(malformed()),
+    };
+
+    let name = match &clause[0] {
+        Object::Symbol(name) => name.to_string(),
+        _ => return Err(malformed()),
+    };
+
+    let parameters = match &clause[1] {
+        Object::List(parameters) => parameters
+            .iter()
+            .map(|p| match p {
+                Object::Symbol(p) => Ok(p.to_string()),
+                _ => Err(malformed()),
+            })
+            .collect::<Result<Vec<String>, Object>>()?,
+        _ => return Err(malformed()),
+    };
+
+    Ok(RestartClause {
+        info: RestartInfo { name, parameters },
+        body: compiler::compile_body(&clause[2..]),
+    })
+}
+
+/// Splits `(restart-case expr (name (params...) body...)...)` into the
+/// expression and its restart clauses.
+pub(crate) fn parse_restart_case(exps: &[Object]) -> Result<(Object, Vec<RestartClause>), Object> {
+    let expr = match exps.get(1) {
+        Some(expr) => expr.clone(),
+        None => {
+            return Err(Object::new_error(
+                ErrorKind::Syntax,
+                "restart-case: missing expression",
+            ))
+        }
+    };
+
+    let clauses = exps[2..]
+        .iter()
+        .map(parse_restart_clause)
+        .collect::<Result<Vec<RestartClause>, Object>>()?;
+    Ok((expr, clauses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Environment;
+    use crate::reader;
+    use crate::vm::test_util::eval_all;
+    use crate::vm::ENGINES;
+
+    #[test]
+    fn test_restart_case_without_condition() {
+        assert_eq!(
+            eval_all("(restart-case (+ 1 2) (use-value (v) v))"),
+            Ok(Object::Integer(3))
+        );
+    }
+
+    #[test]
+    fn test_invoke_restart_from_handler() {
+        let result = eval_all(
+            "(define parse (lambda (r) (restart-case (car r) (use-value (v) v))))
+             (handler-bind ((type-error (lambda (c) (invoke-restart 'use-value 0))))
+               (list (parse 1) (parse (list 2)) (parse 3)))",
+        );
+        assert_eq!(
+            result,
+            Ok(Object::List(vec![
+                Object::Integer(0),
+                Object::Integer(2),
+                Object::Integer(0)
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_handlers_decline_by_returning() {
+        let result = eval_all(
+            "(handler-bind ((error (lambda (c) (invoke-restart 'skip-record))))
+               (handler-bind ((error (lambda (c) 0)))
+                 (restart-case (car 1) (skip-record () 99))))",
+        );
+        assert_eq!(result, Ok(Object::Integer(99)));
+    }
+
+    #[test]
+    fn test_unhandled_conditions_unwind() {
+        let result = eval_all(
+            "(handler-bind ((arity-error (lambda (c) (invoke-restart 'use-value 0))))
+               (restart-case (car 1) (use-value (v) v)))",
+        );
+        assert!(matches!(result, Err(Object::Error(ref e)) if e.kind == ErrorKind::Type));
+    }
+
+    #[test]
+    fn test_invoke_inactive_restart() {
+        let result = eval_all("(invoke-restart 'nope)");
+        assert_eq!(
+            result,
+            Err(Object::new_error_with(
+                ErrorKind::Runtime,
+                "invoke-restart: no such restart is active",
+                vec![Object::symbol("nope")]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_compute_restarts() {
+        let result = eval_all(
+            "(restart-case
+               (restart-case (compute-restarts) (retry () 1))
+               (use-value (v) v)
+               (abort () 0))",
+        );
+        assert_eq!(
+            result,
+            Ok(Object::List(vec![
+                Object::symbol("retry"),
+                Object::symbol("use-value"),
+                Object::symbol("abort"),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_guard_catches_before_outer_handlers() {
+        let result = eval_all(
+            "(handler-bind ((error (lambda (c) (invoke-restart 'use-value 1))))
+               (restart-case
+                 (guard (e (#t 2)) (car 1))
+                 (use-value (v) v)))",
+        );
+        assert_eq!(result, Ok(Object::Integer(2)));
+    }
+
+    #[test]
+    fn test_signal_returns_when_unhandled() {
+        assert_eq!(eval_all("(signal 5)"), Ok(Object::Nil));
+        assert_eq!(
+            eval_all(
+                "(restart-case
+                   (handler-bind ((t (lambda (c) (invoke-restart 'use-value c))))
+                     (signal 5))
+                   (use-value (v) (+ v 1)))"
+            ),
+            Ok(Object::Integer(6))
+        );
+    }
+
+    #[test]
+    fn test_debugger_picks_restart() {
+        let debugger: Debugger = Rc::new(|condition, restarts| {
+            assert!(matches!(condition, Object::Error(_)));
+            assert_eq!(restarts[0].name, "use-value");
+            assert_eq!(restarts[0].parameters, vec!["v"]);
+            Some((0, vec![Object::Integer(42)]))
+        });
+        for (engine, eval) in ENGINES.iter() {
+            let env = Environment::new();
+            env.borrow_mut().set_debugger(Some(debugger.clone()));
+            let exp = reader::read("(+ 1 (restart-case (car 1) (use-value (v) v)))")
+                .unwrap()
+                .remove(0);
+            assert_eq!(
+                eval(exp.clone(), env),
+                Ok(Object::Integer(43)),
+                "{}",
+                engine
+            );
+
+            // Another global environment has a debugger of its own.
+            assert!(eval(exp, Environment::new()).is_err(), "{}", engine);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use crate::object::{short_type_name, Arity, EnvRef, NativeClosure, Object};
+
+/// Converts a risp `Object` into a Rust value. The error is an `Object::Error`
+/// describing what was expected, so it can be returned from natives as is.
+pub trait FromObject: Sized {
+    fn from_object(obj: &Object) -> Result<Self, Object>;
+}
+
+pub trait IntoObject {
+    fn into_object(self) -> Object;
+}
+
+fn type_error(expected: &str, obj: &Object) -> Object {
+    Object::type_error(
+        &format!("expected {}, got {}", expected, obj.type_name()),
+        obj,
+    )
+}
+
+impl FromObject for Object {
+    fn from_object(obj: &Object) -> Result<Object, Object> {
+        Ok(obj.clone())
+    }
+}
+
+impl IntoObject for Object {
+    fn into_object(self) -> Object {
+        self
+    }
+}
+
+impl FromObject for i64 {
+    fn from_object(obj: &Object) -> Result<i64, Object> {
+        match obj {
+            Object::Integer(n) => Ok(*n),
+            _ => Err(type_error("integer", obj)),
+        }
+    }
+}
+
+impl IntoObject for i64 {
+    fn into_object(self) -> Object {
+        Object::Integer(self)
+    }
+}
+
+impl FromObject for f64 {
+    fn from_object(obj: &Object) -> Result<f64, Object> {
+        match obj {
+            Object::Float(n) => Ok(*n),
+            Object::Integer(n) => Ok(*n as f64),
+            _ => Err(type_error("number", obj)),
+        }
+    }
+}
+
+impl IntoObject for f64 {
+    fn into_object(self) -> Object {
+        Object::Float(self)
+    }
+}
+
+impl FromObject for bool {
+    fn from_object(obj: &Object) -> Result<bool, Object> {
+        match obj {
+            Object::Boolean(b) => Ok(*b),
+            _ => Err(type_error("boolean", obj)),
+        }
+    }
+}
+
+impl IntoObject for bool {
+    fn into_object(self) -> Object {
+        Object::Boolean(self)
+    }
+}
+
+impl FromObject for char {
+    fn from_object(obj: &Object) -> Result<char, Object> {
+        match obj {
+            Object::Char(c) => Ok(*c),
+            _ => Err(type_error("char", obj)),
+        }
+    }
+}
+
+impl IntoObject for char {
+    fn into_object(self) -> Object {
+        Object::Char(self)
+    }
+}
+
+impl FromObject for String {
+    fn from_object(obj: &Object) -> Result<String, Object> {
+        match obj {
+            Object::String(s) => Ok(s.clone()),
+            _ => Err(type_error("string", obj)),
+        }
+    }
+}
+
+impl IntoObject for String {
+    fn into_object(self) -> Object {
+        Object::String(self)
+    }
+}
+
+impl IntoObject for &str {
+    fn into_object(self) -> Object {
+        Object::String(self.to_string())
+    }
+}
+
+impl IntoObject for () {
+    fn into_object(self) -> Object {
+        Object::Nil
+    }
+}
+
+/// Host values are passed through as `Object::Opaque` and shared by `Rc`.
+impl<T: Any> FromObject for Rc<T> {
+    fn from_object(obj: &Object) -> Result<Rc<T>, Object> {
+        obj.downcast_rc::<T>()
+            .ok_or_else(|| type_error(short_type_name::<T>(), obj))
+    }
+}
+
+impl<T: Any> IntoObject for Rc<T> {
+    fn into_object(self) -> Object {
+        Object::new_opaque_rc(self)
+    }
+}
+
+impl<T: FromObject> FromObject for Vec<T> {
+    fn from_object(obj: &Object) -> Result<Vec<T>, Object> {
+        match obj {
+            Object::List(items) => items.iter().map(T::from_object).collect(),
+            _ => Err(type_error("list", obj)),
+        }
+    }
+}
+
+impl<T: IntoObject> IntoObject for Vec<T> {
+    fn into_object(self) -> Object {
+        Object::List(self.into_iter().map(IntoObject::into_object).collect())
+    }
+}
+
+/// `None` is represented as nil, so an `Option<Object>` cannot tell an absent
+/// value from an explicit nil.
+impl<T: FromObject> FromObject for Option<T> {
+    fn from_object(obj: &Object) -> Result<Option<T>, Object> {
+        match obj {
+            Object::Nil => Ok(None),
+            _ => T::from_object(obj).map(Some),
+        }
+    }
+}
+
+impl<T: IntoObject> IntoObject for Option<T> {
+    fn into_object(self) -> Object {
+        match self {
+            Some(value) => value.into_object(),
+            None => Object::Nil,
+        }
+    }
+}
+
+/// Maps are represented as association lists of two-element lists.
+impl<K: FromObject + Eq + Hash, V: FromObject> FromObject for HashMap<K, V> {
+    fn from_object(obj: &Object) -> Result<HashMap<K, V>, Object> {
+        let pairs: Vec<(K, V)> = FromObject::from_object(obj)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+impl<K: IntoObject, V: IntoObject> IntoObject for HashMap<K, V> {
+    fn into_object(self) -> Object {
+        Object::List(
+            self.into_iter()
+                .map(|(k, v)| Object::List(vec![k.into_object(), v.into_object()]))
+                .collect(),
+        )
+    }
+}
+
+macro_rules! tuple_conversions {
+    ( $len:expr; $( $name:ident : $idx:tt ),+ ) => {
+        impl<$( $name: FromObject ),+> FromObject for ( $( $name, )+ ) {
+            fn from_object(obj: &Object) -> Result<Self, Object> {
+                match obj {
+                    Object::List(items) if items.len() == $len => {
+                        Ok(( $( $name::from_object(&items[$idx])?, )+ ))
+                    }
+                    _ => Err(type_error(concat!("list of length ", $len), obj)),
+                }
+            }
+        }
+
+        impl<$( $name: IntoObject ),+> IntoObject for ( $( $name, )+ ) {
+            fn into_object(self) -> Object {
+                Object::List(vec![ $( self.$idx.into_object() ),+ ])
+            }
+        }
+    };
+}
+
+tuple_conversions!(1; A: 0);
+tuple_conversions!(2; A: 0, B: 1);
+tuple_conversions!(3; A: 0, B: 1, C: 2);
+tuple_conversions!(4; A: 0, B: 1, C: 2, D: 3);
+
+/// Turns a typed Rust function into a `NativeClosure` that converts its
+/// arguments with `FromObject` and its result with `IntoObject`.
+pub trait IntoNative<Args> {
+    fn into_native(self, name: &str) -> NativeClosure;
+}
+
+fn argument_error(name: &str, position: usize, e: Object) -> Object {
+    match e {
+        Object::Error(mut e) => {
+            e.message = format!("{}: argument {}: {}", name, position + 1, e.message);
+            Object::Error(e)
+        }
+        other => other,
+    }
+}
+
+macro_rules! into_native {
+    ( $len:expr; $( $arg:ident : $idx:tt ),* ) => {
+        impl<Func, Ret, $( $arg ),*> IntoNative<( $( $arg, )* )> for Func
+        where
+            Func: Fn( $( $arg ),* ) -> Ret + 'static,
+            Ret: IntoObject,
+            $( $arg: FromObject, )*
+        {
+            #[allow(unused_variables)]
+            fn into_native(self, name: &str) -> NativeClosure {
+                let native_name = name.to_string();
+                NativeClosure {
+                    name: name.to_string(),
+                    arity: Arity::Exact($len),
+                    func: Rc::new(move |args: &[Object], _env: EnvRef| {
+                        let result = self( $(
+                            $arg::from_object(&args[$idx])
+                                .map_err(|e| argument_error(&native_name, $idx, e))?
+                        ),* );
+                        Ok(result.into_object())
+                    }),
+                }
+            }
+        }
+    };
+}
+
+into_native!(0;);
+into_native!(1; A: 0);
+into_native!(2; A: 0, B: 1);
+into_native!(3; A: 0, B: 1, C: 2);
+into_native!(4; A: 0, B: 1, C: 2, D: 3);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::object::Environment;
+
+    #[test]
+    fn test_scalar_conversions() {
+        assert_eq!(i64::from_object(&Object::Integer(3)), Ok(3));
+        assert_eq!(f64::from_object(&Object::Integer(3)), Ok(3.0));
+        assert_eq!(f64::from_object(&Object::Float(0.5)), Ok(0.5));
+        assert_eq!(bool::from_object(&Object::Boolean(true)), Ok(true));
+        assert_eq!(
+            String::from_object(&Object::String(String::from("hi"))),
+            Ok(String::from("hi"))
+        );
+        assert_eq!(
+            i64::from_object(&Object::String(String::from("hi"))),
+            Err(Object::type_error(
+                "expected integer, got string",
+                &Object::String(String::from("hi"))
+            ))
+        );
+
+        assert_eq!(7.into_object(), Object::Integer(7));
+        assert_eq!(1.5.into_object(), Object::Float(1.5));
+        assert_eq!(false.into_object(), Object::Boolean(false));
+        assert_eq!("hi".into_object(), Object::String(String::from("hi")));
+    }
+
+    #[test]
+    fn test_container_conversions() {
+        let list = Object::List(vec![Object::Integer(1), Object::Integer(2)]);
+        assert_eq!(Vec::<i64>::from_object(&list), Ok(vec![1, 2]));
+        assert_eq!(vec![1, 2].into_object(), list);
+        assert_eq!(<(i64, i64)>::from_object(&list), Ok((1, 2)));
+        assert_eq!(
+            <(i64, i64, i64)>::from_object(&list),
+            Err(Object::type_error(
+                "expected list of length 3, got list",
+                &list
+            ))
+        );
+        assert_eq!(
+            (1, "a").into_object(),
+            Object::List(vec![Object::Integer(1), Object::String(String::from("a"))])
+        );
+
+        assert_eq!(Option::<i64>::from_object(&Object::Nil), Ok(None));
+        assert_eq!(Option::<i64>::from_object(&Object::Integer(1)), Ok(Some(1)));
+        assert_eq!(None::<i64>.into_object(), Object::Nil);
+
+        let mut map = HashMap::new();
+        map.insert(String::from("a"), 1);
+        let object = map.clone().into_object();
+        assert_eq!(
+            object,
+            Object::List(vec![Object::List(vec![
+                Object::String(String::from("a")),
+                Object::Integer(1)
+            ])])
+        );
+        assert_eq!(HashMap::<String, i64>::from_object(&object), Ok(map));
+    }
+
+    #[test]
+    fn test_into_native() {
+        let add = (|a: i64, b: i64| a + b).into_native("add");
+        assert_eq!(add.arity, Arity::Exact(2));
+
+        let args = vec![Object::Integer(1), Object::Integer(2)];
+        assert_eq!(add.call(&args, Environment::new()), Ok(Object::Integer(3)));
+
+        let args = vec![Object::Integer(1), Object::Boolean(true)];
+        assert_eq!(
+            add.call(&args, Environment::new()),
+            Err(Object::type_error(
+                "add: argument 2: expected integer, got boolean",
+                &Object::Boolean(true)
+            ))
+        );
+
+        let args = vec![Object::Integer(1)];
+        assert_eq!(
+            add.call(&args, Environment::new()),
+            Err(Object::new_error(
+                ErrorKind::Arity,
+                "add: wrong number of arguments (expected 2, got 1)"
+            ))
+        );
+
+        struct Counter(std::cell::Cell<i64>);
+        let new_counter = (|| Rc::new(Counter(std::cell::Cell::new(0)))).into_native("new");
+        let counter = new_counter.call(&[], Environment::new()).unwrap();
+        assert_eq!(counter.type_name(), "Counter");
+
+        let bump = (|c: Rc<Counter>, by: i64| {
+            c.0.set(c.0.get() + by);
+            c.0.get()
+        })
+        .into_native("bump");
+        let args = vec![counter.clone(), Object::Integer(5)];
+        assert_eq!(bump.call(&args, Environment::new()), Ok(Object::Integer(5)));
+        assert_eq!(
+            bump.call(&args, Environment::new()),
+            Ok(Object::Integer(10))
+        );
+
+        let args = vec![Object::Integer(1), Object::Integer(5)];
+        assert_eq!(
+            bump.call(&args, Environment::new()),
+            Err(Object::type_error(
+                "bump: argument 1: expected Counter, got integer",
+                &Object::Integer(1)
+            ))
+        );
+
+        let join = (|parts: Vec<String>| parts.join(",")).into_native("join");
+        let args = vec![vec!["a", "b"].into_object()];
+        assert_eq!(
+            join.call(&args, Environment::new()),
+            Ok(Object::String(String::from("a,b")))
+        );
+    }
+}
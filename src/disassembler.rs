@@ -0,0 +1,257 @@
+use std::fmt::{self, Write};
+
+use crate::bytecode::{Capture, Op, Proto};
+use crate::object::{Arity, EnvRef, Function, Object};
+use crate::parameters::Parameters;
+use crate::symbol::Symbol;
+
+/// Lists the code of `proto` under `title`, then that of each lambda it
+/// creates, titled by its index: `title/0`, `title/1`, and so on.
+///
+/// ```text
+/// == fib (n) ==
+/// locals: n
+/// constants:
+///      0  <
+///      1  2
+/// offset  line  instruction
+///   0000     1  get-global     0     ; <
+///   0003     |  get-local      0     ; n
+/// ```
+///
+/// The line is that of the source the instruction was compiled from, `|`
+/// when it is the same as the one above, and `-` when it is not known.
+pub fn disassemble(proto: &Proto, title: &str) -> String {
+    let mut out = String::new();
+    write_proto(&mut out, proto, title).expect("writing to a string cannot fail");
+    out
+}
+
+/// `(disassemble f)` prints the bytecode of the compiled procedure `f`.
+pub fn disassemble_native(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("disassemble", args)?;
+    print!("{}", listing(&args[0])?);
+    Ok(Object::Nil)
+}
+
+/// What `(disassemble f)` prints for `f`.
+fn listing(f: &Object) -> Result<String, Object> {
+    match f {
+        Object::Callable(Function::Compiled(closure)) => {
+            Ok(disassemble(&closure.proto, closure.display_name()))
+        }
+        Object::Callable(_) => Err(Object::type_error(
+            "disassemble: procedure is not compiled to bytecode",
+            f,
+        )),
+        other => Err(Object::type_error(
+            "disassemble: argument is not a procedure",
+            other,
+        )),
+    }
+}
+
+fn write_proto(out: &mut String, proto: &Proto, title: &str) -> fmt::Result {
+    if proto.toplevel {
+        writeln!(out, "== {} ==", title)?;
+    } else {
+        writeln!(out, "== {} {} ==", title, lambda_list(&proto.parameters))?;
+    }
+    if !proto.locals.is_empty() {
+        let names: Vec<String> = proto.locals.iter().map(Symbol::to_string).collect();
+        writeln!(out, "locals: {}", names.join(" "))?;
+    }
+    if !proto.upvalues.is_empty() {
+        let upvalues: Vec<String> = proto
+            .upvalues
+            .iter()
+            .map(|(name, capture)| match capture {
+                Capture::Local(i) => format!("{} (local {})", name, i),
+                Capture::Upvalue(i) => format!("{} (upvalue {})", name, i),
+            })
+            .collect();
+        writeln!(out, "upvalues: {}", upvalues.join(", "))?;
+    }
+    if !proto.constants.is_empty() {
+        writeln!(out, "constants:")?;
+        for (i, constant) in proto.constants.iter().enumerate() {
+            writeln!(out, "  {:>4}  {}", i, constant)?;
+        }
+    }
+
+    writeln!(out, "offset  line  instruction")?;
+    let mut ip = 0;
+    let mut last_line = None;
+    while ip < proto.code.len() {
+        let line = match proto.line_at(ip) {
+            Some(line) if last_line == Some(line) => String::from("|"),
+            Some(line) => line.to_string(),
+            None => String::from("-"),
+        };
+        last_line = proto.line_at(ip);
+        write!(out, "  {:04}  {:>4}  ", ip, line)?;
+
+        let op = match Op::from_byte(proto.code[ip]) {
+            Some(op) => op,
+            None => {
+                writeln!(out, "unknown instruction {}", proto.code[ip])?;
+                break;
+            }
+        };
+        let sizes = op.operands();
+        let end = ip + 1 + sizes.iter().sum::<usize>();
+        let bytes = match proto.code.get(ip + 1..end) {
+            Some(bytes) => bytes,
+            None => {
+                writeln!(out, "{} (truncated)", op.name())?;
+                break;
+            }
+        };
+        let mut operands = Vec::new();
+        let mut at = 0;
+        for size in sizes {
+            operands.push(match size {
+                1 => bytes[at] as usize,
+                _ => u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize,
+            });
+            at += size;
+        }
+
+        let listed: Vec<String> = operands.iter().map(usize::to_string).collect();
+        let instruction = format!("{:<14} {}", op.name(), listed.join(" "));
+        match comment(proto, op, &operands, title) {
+            Some(comment) => writeln!(out, "{:<19}  ; {}", instruction.trim_end(), comment)?,
+            None => writeln!(out, "{}", instruction.trim_end())?,
+        }
+        ip = end;
+    }
+
+    for (i, inner) in proto.protos.iter().enumerate() {
+        writeln!(out)?;
+        write_proto(out, inner, &format!("{}/{}", title, i))?;
+    }
+    Ok(())
+}
+
+/// What an instruction's operands refer to.
+fn comment(proto: &Proto, op: Op, operands: &[usize], title: &str) -> Option<String> {
+    let local = |i: usize| proto.locals.get(i).map(|name| name.to_string());
+    match op {
+        Op::Constant | Op::GetGlobal | Op::SetGlobal | Op::DefineGlobal => proto
+            .constants
+            .get(operands[0])
+            .map(|constant| constant.to_string()),
+        Op::GetLocal
+        | Op::BindLocal
+        | Op::MakeCell
+        | Op::GetCell
+        | Op::SetCell
+        | Op::DefineCell => local(operands[0]),
+        Op::GetUpvalue | Op::SetUpvalue => proto
+            .upvalues
+            .get(operands[0])
+            .map(|(name, _)| name.to_string()),
+        Op::Closure => Some(format!("{}/{}", title, operands[0])),
+        Op::Jump | Op::JumpIfFalse => Some(format!("-> {:04}", operands[0])),
+        Op::JumpIfBound => Some(format!(
+            "{} -> {:04}",
+            local(operands[0]).unwrap_or_default(),
+            operands[1]
+        )),
+        Op::Pop | Op::Call | Op::TailCall | Op::Return => None,
+    }
+}
+
+fn lambda_list(parameters: &Parameters) -> String {
+    let mut items: Vec<String> = parameters
+        .required
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    let sections = [
+        ("&optional", &parameters.optional),
+        ("&key", &parameters.keys),
+    ];
+    for (marker, section) in sections.iter() {
+        if section.is_empty() {
+            continue;
+        }
+        items.push(marker.to_string());
+        for (name, default) in section.iter() {
+            items.push(match default {
+                Object::Nil => name.to_string(),
+                default => format!("({} {})", name, default),
+            });
+        }
+    }
+    format!("({})", items.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode;
+    use crate::object::Environment;
+    use crate::reader;
+    use crate::vm;
+
+    #[test]
+    fn test_listing_shows_constants_operands_and_lines() {
+        let code = "(define fib\n  (lambda (n)\n    (if (< n 2)\n        n\n        (+ (fib (- n 1)) (fib (- n 2))))))";
+        let (exp, spans) = reader::read_with_span_trees(code).unwrap().remove(0);
+        let proto = bytecode::compile_with_spans(&exp, &spans).unwrap();
+        let listing = disassemble(&proto, "toplevel");
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "== toplevel ==");
+        assert!(lines.contains(&"  0000     2  closure        0     ; toplevel/0"));
+        assert!(lines.contains(&"== toplevel/0 (n) =="));
+        assert!(lines.contains(&"locals: n"));
+        assert!(lines.contains(&"     1  2"));
+        assert!(lines.contains(&"  0000     3  get-global     0     ; <"));
+        assert!(lines.contains(&"  0003     |  get-local      0     ; n"));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("jump-if-false") && line.ends_with("; -> 0020")));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("     5  get-global") && line.ends_with("; +")));
+    }
+
+    #[test]
+    fn test_listing_without_source_lines() {
+        let exp = reader::read("(lambda (x &optional (y 1)) (lambda () (set! x y)))")
+            .unwrap()
+            .remove(0);
+        let listing = disassemble(&bytecode::compile(&exp).unwrap(), "f");
+        assert!(listing.contains("== f/0 (x &optional (y 1)) =="));
+        assert!(listing.contains("jump-if-bound  1 17  ; y -> 0017"));
+        assert!(listing.contains("upvalues: y (local 1), x (local 0)"));
+        assert!(listing.contains("     -  set-upvalue    1     ; x"));
+    }
+
+    #[test]
+    fn test_only_compiled_procedures_disassemble() {
+        let env = Environment::new();
+        let f = vm::eval(
+            reader::read("(lambda (x) x)").unwrap().remove(0),
+            env.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            listing(&f),
+            Ok(String::from(
+                "== <lambda> (x) ==
+locals: x
+offset  line  instruction
+  0000     -  get-local      0     ; x
+  0003     -  return
+"
+            ))
+        );
+        assert_eq!(disassemble_native(&[f], env.clone()), Ok(Object::Nil));
+        assert!(disassemble_native(&[Object::Integer(1)], env.clone()).is_err());
+        let car = env.borrow().get("car");
+        assert!(disassemble_native(&[car], env).is_err());
+    }
+}
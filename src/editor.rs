@@ -0,0 +1,637 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use risp::object::EnvRef;
+use risp::reader::valid_symbol_char;
+
+const HISTORY_FILE: &str = ".risp_history";
+
+#[derive(Debug, PartialEq)]
+enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    KillToEnd,
+    KillToStart,
+    Clear,
+    Interrupt,
+    Eof,
+    Unknown,
+}
+
+fn read_byte<R: Read>(input: &mut R) -> io::Result<Option<u8>> {
+    let mut buf = [0; 1];
+    match input.read(&mut buf)? {
+        0 => Ok(None),
+        _ => Ok(Some(buf[0])),
+    }
+}
+
+fn read_escape_sequence<R: Read>(input: &mut R) -> io::Result<Key> {
+    match read_byte(input)? {
+        Some(b'[') | Some(b'O') => {}
+        _ => return Ok(Key::Unknown),
+    }
+
+    // Parameters and intermediates, like the `1;5` of Ctrl-Right's
+    // `ESC [ 1 ; 5 C`, run up to a final byte in `@` to `~`. The whole
+    // sequence is read so that none of it is taken for typed text.
+    let mut parameters = Vec::new();
+    let last = loop {
+        match read_byte(input)? {
+            Some(byte @ 0x40..=0x7e) => break byte,
+            Some(byte @ 0x20..=0x3f) => parameters.push(byte),
+            _ => return Ok(Key::Unknown),
+        }
+    };
+
+    let key = match last {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'H' => Key::Home,
+        b'F' => Key::End,
+        // Sequences like `ESC [ 3 ~` are terminated by a tilde.
+        b'~' => match parameters.split(|&b| b == b';').next() {
+            Some(b"1") | Some(b"7") => Key::Home,
+            Some(b"4") | Some(b"8") => Key::End,
+            Some(b"3") => Key::Delete,
+            _ => Key::Unknown,
+        },
+        _ => Key::Unknown,
+    };
+
+    Ok(key)
+}
+
+fn read_utf8_char<R: Read>(input: &mut R, first: u8) -> io::Result<Key> {
+    let len = match first {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Ok(Key::Unknown),
+    };
+
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        match read_byte(input)? {
+            Some(b) => bytes.push(b),
+            None => return Ok(Key::Unknown),
+        }
+    }
+
+    match std::str::from_utf8(&bytes)
+        .ok()
+        .and_then(|s| s.chars().next())
+    {
+        Some(c) => Ok(Key::Char(c)),
+        None => Ok(Key::Unknown),
+    }
+}
+
+fn read_key<R: Read>(input: &mut R) -> io::Result<Option<Key>> {
+    let byte = match read_byte(input)? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+
+    let key = match byte {
+        1 => Key::Home,
+        2 => Key::Left,
+        3 => Key::Interrupt,
+        4 => Key::Eof,
+        5 => Key::End,
+        6 => Key::Right,
+        8 | 127 => Key::Backspace,
+        9 => Key::Tab,
+        10 | 13 => Key::Enter,
+        11 => Key::KillToEnd,
+        12 => Key::Clear,
+        14 => Key::Down,
+        16 => Key::Up,
+        21 => Key::KillToStart,
+        27 => read_escape_sequence(input)?,
+        b if b < 32 => Key::Unknown,
+        b => read_utf8_char(input, b)?,
+    };
+
+    Ok(Some(key))
+}
+
+struct LineBuffer {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl LineBuffer {
+    fn new() -> LineBuffer {
+        LineBuffer {
+            chars: Vec::new(),
+            cursor: 0,
+        }
+    }
+
+    fn as_string(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    fn set(&mut self, line: &str) {
+        self.chars = line.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    fn insert_str(&mut self, s: &str) {
+        s.chars().for_each(|c| self.insert(c));
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn move_left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn move_right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+
+    fn kill_to_end(&mut self) {
+        self.chars.truncate(self.cursor);
+    }
+
+    fn kill_to_start(&mut self) {
+        self.chars.drain(..self.cursor);
+        self.cursor = 0;
+    }
+
+    fn word_before_cursor(&self) -> String {
+        let start = self.chars[..self.cursor]
+            .iter()
+            .rposition(|c| !valid_symbol_char(c))
+            .map_or(0, |i| i + 1);
+        self.chars[start..self.cursor].iter().collect()
+    }
+
+    /// Returns the position of the paren matching the one the cursor is on or
+    /// just behind, if there is one.
+    fn highlighted_paren(&self) -> Option<usize> {
+        if self.cursor > 0 {
+            if let Some(pos) = matching_paren(&self.chars, self.cursor - 1) {
+                return Some(pos);
+            }
+        }
+        matching_paren(&self.chars, self.cursor)
+    }
+}
+
+fn matching_paren(chars: &[char], pos: usize) -> Option<usize> {
+    let mut depth = 0;
+    match chars.get(pos) {
+        Some('(') => {
+            for (i, c) in chars.iter().enumerate().skip(pos) {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            None
+        }
+        Some(')') => {
+            for i in (0..=pos).rev() {
+                match chars[i] {
+                    ')' => depth += 1,
+                    '(' => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+fn completions(prefix: &str, names: &[String]) -> Vec<String> {
+    names
+        .iter()
+        .filter(|name| name.starts_with(prefix))
+        .cloned()
+        .collect()
+}
+
+fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix = match candidates.first() {
+        Some(first) => first.clone(),
+        None => return String::new(),
+    };
+
+    for candidate in candidates.iter().skip(1) {
+        let len = prefix
+            .chars()
+            .zip(candidate.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        prefix.truncate(len);
+    }
+
+    prefix
+}
+
+struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl History {
+    fn load(path: Option<PathBuf>) -> History {
+        let entries = match path {
+            Some(ref path) => fs::read_to_string(path)
+                .map(|contents| contents.lines().map(String::from).collect())
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        History { entries, path }
+    }
+
+    fn add(&mut self, line: &str) {
+        if line.trim().is_empty() || self.entries.last().map(String::as_str) == Some(line) {
+            return;
+        }
+
+        self.entries.push(line.to_string());
+
+        if let Some(ref path) = self.path {
+            // Losing a history entry is not worth interrupting the session.
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+        }
+    }
+}
+
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let output = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::other("stty -g failed"));
+        }
+
+        let saved = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Command::new("stty")
+            .args(["raw", "-echo"])
+            .stdin(Stdio::inherit())
+            .status()?;
+
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = Command::new("stty")
+            .arg(&self.saved)
+            .stdin(Stdio::inherit())
+            .status();
+    }
+}
+
+pub struct Editor {
+    history: History,
+}
+
+impl Editor {
+    pub fn new() -> Editor {
+        let path = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        Editor {
+            history: History::load(path),
+        }
+    }
+
+    /// Reads a line of input, returning `None` once the input is exhausted.
+    /// When stdin is not a terminal this falls back to plain buffered reads.
+    pub fn read_line(&mut self, prompt: &str, env: &EnvRef) -> io::Result<Option<String>> {
+        if !io::stdin().is_terminal() {
+            return read_line_plain(prompt);
+        }
+
+        let line = {
+            let _raw = RawMode::enable()?;
+            self.edit(prompt, env)?
+        };
+
+        if let Some(ref line) = line {
+            self.history.add(line);
+        }
+        Ok(line)
+    }
+
+    fn edit(&mut self, prompt: &str, env: &EnvRef) -> io::Result<Option<String>> {
+        let mut stdin = io::stdin();
+        let mut stdout = io::stdout();
+
+        let mut buffer = LineBuffer::new();
+        let mut history_pos = self.history.entries.len();
+        let mut draft = String::new();
+
+        render(&mut stdout, prompt, &buffer)?;
+
+        while let Some(key) = read_key(&mut stdin)? {
+            match key {
+                Key::Char(c) => buffer.insert(c),
+                Key::Enter => {
+                    write!(stdout, "\r\n")?;
+                    stdout.flush()?;
+                    return Ok(Some(buffer.as_string()));
+                }
+                Key::Tab => complete(&mut stdout, prompt, &mut buffer, env)?,
+                Key::Backspace => buffer.backspace(),
+                Key::Delete => buffer.delete(),
+                Key::Left => buffer.move_left(),
+                Key::Right => buffer.move_right(),
+                Key::Home => buffer.cursor = 0,
+                Key::End => buffer.cursor = buffer.chars.len(),
+                Key::KillToEnd => buffer.kill_to_end(),
+                Key::KillToStart => buffer.kill_to_start(),
+                Key::Up => {
+                    if history_pos > 0 {
+                        if history_pos == self.history.entries.len() {
+                            draft = buffer.as_string();
+                        }
+                        history_pos -= 1;
+                        buffer.set(&self.history.entries[history_pos]);
+                    }
+                }
+                Key::Down => {
+                    if history_pos < self.history.entries.len() {
+                        history_pos += 1;
+                        match self.history.entries.get(history_pos) {
+                            Some(entry) => buffer.set(entry),
+                            None => buffer.set(&draft),
+                        }
+                    }
+                }
+                Key::Clear => write!(stdout, "\x1b[H\x1b[2J")?,
+                Key::Interrupt => {
+                    write!(stdout, "^C\r\n")?;
+                    stdout.flush()?;
+                    return Ok(Some(String::new()));
+                }
+                Key::Eof => {
+                    if buffer.chars.is_empty() {
+                        write!(stdout, "\r\n")?;
+                        stdout.flush()?;
+                        return Ok(None);
+                    }
+                    buffer.delete();
+                }
+                Key::Unknown => {}
+            }
+
+            render(&mut stdout, prompt, &buffer)?;
+        }
+
+        Ok(None)
+    }
+}
+
+fn read_line_plain(prompt: &str) -> io::Result<Option<String>> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input)? {
+        0 => Ok(None),
+        _ => Ok(Some(input)),
+    }
+}
+
+fn render<W: Write>(out: &mut W, prompt: &str, buffer: &LineBuffer) -> io::Result<()> {
+    let highlight = buffer.highlighted_paren();
+
+    write!(out, "\r{}", prompt)?;
+    for (i, c) in buffer.chars.iter().enumerate() {
+        if Some(i) == highlight {
+            write!(out, "\x1b[1;7m{}\x1b[0m", c)?;
+        } else {
+            write!(out, "{}", c)?;
+        }
+    }
+    write!(out, "\x1b[K\r")?;
+
+    let column = prompt.chars().count() + buffer.cursor;
+    if column > 0 {
+        write!(out, "\x1b[{}C", column)?;
+    }
+    out.flush()
+}
+
+fn complete<W: Write>(
+    out: &mut W,
+    prompt: &str,
+    buffer: &mut LineBuffer,
+    env: &EnvRef,
+) -> io::Result<()> {
+    let prefix = buffer.word_before_cursor();
+    let candidates = completions(&prefix, &env.borrow().names());
+
+    match candidates.len() {
+        0 => write!(out, "\x07")?,
+        1 => buffer.insert_str(&candidates[0][prefix.len()..]),
+        _ => {
+            let common = common_prefix(&candidates);
+            if common.len() > prefix.len() {
+                buffer.insert_str(&common[prefix.len()..]);
+            } else {
+                write!(out, "\r\n{}\r\n", candidates.join("  "))?;
+                render(out, prompt, buffer)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risp::object::{Environment, Object};
+
+    fn keys(input: &[u8]) -> Vec<Key> {
+        let mut input = input;
+        let mut keys = Vec::new();
+        while let Some(key) = read_key(&mut input).unwrap() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    fn buffer_with(line: &str, cursor: usize) -> LineBuffer {
+        let mut buffer = LineBuffer::new();
+        buffer.set(line);
+        buffer.cursor = cursor;
+        buffer
+    }
+
+    #[test]
+    fn test_reading_keys() {
+        assert_eq!(
+            keys(b"a(\r\x7f\t"),
+            vec![
+                Key::Char('a'),
+                Key::Char('('),
+                Key::Enter,
+                Key::Backspace,
+                Key::Tab
+            ]
+        );
+        assert_eq!(
+            keys(b"\x1b[A\x1b[B\x1b[C\x1b[D\x1b[3~\x1b[H\x1b[4~"),
+            vec![
+                Key::Up,
+                Key::Down,
+                Key::Right,
+                Key::Left,
+                Key::Delete,
+                Key::Home,
+                Key::End
+            ]
+        );
+        assert_eq!(keys("λ".as_bytes()), vec![Key::Char('λ')]);
+    }
+
+    #[test]
+    fn test_escape_sequences_with_parameters_are_read_whole() {
+        assert_eq!(
+            keys(b"\x1b[1;5Cx\x1b[1;5D\x1b[3;2~\x1b[200~"),
+            vec![
+                Key::Right,
+                Key::Char('x'),
+                Key::Left,
+                Key::Delete,
+                Key::Unknown
+            ]
+        );
+    }
+
+    #[test]
+    fn test_line_buffer_editing() {
+        let mut buffer = LineBuffer::new();
+        buffer.insert_str("(+ 1 2)");
+        buffer.move_left();
+        buffer.backspace();
+        buffer.insert('3');
+        assert_eq!(buffer.as_string(), "(+ 1 3)");
+
+        buffer.cursor = 3;
+        buffer.kill_to_end();
+        assert_eq!(buffer.as_string(), "(+ ");
+
+        buffer.move_left();
+        buffer.kill_to_start();
+        assert_eq!(buffer.as_string(), " ");
+        assert_eq!(buffer.cursor, 0);
+    }
+
+    #[test]
+    fn test_matching_paren() {
+        let chars: Vec<char> = "(a (b c) d)".chars().collect();
+        assert_eq!(matching_paren(&chars, 0), Some(10));
+        assert_eq!(matching_paren(&chars, 10), Some(0));
+        assert_eq!(matching_paren(&chars, 3), Some(7));
+        assert_eq!(matching_paren(&chars, 7), Some(3));
+        assert_eq!(matching_paren(&chars, 1), None);
+
+        let chars: Vec<char> = "(a (b".chars().collect();
+        assert_eq!(matching_paren(&chars, 0), None);
+    }
+
+    #[test]
+    fn test_highlighted_paren() {
+        assert_eq!(buffer_with("(+ 1 2)", 7).highlighted_paren(), Some(0));
+        assert_eq!(buffer_with("(+ 1 2)", 0).highlighted_paren(), Some(6));
+        assert_eq!(buffer_with("(+ 1 2)", 3).highlighted_paren(), None);
+    }
+
+    #[test]
+    fn test_word_before_cursor() {
+        assert_eq!(buffer_with("(ca", 3).word_before_cursor(), "ca");
+        assert_eq!(buffer_with("(list-o 1)", 7).word_before_cursor(), "list-o");
+        assert_eq!(buffer_with("(", 1).word_before_cursor(), "");
+    }
+
+    #[test]
+    fn test_completion_from_environment() {
+        let parent = Environment::new();
+        parent
+            .borrow_mut()
+            .define("counter".to_string(), Object::Integer(1))
+            .unwrap();
+        let child = Environment::new_child(parent);
+        child
+            .borrow_mut()
+            .define("count-up".to_string(), Object::Integer(2))
+            .unwrap();
+
+        let names = child.borrow().names();
+        let candidates = completions("cou", &names);
+        assert_eq!(candidates, vec!["count-up", "counter"]);
+        assert_eq!(common_prefix(&candidates), "count");
+        assert_eq!(completions("counte", &names), vec!["counter"]);
+        assert!(completions("co", &names).contains(&String::from("cons")));
+    }
+
+    #[test]
+    fn test_history_skips_blank_and_repeated_lines() {
+        let mut history = History::load(None);
+        history.add("(+ 1 2)");
+        history.add("(+ 1 2)");
+        history.add("   ");
+        history.add("(list 1)");
+        assert_eq!(history.entries, vec!["(+ 1 2)", "(list 1)"]);
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+use crate::object::Object;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Type,
+    Arity,
+    Unbound,
+    Syntax,
+    Runtime,
+    User,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ErrorKind::Type => "type-error",
+            ErrorKind::Arity => "arity-error",
+            ErrorKind::Unbound => "unbound-variable",
+            ErrorKind::Syntax => "syntax-error",
+            ErrorKind::Runtime => "runtime-error",
+            ErrorKind::User => "error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The position of a form in its source, both 1-based.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// The value carried by `Object::Error`. `trace` holds the names of the
+/// lambdas the error unwound through, innermost first.
+#[derive(Clone, PartialEq)]
+pub struct ErrorObject {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub irritants: Vec<Object>,
+    pub span: Option<Span>,
+    pub trace: Vec<String>,
+    pub cause: Option<Object>,
+}
+
+impl ErrorObject {
+    pub fn new(kind: ErrorKind, message: &str, irritants: Vec<Object>) -> ErrorObject {
+        ErrorObject {
+            kind,
+            message: message.to_string(),
+            irritants,
+            span: None,
+            trace: Vec::new(),
+            cause: None,
+        }
+    }
+
+    /// Renders the error along with its location, trace and causes, one per
+    /// line, for showing to a user.
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        if let Some(span) = self.span {
+            report.push_str(&format!("\n  at {}", span));
+        }
+        // Runs of the same name, as left by deep recursion, are shown once.
+        let mut trace = self.trace.iter().peekable();
+        while let Some(name) = trace.next() {
+            let mut times = 1;
+            while trace.next_if_eq(&name).is_some() {
+                times += 1;
+            }
+            match times {
+                1 => report.push_str(&format!("\n  in {}", name)),
+                _ => report.push_str(&format!("\n  in {} ({} times)", name, times)),
+            }
+        }
+        if let Some(Object::Error(ref cause)) = self.cause {
+            report.push_str(&format!("\ncaused by: {}", cause.report()));
+        }
+        report
+    }
+}
+
+impl fmt::Display for ErrorObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)?;
+        for irritant in self.irritants.iter() {
+            write!(f, " {}", irritant)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ErrorObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)?;
+        if !self.trace.is_empty() {
+            write!(f, " in {}", self.trace.join(" < "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_report() {
+        let cause = Object::new_error(ErrorKind::Runtime, "disk on fire");
+        let mut error = ErrorObject::new(
+            ErrorKind::Type,
+            "argument has wrong type",
+            vec![Object::symbol("foo"), Object::Integer(1)],
+        );
+        error.span = Some(Span { line: 3, column: 5 });
+        error.trace = vec![
+            String::from("inner"),
+            String::from("outer"),
+            String::from("outer"),
+        ];
+        error.cause = Some(cause);
+
+        assert_eq!(
+            error.to_string(),
+            "type-error: argument has wrong type foo 1"
+        );
+        assert_eq!(
+            error.report(),
+            "type-error: argument has wrong type foo 1
+  at line 3, column 5
+  in inner
+  in outer (2 times)
+caused by: runtime-error: disk on fire"
+        );
+    }
+}
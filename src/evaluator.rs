@@ -1,138 +1,1321 @@
-use crate::object::{EnvRef, Environment, Function, Object};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 
-fn apply_lambda(lambda: &Function, args: &[Object]) -> Result<Object, Object> {
-    if let Function::Lambda(parameters, body, lambda_env) = lambda {
-        let application_env = Environment::new_child(lambda_env.clone());
+use crate::analysis;
+use crate::compiler::{self, Code, GuardClause, Node, Template};
+use crate::condition::{self, RestartClause, RestartInfo};
+use crate::error::ErrorKind;
+use crate::lazy::{self, Promise, PromiseState};
+use crate::object::{Arity, EnvRef, Environment, Function, Lambda, Object};
+use crate::parameters::Argument;
+use crate::persistent;
+use crate::symbol::Symbol;
+use crate::vm::{self, Closure, Exit, VmState};
 
-        for (i, p) in parameters.iter().enumerate() {
-            if let Object::Symbol(name) = p {
-                let result = application_env
-                    .borrow_mut()
-                    .define(name.to_string(), args[i].clone());
+/// Procedures that work on the evaluator's stack itself, so they cannot be
+/// written as natives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Primitive {
+    CallCC,
+    DynamicWind,
+    WithExceptionHandler,
+    Raise,
+    RaiseContinuable,
+    Signal,
+    InvokeRestart,
+    ComputeRestarts,
+    Force,
+}
 
-                if result.is_err() {
-                    return Err(Object::new_error(&format!(
-                        "failed to define {} in env",
-                        name
-                    )));
-                }
-            }
+impl Primitive {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Primitive::CallCC => "call/cc",
+            Primitive::DynamicWind => "dynamic-wind",
+            Primitive::WithExceptionHandler => "with-exception-handler",
+            Primitive::Raise => "raise",
+            Primitive::RaiseContinuable => "raise-continuable",
+            Primitive::Signal => "signal",
+            Primitive::InvokeRestart => "invoke-restart",
+            Primitive::ComputeRestarts => "compute-restarts",
+            Primitive::Force => "force",
         }
+    }
 
-        body.iter()
-            .map(|e| eval(e.clone(), application_env.clone()))
-            .last()
-            .unwrap()
-    } else {
-        Err(Object::new_error(&format!(
-            "lambda is not a Function::Lambda: {}",
-            lambda
-        )))
+    fn arity(&self) -> Arity {
+        match self {
+            Primitive::CallCC
+            | Primitive::Raise
+            | Primitive::RaiseContinuable
+            | Primitive::Signal
+            | Primitive::Force => Arity::Exact(1),
+            Primitive::DynamicWind => Arity::Exact(3),
+            Primitive::WithExceptionHandler => Arity::Exact(2),
+            Primitive::InvokeRestart => Arity::AtLeast(1),
+            Primitive::ComputeRestarts => Arity::Exact(0),
+        }
     }
 }
 
-pub fn apply(proc: &Object, args: &[Object], env: EnvRef) -> Result<Object, Object> {
-    match proc {
-        Object::Callable(func) => match func {
-            Function::Native(builtin) => builtin(args, env),
-            Function::Lambda(_, _, _) => apply_lambda(func, args),
-        },
-        _ => Err(Object::new_error("cannot call non-function")),
+/// The rest of a computation, as captured by `call/cc`. Invoking it
+/// reinstates a copy of the frames, so it can be resumed any number of times.
+/// Continuations captured by `shift` only hold the frames up to the nearest
+/// `reset`, and `base` is the depth they were taken from; invoking one
+/// composes its frames onto the current stack instead of replacing it.
+pub struct Continuation {
+    run: usize,
+    frames: Vec<Frame>,
+    base: Option<usize>,
+}
+
+/// The most frames a run, or the VM within it, may hold. Going past it is a
+/// stack overflow error, which handlers can catch: they get another
+/// `OVERFLOW_ROOM` frames to do so, past which the run fails outright.
+pub(crate) const MAX_FRAMES: usize = 100_000;
+const OVERFLOW_ROOM: usize = 10_000;
+
+/// The most lambda names an error's trace holds.
+const TRACE_LIMIT: usize = 100;
+
+pub(crate) fn stack_overflow() -> Object {
+    Object::new_error(ErrorKind::Runtime, "stack overflow")
+}
+
+/// How much of the Rust stack runs nested in each other may take, as when
+/// a native such as `vector-map` calls a procedure that calls it again. It
+/// is half the stack that Rust gives a new thread.
+const MAX_NESTED_STACK: usize = 1 << 20;
+
+thread_local! {
+    static NEXT_ID: Cell<usize> = const { Cell::new(0) };
+    static ACTIVE_RUNS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    /// Where the stack was when the outermost active run started.
+    static STACK_BASE: Cell<usize> = const { Cell::new(0) };
+}
+
+fn stack_position() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+/// Fails with a stack overflow error if starting another run would take
+/// the runs already active past `MAX_NESTED_STACK`.
+fn check_nesting() -> Result<(), Object> {
+    let nested = ACTIVE_RUNS.with(|runs| !runs.borrow().is_empty());
+    let used = STACK_BASE.with(|base| base.get().abs_diff(stack_position()));
+    if nested && used > MAX_NESTED_STACK {
+        return Err(stack_overflow());
     }
+    Ok(())
 }
 
-pub fn eval(exp: Object, env: EnvRef) -> Result<Object, Object> {
-    match exp {
-        Object::Nil | Object::Integer(_) | Object::Callable(_) | Object::Error(_) => Ok(exp),
-        Object::Symbol(name) => Ok(env.borrow().get(&name)),
-        Object::List(elems) => {
-            if is_definition(&elems) {
-                return make_definition(&elems, env.clone());
-            }
+fn next_id() -> usize {
+    NEXT_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    })
+}
 
-            if is_lambda(&elems) {
-                return make_lambda(&elems, env.clone());
-            }
+fn is_active(run: usize) -> bool {
+    ACTIVE_RUNS.with(|runs| runs.borrow().contains(&run))
+}
+
+/// Carries a continuation invocation out of a nested run, such as one started
+/// by a native calling `apply`, to the run that captured the continuation.
+struct Escape {
+    continuation: Rc<Continuation>,
+    value: Object,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// `raise` and errors: a handler that returns is itself an error.
+    Raise,
+    /// `raise-continuable`: the handler's value is returned to the raiser.
+    Continuable,
+    /// `signal`: only `handler-bind` handlers are consulted.
+    Signal,
+}
 
-            let mut iter = elems.into_iter();
-            let proc = eval(iter.next().unwrap(), env.clone())?;
+/// What to do once the stack has been unwound to its target depth.
+#[derive(Clone)]
+enum Then {
+    Catch(Object),
+    Restart { clause: usize, args: Vec<Object> },
+    Reenter(Rc<Continuation>, Object),
+    Fail(Object),
+}
+
+/// What remains to be done with the value of the expression being evaluated.
+/// Frames that are only there to be found (handlers, guards, restarts,
+/// winds) pass values through unchanged.
+#[derive(Clone)]
+enum Frame {
+    Call {
+        exps: Rc<[Code]>,
+        values: Vec<Object>,
+        env: EnvRef,
+    },
+    Sequence {
+        body: Rc<[Code]>,
+        next: usize,
+        env: EnvRef,
+    },
+    Define {
+        name: Symbol,
+        env: EnvRef,
+    },
+    Assign {
+        name: Symbol,
+        env: EnvRef,
+    },
+    /// Evaluating the default of `bindings[next - 1]`.
+    Bind {
+        bindings: Rc<[(Symbol, Argument)]>,
+        next: usize,
+        template: Rc<Template>,
+        env: EnvRef,
+    },
+    If {
+        consequent: Code,
+        alternative: Option<Code>,
+        env: EnvRef,
+    },
+    /// Marks the body of a lambda, for error traces. Tail calls replace it
+    /// rather than pushing another one.
+    Return {
+        name: &'static str,
+    },
+    WindBefore {
+        before: Object,
+        thunk: Object,
+        after: Object,
+    },
+    Wind {
+        id: usize,
+        before: Object,
+        after: Object,
+    },
+    WindAfter {
+        value: Object,
+    },
+    Handler {
+        handler: Object,
+    },
+    BindHandlers {
+        types: Vec<String>,
+        exps: Rc<[Code]>,
+        handlers: Vec<(String, Object)>,
+        body: Rc<[Code]>,
+        env: EnvRef,
+    },
+    HandlerBind {
+        clauses: Rc<[(String, Object)]>,
+    },
+    Guard {
+        var: Symbol,
+        clauses: Rc<[GuardClause]>,
+        env: EnvRef,
+    },
+    GuardClause {
+        clauses: Rc<[GuardClause]>,
+        index: usize,
+        condition: Object,
+        env: EnvRef,
+    },
+    GuardReceiver {
+        value: Object,
+    },
+    RestartCase {
+        clauses: Rc<[RestartClause]>,
+        env: EnvRef,
+    },
+    /// Hides the handlers at and above `depth` while a handler runs.
+    Mask {
+        depth: usize,
+    },
+    /// Continues looking for handlers once a `handler-bind` handler declines.
+    Signal {
+        condition: Object,
+        depth: usize,
+        clause: usize,
+        mode: Mode,
+    },
+    NonContinuable {
+        condition: Object,
+        depth: usize,
+    },
+    Unwinding {
+        target: usize,
+        then: Then,
+    },
+    Rewinding {
+        continuation: Rc<Continuation>,
+        wind: usize,
+        value: Object,
+    },
+    /// Delimits the continuation captured by `shift`.
+    Prompt,
+    Forcing {
+        promise: Rc<Promise>,
+        chained: bool,
+    },
+    /// Compiled code waiting for the value of a call.
+    Vm(VmState),
+}
 
-            let mut args: Vec<Object> = Vec::new();
-            for a in iter {
-                let result = eval(a.clone(), env.clone())?;
-                args.push(result)
+impl Frame {
+    /// Moves a frame captured by `shift` from depth `from` to depth `to`,
+    /// adjusting the depths it refers to within the captured frames.
+    fn relocated(&self, from: usize, to: usize) -> Frame {
+        let move_depth = |depth: usize| {
+            if depth >= from {
+                depth - from + to
+            } else {
+                depth
             }
+        };
 
-            apply(&proc, &args, env.clone())
+        match self.clone() {
+            Frame::Mask { depth } => Frame::Mask {
+                depth: move_depth(depth),
+            },
+            Frame::Signal {
+                condition,
+                depth,
+                clause,
+                mode,
+            } => Frame::Signal {
+                condition,
+                depth: move_depth(depth),
+                clause,
+                mode,
+            },
+            Frame::NonContinuable { condition, depth } => Frame::NonContinuable {
+                condition,
+                depth: move_depth(depth),
+            },
+            Frame::Unwinding { target, then } => Frame::Unwinding {
+                target: move_depth(target),
+                then,
+            },
+            frame => frame,
         }
     }
 }
 
-fn is_lambda(exps: &[Object]) -> bool {
-    match exps.first().and_then(|o| o.has_symbol_value("lambda")) {
-        Some(b) => b,
-        None => false,
+enum Control {
+    Eval(Code, EnvRef),
+    Return(Object),
+    Apply(Object, Vec<Object>),
+    Raise(Object, Mode),
+    Unwind(usize, Then),
+    Fail(Object),
+}
+
+/// Evaluates with an explicit stack of frames instead of recursing on the
+/// Rust stack, so that `call/cc` can capture and reinstate the rest of a
+/// computation. Each call to `eval` or `apply` is a separate run.
+struct Machine {
+    run: usize,
+    env: EnvRef,
+    frames: Vec<Frame>,
+    /// `MAX_FRAMES`, or more while a stack overflow is being handled.
+    limit: usize,
+}
+
+impl Drop for Machine {
+    fn drop(&mut self) {
+        ACTIVE_RUNS.with(|runs| runs.borrow_mut().retain(|run| *run != self.run));
     }
 }
 
-fn make_lambda(exps: &[Object], env: EnvRef) -> Result<Object, Object> {
-    let args = match &exps[1] {
-        Object::List(args) => args.clone(),
-        _ => return Err(Object::new_error("arguments are not a list")),
+fn wind_depths(frames: &[Frame]) -> Vec<usize> {
+    frames
+        .iter()
+        .enumerate()
+        .filter(|(_, frame)| matches!(frame, Frame::Wind { .. }))
+        .map(|(depth, _)| depth)
+        .collect()
+}
+
+/// Counts the `dynamic-wind` extents that two stacks share, outermost first.
+fn common_winds(a: &[Frame], b: &[Frame]) -> usize {
+    let ids = |frames: &[Frame]| -> Vec<usize> {
+        frames
+            .iter()
+            .filter_map(|frame| match frame {
+                Frame::Wind { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect()
     };
 
-    let body = vec![exps[2].clone()];
-    let lambda = Object::Callable(Function::Lambda(args, body, env.clone()));
-    Ok(lambda)
+    ids(a)
+        .iter()
+        .zip(ids(b).iter())
+        .take_while(|(x, y)| x == y)
+        .count()
 }
 
-fn is_definition(exps: &[Object]) -> bool {
-    match exps.first().and_then(|o| o.has_symbol_value("define")) {
-        Some(b) => b,
-        None => false,
+impl Machine {
+    fn new(env: EnvRef) -> Machine {
+        let run = next_id();
+        ACTIVE_RUNS.with(|runs| {
+            let mut runs = runs.borrow_mut();
+            if runs.is_empty() {
+                STACK_BASE.with(|base| base.set(stack_position()));
+            }
+            runs.push(run);
+        });
+        Machine {
+            run,
+            env,
+            frames: Vec::new(),
+            limit: MAX_FRAMES,
+        }
+    }
+
+    fn run(&mut self, mut control: Control) -> Result<Object, Object> {
+        loop {
+            let next = match control {
+                Control::Eval(exp, env) => self.eval(exp, env),
+                Control::Return(value) => match self.frames.pop() {
+                    Some(frame) => self.resume(frame, value),
+                    None => return Ok(value),
+                },
+                Control::Apply(proc, args) => self.apply(proc, args),
+                Control::Raise(condition, mode) => Ok(self.raise(condition, mode)),
+                Control::Unwind(target, then) => self.unwind(target, then),
+                Control::Fail(condition) => return Err(condition),
+            };
+            control = next.unwrap_or_else(|e| Control::Raise(e, Mode::Raise));
+
+            if self.frames.len() > self.limit {
+                control = self.overflow();
+            } else if self.limit != MAX_FRAMES && self.frames.len() < MAX_FRAMES {
+                self.limit = MAX_FRAMES;
+            }
+        }
+    }
+
+    /// Raises a stack overflow error, or, if one is already being handled,
+    /// gives up on the run.
+    fn overflow(&mut self) -> Control {
+        if self.limit > MAX_FRAMES {
+            return Control::Unwind(0, Then::Fail(stack_overflow()));
+        }
+        self.limit = MAX_FRAMES + OVERFLOW_ROOM;
+        Control::Raise(stack_overflow(), Mode::Raise)
+    }
+
+    fn eval(&mut self, code: Code, env: EnvRef) -> Result<Control, Object> {
+        if let Some(value) = eval_simple(&code, &env)? {
+            return Ok(Control::Return(value));
+        }
+
+        match &*code {
+            Node::Constant(_) | Node::Variable(_) | Node::Local(_) | Node::Global(_) => {
+                unreachable!("simple nodes are evaluated on the spot")
+            }
+            Node::Define(name, value) => {
+                self.frames.push(Frame::Define {
+                    name: *name,
+                    env: env.clone(),
+                });
+                Ok(Control::Eval(value.clone(), env))
+            }
+            Node::Set(name, value) => {
+                self.frames.push(Frame::Assign {
+                    name: *name,
+                    env: env.clone(),
+                });
+                Ok(Control::Eval(value.clone(), env))
+            }
+            Node::If(test, consequent, alternative) => match eval_simple(test, &env)? {
+                Some(value) => Ok(branch(value, consequent, alternative, env)),
+                None => {
+                    self.frames.push(Frame::If {
+                        consequent: consequent.clone(),
+                        alternative: alternative.clone(),
+                        env: env.clone(),
+                    });
+                    Ok(Control::Eval(test.clone(), env))
+                }
+            },
+            Node::Lambda(template) => {
+                let lambda = Lambda {
+                    name: None,
+                    template: template.clone(),
+                    env,
+                };
+                Ok(Control::Return(Object::Callable(Function::Lambda(lambda))))
+            }
+            Node::Sequence(body) => Ok(self.eval_body(body.clone(), env)),
+            Node::Call(exps) => {
+                self.eval_operands(exps.clone(), Vec::with_capacity(exps.len()), env)
+            }
+            Node::Guard { var, clauses, body } => {
+                self.frames.push(Frame::Guard {
+                    var: *var,
+                    clauses: clauses.clone(),
+                    env: env.clone(),
+                });
+                Ok(self.eval_body(body.clone(), env))
+            }
+            Node::HandlerBind {
+                types,
+                handlers,
+                body,
+            } => Ok(self.eval_handler_bind(types, handlers, body, env)),
+            Node::RestartCase { expr, clauses } => {
+                self.frames.push(Frame::RestartCase {
+                    clauses: clauses.clone(),
+                    env: env.clone(),
+                });
+                Ok(Control::Eval(expr.clone(), env))
+            }
+            Node::Reset(body) => {
+                self.frames.push(Frame::Prompt);
+                Ok(self.eval_body(body.clone(), env))
+            }
+            Node::Shift(name, body) => self.eval_shift(*name, body, env),
+            Node::Delay { exp, chained } => {
+                let promise = Promise::delayed(exp.clone(), env, *chained);
+                Ok(Control::Return(lazy::new_promise(promise)))
+            }
+            Node::StreamCons(head, tail) => {
+                let head = Promise::delayed(head.clone(), env.clone(), false);
+                let tail = Promise::delayed(tail.clone(), env, true);
+                let pair = Object::List(vec![lazy::new_promise(head), lazy::new_promise(tail)]);
+                Ok(Control::Return(lazy::new_promise(Promise::done(pair))))
+            }
+            Node::Error(error) => Err(error.clone()),
+        }
+    }
+
+    /// Evaluates the operator and operands of a call from `values.len()` on,
+    /// then applies the operator. Only operands that are not simple need a
+    /// frame to come back to.
+    fn eval_operands(
+        &mut self,
+        exps: Rc<[Code]>,
+        mut values: Vec<Object>,
+        env: EnvRef,
+    ) -> Result<Control, Object> {
+        while let Some(exp) = exps.get(values.len()) {
+            match eval_simple(exp, &env)? {
+                Some(value) => values.push(value),
+                None => {
+                    let exp = exp.clone();
+                    self.frames.push(Frame::Call {
+                        exps,
+                        values,
+                        env: env.clone(),
+                    });
+                    return Ok(Control::Eval(exp, env));
+                }
+            }
+        }
+
+        let proc = values.remove(0);
+        Ok(Control::Apply(proc, values))
+    }
+
+    fn eval_body(&mut self, body: Rc<[Code]>, env: EnvRef) -> Control {
+        match body.len() {
+            0 => Control::Return(Object::Nil),
+            1 => Control::Eval(body[0].clone(), env),
+            _ => {
+                let first = body[0].clone();
+                self.frames.push(Frame::Sequence {
+                    body,
+                    next: 1,
+                    env: env.clone(),
+                });
+                Control::Eval(first, env)
+            }
+        }
+    }
+
+    fn eval_handler_bind(
+        &mut self,
+        types: &[String],
+        handlers: &Rc<[Code]>,
+        body: &Rc<[Code]>,
+        env: EnvRef,
+    ) -> Control {
+        if handlers.is_empty() {
+            self.frames.push(Frame::HandlerBind {
+                clauses: Rc::from(Vec::new()),
+            });
+            return self.eval_body(body.clone(), env);
+        }
+
+        self.frames.push(Frame::BindHandlers {
+            types: types.to_vec(),
+            exps: handlers.clone(),
+            handlers: Vec::new(),
+            body: body.clone(),
+            env: env.clone(),
+        });
+        Control::Eval(handlers[0].clone(), env)
+    }
+
+    /// Evaluates `(shift k body...)`: removes the frames up to the nearest
+    /// `reset` and evaluates the body with `k` bound to them, as a procedure
+    /// that composes them back onto the stack.
+    fn eval_shift(
+        &mut self,
+        name: Symbol,
+        body: &Rc<[Code]>,
+        env: EnvRef,
+    ) -> Result<Control, Object> {
+        let base = match self
+            .frames
+            .iter()
+            .rposition(|frame| matches!(frame, Frame::Prompt))
+        {
+            Some(prompt) => prompt + 1,
+            None => {
+                return Err(Object::new_error(
+                    ErrorKind::Runtime,
+                    "shift: no enclosing reset",
+                ))
+            }
+        };
+
+        let continuation = Continuation {
+            run: self.run,
+            frames: self.frames.split_off(base),
+            base: Some(base),
+        };
+        let shift_env = Environment::new_child(env);
+        shift_env.borrow_mut().define(
+            name,
+            Object::Callable(Function::Continuation(Rc::new(continuation))),
+        )?;
+        Ok(self.eval_body(body.clone(), shift_env))
+    }
+
+    fn force(&mut self, obj: Object) -> Control {
+        let promise = match obj {
+            Object::Promise(promise) => promise,
+            other => return Control::Return(other),
+        };
+
+        match promise.state() {
+            PromiseState::Done(value) => Control::Return(value),
+            PromiseState::Delayed { exp, env, chained } => {
+                self.frames.push(Frame::Forcing { promise, chained });
+                Control::Eval(exp, env)
+            }
+        }
+    }
+
+    fn compose(&mut self, continuation: &Continuation, base: usize, value: Object) -> Control {
+        self.frames.push(Frame::Prompt);
+        let to = self.frames.len();
+        self.frames.extend(
+            continuation
+                .frames
+                .iter()
+                .map(|frame| frame.relocated(base, to)),
+        );
+        Control::Return(value)
+    }
+
+    fn apply(&mut self, proc: Object, args: Vec<Object>) -> Result<Control, Object> {
+        let func = match proc {
+            Object::Callable(func) => func,
+            Object::Keyword(keyword) => {
+                return persistent::apply_keyword(keyword, &args).map(Control::Return)
+            }
+            other => return Err(Object::type_error("cannot call non-function", &other)),
+        };
+
+        match func {
+            Function::Native(builtin) => builtin(&args, self.env.clone()).map(Control::Return),
+            Function::NativeClosure(closure) => {
+                closure.call(&args, self.env.clone()).map(Control::Return)
+            }
+            Function::Lambda(lambda) => self.apply_lambda(lambda, args),
+            Function::Compiled(closure) => self.apply_compiled(closure, args),
+            Function::Primitive(primitive) => self.apply_primitive(primitive, args),
+            Function::Continuation(continuation) => {
+                let value = match args.len() {
+                    0 => Object::Nil,
+                    1 => args.into_iter().next().unwrap(),
+                    n => return Err(Arity::Range(0, 1).error("continuation", n)),
+                };
+                match continuation.base {
+                    Some(base) => Ok(self.compose(&continuation, base, value)),
+                    None => Ok(self.reenter(continuation, value)),
+                }
+            }
+        }
+    }
+
+    fn apply_lambda(&mut self, lambda: Lambda, args: Vec<Object>) -> Result<Control, Object> {
+        let name = lambda.display_name();
+        match self.frames.last_mut() {
+            Some(Frame::Return { name: current }) => *current = name,
+            _ => self.frames.push(Frame::Return { name }),
+        }
+
+        let template = &lambda.template;
+        let parameters = &template.parameters;
+        if parameters.is_simple() && args.len() != parameters.required.len() {
+            return Err(parameters.arity_error(lambda.display_name(), args.len()));
+        }
+
+        // The parameters come first in the frame, so required arguments fill
+        // their slots in order.
+        if parameters.is_simple() {
+            let application_env =
+                Environment::new_frame(lambda.env.clone(), template.slots.clone(), args);
+            return Ok(self.eval_body(template.body.clone(), application_env));
+        }
+
+        let bindings = parameters.bind(lambda.display_name(), args)?;
+        let application_env =
+            Environment::new_frame(lambda.env.clone(), template.slots.clone(), Vec::new());
+        self.bind_arguments(Rc::from(bindings), 0, template.clone(), application_env)
+    }
+
+    fn apply_compiled(
+        &mut self,
+        closure: Rc<Closure>,
+        args: Vec<Object>,
+    ) -> Result<Control, Object> {
+        // Like a lambda, a closure called in tail position replaces its
+        // caller in traces.
+        if let Some(Frame::Return { .. }) = self.frames.last() {
+            self.frames.pop();
+        }
+
+        let mut state = VmState::default();
+        if let Err(e) = state.enter(closure, args) {
+            self.frames.push(Frame::Vm(state));
+            return Err(e);
+        }
+        self.run_vm(state)
+    }
+
+    fn run_vm(&mut self, mut state: VmState) -> Result<Control, Object> {
+        let exit = state.run(&self.env);
+        if !state.is_empty() {
+            self.frames.push(Frame::Vm(state));
+        }
+
+        match exit {
+            Exit::Return(value) => Ok(Control::Return(value)),
+            Exit::Apply(proc, args) => Ok(Control::Apply(proc, args)),
+            Exit::Error(e) => Err(e),
+        }
+    }
+
+    /// Binds parameters in order, stopping to evaluate each default in the
+    /// environment holding the parameters before it, then runs the body.
+    fn bind_arguments(
+        &mut self,
+        bindings: Rc<[(Symbol, Argument)]>,
+        mut next: usize,
+        template: Rc<Template>,
+        env: EnvRef,
+    ) -> Result<Control, Object> {
+        while let Some((name, argument)) = bindings.get(next) {
+            next += 1;
+            match argument {
+                Argument::Given(value) => {
+                    env.borrow_mut().define(*name, value.clone())?;
+                }
+                Argument::Default(default) => {
+                    let exp = template.defaults[*default].clone();
+                    self.frames.push(Frame::Bind {
+                        bindings,
+                        next,
+                        template,
+                        env: env.clone(),
+                    });
+                    return Ok(Control::Eval(exp, env));
+                }
+            }
+        }
+        Ok(self.eval_body(template.body.clone(), env))
+    }
+
+    fn apply_primitive(
+        &mut self,
+        primitive: Primitive,
+        mut args: Vec<Object>,
+    ) -> Result<Control, Object> {
+        primitive.arity().check(primitive.name(), &args)?;
+
+        match primitive {
+            Primitive::CallCC => {
+                let continuation = Rc::new(Continuation {
+                    run: self.run,
+                    frames: self.frames.clone(),
+                    base: None,
+                });
+                let receiver = args.remove(0);
+                Ok(Control::Apply(
+                    receiver,
+                    vec![Object::Callable(Function::Continuation(continuation))],
+                ))
+            }
+            Primitive::DynamicWind => {
+                let after = args.pop().unwrap();
+                let thunk = args.pop().unwrap();
+                let before = args.pop().unwrap();
+                self.frames.push(Frame::WindBefore {
+                    before: before.clone(),
+                    thunk,
+                    after,
+                });
+                Ok(Control::Apply(before, Vec::new()))
+            }
+            Primitive::WithExceptionHandler => {
+                let thunk = args.pop().unwrap();
+                let handler = args.pop().unwrap();
+                self.frames.push(Frame::Handler { handler });
+                Ok(Control::Apply(thunk, Vec::new()))
+            }
+            Primitive::Raise => Ok(Control::Raise(args.remove(0), Mode::Raise)),
+            Primitive::RaiseContinuable => Ok(Control::Raise(args.remove(0), Mode::Continuable)),
+            Primitive::Signal => Ok(Control::Raise(args.remove(0), Mode::Signal)),
+            Primitive::InvokeRestart => {
+                let name = match &args[0] {
+                    Object::Symbol(name) => name.to_string(),
+                    other => {
+                        return Err(Object::type_error(
+                            "invoke-restart: restart name is not a symbol",
+                            other,
+                        ))
+                    }
+                };
+
+                match self
+                    .restarts()
+                    .into_iter()
+                    .find(|(_, _, info)| info.name == name)
+                {
+                    Some((depth, clause, _)) => Ok(Control::Unwind(
+                        depth + 1,
+                        Then::Restart {
+                            clause,
+                            args: args.split_off(1),
+                        },
+                    )),
+                    None => Err(Object::new_error_with(
+                        ErrorKind::Runtime,
+                        "invoke-restart: no such restart is active",
+                        vec![args[0].clone()],
+                    )),
+                }
+            }
+            Primitive::Force => Ok(self.force(args.remove(0))),
+            Primitive::ComputeRestarts => {
+                let names = self
+                    .restarts()
+                    .into_iter()
+                    .map(|(_, _, info)| Object::symbol(&info.name))
+                    .collect();
+                Ok(Control::Return(Object::List(names)))
+            }
+        }
+    }
+
+    fn resume(&mut self, frame: Frame, value: Object) -> Result<Control, Object> {
+        match frame {
+            Frame::Call {
+                exps,
+                mut values,
+                env,
+            } => {
+                values.push(value);
+                self.eval_operands(exps, values, env)
+            }
+            Frame::Sequence { body, next, env } => {
+                let exp = body[next].clone();
+                if next + 1 < body.len() {
+                    self.frames.push(Frame::Sequence {
+                        body,
+                        next: next + 1,
+                        env: env.clone(),
+                    });
+                }
+                Ok(Control::Eval(exp, env))
+            }
+            Frame::Define { name, env } => env
+                .borrow_mut()
+                .define(name, named(value, name))
+                .map(|_| Control::Return(Object::Nil))
+                .map_err(|e| {
+                    Object::new_error(ErrorKind::Runtime, "defining failed").with_cause(e)
+                }),
+            Frame::Bind {
+                bindings,
+                next,
+                template,
+                env,
+            } => {
+                env.borrow_mut().define(bindings[next - 1].0, value)?;
+                self.bind_arguments(bindings, next, template, env)
+            }
+            Frame::Assign { name, env } => {
+                env.borrow_mut().set(name, value)?;
+                Ok(Control::Return(Object::Nil))
+            }
+            Frame::If {
+                consequent,
+                alternative,
+                env,
+            } => Ok(branch(value, &consequent, &alternative, env)),
+            Frame::WindBefore {
+                before,
+                thunk,
+                after,
+            } => {
+                self.frames.push(Frame::Wind {
+                    id: next_id(),
+                    before,
+                    after,
+                });
+                Ok(Control::Apply(thunk, Vec::new()))
+            }
+            Frame::Wind { after, .. } => {
+                self.frames.push(Frame::WindAfter { value });
+                Ok(Control::Apply(after, Vec::new()))
+            }
+            Frame::WindAfter { value } => Ok(Control::Return(value)),
+            Frame::BindHandlers {
+                types,
+                exps,
+                mut handlers,
+                body,
+                env,
+            } => {
+                handlers.push((types[handlers.len()].clone(), value));
+                if handlers.len() < exps.len() {
+                    let next = exps[handlers.len()].clone();
+                    self.frames.push(Frame::BindHandlers {
+                        types,
+                        exps,
+                        handlers,
+                        body,
+                        env: env.clone(),
+                    });
+                    return Ok(Control::Eval(next, env));
+                }
+
+                self.frames.push(Frame::HandlerBind {
+                    clauses: Rc::from(handlers),
+                });
+                Ok(self.eval_body(body, env))
+            }
+            Frame::GuardClause {
+                clauses,
+                index,
+                condition,
+                env,
+            } => {
+                if value.is_truthy() {
+                    Ok(self.guard_consequent(&clauses[index], value, env))
+                } else {
+                    Ok(self.guard_clause(clauses, index + 1, condition, env))
+                }
+            }
+            Frame::GuardReceiver { value: test } => Ok(Control::Apply(value, vec![test])),
+            Frame::Signal {
+                condition,
+                depth,
+                clause,
+                mode,
+            } => Ok(self.scan(condition, depth + 1, clause, mode)),
+            Frame::NonContinuable { condition, depth } => {
+                // The secondary error is raised where the handler ran, with
+                // the handler itself still hidden.
+                self.frames.push(Frame::Mask { depth });
+                Ok(Control::Raise(
+                    Object::new_error_with(
+                        ErrorKind::Runtime,
+                        "handler returned from non-continuable raise",
+                        vec![condition],
+                    ),
+                    Mode::Raise,
+                ))
+            }
+            Frame::Unwinding { target, then } => self.unwind(target, then),
+            Frame::Rewinding {
+                continuation,
+                wind,
+                value,
+            } => Ok(self.rewind(continuation, wind, value)),
+            Frame::Return { .. }
+            | Frame::Handler { .. }
+            | Frame::HandlerBind { .. }
+            | Frame::Guard { .. }
+            | Frame::RestartCase { .. }
+            | Frame::Mask { .. }
+            | Frame::Prompt => Ok(Control::Return(value)),
+            Frame::Forcing { promise, chained } => {
+                match value {
+                    Object::Promise(ref next) if chained => promise.adopt(next),
+                    value => promise.resolve(value),
+                }
+                Ok(self.force(Object::Promise(promise)))
+            }
+            Frame::Vm(mut state) => {
+                state.push(value);
+                self.run_vm(state)
+            }
+        }
+    }
+
+    /// Evaluates the guard clauses from `index` on, re-raising the condition
+    /// when none of them match.
+    fn guard_clause(
+        &mut self,
+        clauses: Rc<[GuardClause]>,
+        index: usize,
+        condition: Object,
+        env: EnvRef,
+    ) -> Control {
+        let test = match clauses.get(index) {
+            None => return Control::Raise(condition, Mode::Raise),
+            Some(clause) => clause.test.clone(),
+        };
+
+        match test {
+            None => self.guard_consequent(&clauses[index], Object::Boolean(true), env),
+            Some(test) => {
+                self.frames.push(Frame::GuardClause {
+                    clauses,
+                    index,
+                    condition,
+                    env: env.clone(),
+                });
+                Control::Eval(test, env)
+            }
+        }
+    }
+
+    fn guard_consequent(&mut self, clause: &GuardClause, test: Object, env: EnvRef) -> Control {
+        if let Some(receiver) = &clause.receiver {
+            self.frames.push(Frame::GuardReceiver { value: test });
+            return Control::Eval(receiver.clone(), env);
+        }
+
+        if clause.body.is_empty() {
+            return Control::Return(test);
+        }
+        self.eval_body(clause.body.clone(), env)
+    }
+
+    /// The active restarts, innermost first, with the depth of the
+    /// `restart-case` frame and the index of the clause that establishes them.
+    fn restarts(&self) -> Vec<(usize, usize, RestartInfo)> {
+        let mut restarts = Vec::new();
+        for (depth, frame) in self.frames.iter().enumerate().rev() {
+            if let Frame::RestartCase { clauses, .. } = frame {
+                for (i, clause) in clauses.iter().enumerate() {
+                    restarts.push((depth, i, clause.info.clone()));
+                }
+            }
+        }
+        restarts
+    }
+
+    /// The names of the lambdas being run, innermost first. Past
+    /// `TRACE_LIMIT` of them the rest are left out for a `...`, so that an
+    /// error raised deep in a recursion stays cheap to copy.
+    fn trace(&self) -> Vec<String> {
+        let mut trace = Vec::new();
+        for frame in self.frames.iter().rev() {
+            match frame {
+                Frame::Return { name } => trace.push(name.to_string()),
+                Frame::Vm(state) => {
+                    trace.extend(state.names().take(TRACE_LIMIT + 1).map(str::to_string))
+                }
+                _ => {}
+            }
+            if trace.len() > TRACE_LIMIT {
+                trace.truncate(TRACE_LIMIT);
+                trace.push(String::from("..."));
+                break;
+            }
+        }
+        trace
+    }
+
+    fn raise(&mut self, condition: Object, mode: Mode) -> Control {
+        if let Some(escape) = condition.downcast_ref::<Escape>() {
+            let continuation = escape.continuation.clone();
+            let value = escape.value.clone();
+            return self.reenter(continuation, value);
+        }
+
+        let condition = match condition {
+            Object::Error(mut e) if e.trace.is_empty() => {
+                e.trace = self.trace();
+                Object::Error(e)
+            }
+            other => other,
+        };
+
+        let from = self.frames.len();
+        self.scan(condition, from, 0, mode)
+    }
+
+    /// Looks for a handler below `from`, innermost first, starting at
+    /// `clause` in the first frame examined. `handler-bind` handlers are
+    /// called on top of the stack, so they can still invoke restarts; a
+    /// `guard` is unwound to instead.
+    fn scan(&mut self, condition: Object, from: usize, mut clause: usize, mode: Mode) -> Control {
+        let mut i = from;
+        while i > 0 {
+            i -= 1;
+            match &self.frames[i] {
+                Frame::Mask { depth } => {
+                    i = *depth;
+                    clause = 0;
+                    continue;
+                }
+                Frame::HandlerBind { clauses } => {
+                    let found = clauses
+                        .iter()
+                        .enumerate()
+                        .skip(clause)
+                        .find(|(_, (type_name, _))| {
+                            condition::condition_matches(type_name, &condition)
+                        })
+                        .map(|(k, (_, handler))| (k, handler.clone()));
+
+                    if let Some((k, handler)) = found {
+                        self.frames.push(Frame::Signal {
+                            condition: condition.clone(),
+                            depth: i,
+                            clause: k + 1,
+                            mode,
+                        });
+                        self.frames.push(Frame::Mask { depth: i });
+                        return Control::Apply(handler, vec![condition]);
+                    }
+                }
+                Frame::Handler { handler } if mode != Mode::Signal => {
+                    let handler = handler.clone();
+                    if mode == Mode::Raise {
+                        self.frames.push(Frame::NonContinuable {
+                            condition: condition.clone(),
+                            depth: i,
+                        });
+                    }
+                    self.frames.push(Frame::Mask { depth: i });
+                    return Control::Apply(handler, vec![condition]);
+                }
+                Frame::Guard { .. } if mode != Mode::Signal => {
+                    return Control::Unwind(i + 1, Then::Catch(condition));
+                }
+                _ => {}
+            }
+            clause = 0;
+        }
+
+        match mode {
+            Mode::Signal => Control::Return(Object::Nil),
+            Mode::Raise | Mode::Continuable => self.unhandled(condition),
+        }
+    }
+
+    /// Offers an unhandled condition to the debugger while its restarts are
+    /// still active, then unwinds to the top level.
+    fn unhandled(&mut self, condition: Object) -> Control {
+        let restarts = self.restarts();
+        if let (false, Some(debugger)) = (restarts.is_empty(), self.env.borrow().debugger()) {
+            let infos: Vec<RestartInfo> =
+                restarts.iter().map(|(_, _, info)| info.clone()).collect();
+            if let Some((i, args)) = debugger(&condition, &infos) {
+                if let Some(&(depth, clause, _)) = restarts.get(i) {
+                    return Control::Unwind(depth + 1, Then::Restart { clause, args });
+                }
+            }
+        }
+
+        Control::Unwind(0, Then::Fail(condition))
+    }
+
+    /// Pops frames down to `target`, running the after thunk of every
+    /// `dynamic-wind` on the way.
+    fn unwind(&mut self, target: usize, then: Then) -> Result<Control, Object> {
+        while self.frames.len() > target {
+            if let Some(Frame::Wind { after, .. }) = self.frames.pop() {
+                self.frames.push(Frame::Unwinding { target, then });
+                return Ok(Control::Apply(after, Vec::new()));
+            }
+        }
+
+        match then {
+            Then::Catch(condition) => match self.frames.pop() {
+                Some(Frame::Guard { var, clauses, env }) => {
+                    let guard_env = Environment::new_child(env);
+                    guard_env.borrow_mut().define(var, condition.clone())?;
+                    Ok(self.guard_clause(clauses, 0, condition, guard_env))
+                }
+                _ => unreachable!("unwound to a frame that is not a guard"),
+            },
+            Then::Restart { clause, args } => match self.frames.pop() {
+                Some(Frame::RestartCase { clauses, env }) => {
+                    let clause = &clauses[clause];
+                    Arity::Exact(clause.info.parameters.len()).check(&clause.info.name, &args)?;
+
+                    let restart_env = Environment::new_child(env);
+                    for (name, arg) in clause.info.parameters.iter().zip(args) {
+                        restart_env.borrow_mut().define(name.clone(), arg)?;
+                    }
+                    Ok(self.eval_body(clause.body.clone(), restart_env))
+                }
+                _ => unreachable!("unwound to a frame that is not a restart-case"),
+            },
+            Then::Reenter(continuation, value) => {
+                let wind = common_winds(&self.frames, &continuation.frames);
+                Ok(self.rewind(continuation, wind, value))
+            }
+            Then::Fail(condition) => Ok(Control::Fail(condition)),
+        }
+    }
+
+    /// Invokes a continuation: leaves the `dynamic-wind` extents that it does
+    /// not share with the current stack, then enters its own.
+    fn reenter(&mut self, continuation: Rc<Continuation>, value: Object) -> Control {
+        if continuation.run != self.run && is_active(continuation.run) {
+            let escape = Object::new_opaque(Escape {
+                continuation,
+                value,
+            });
+            return Control::Unwind(0, Then::Fail(escape));
+        }
+
+        let common = common_winds(&self.frames, &continuation.frames);
+        match wind_depths(&self.frames).get(common) {
+            Some(&depth) => Control::Unwind(depth, Then::Reenter(continuation, value)),
+            None => self.rewind(continuation, common, value),
+        }
+    }
+
+    /// Runs the before thunks of the continuation's winds from `wind` on,
+    /// each with the stack below it installed, then installs the rest.
+    fn rewind(&mut self, continuation: Rc<Continuation>, wind: usize, value: Object) -> Control {
+        match wind_depths(&continuation.frames).get(wind) {
+            Some(&depth) => {
+                let before = match &continuation.frames[depth] {
+                    Frame::Wind { before, .. } => before.clone(),
+                    _ => unreachable!(),
+                };
+                self.frames = continuation.frames[..depth].to_vec();
+                self.frames.push(Frame::Rewinding {
+                    continuation,
+                    wind: wind + 1,
+                    value,
+                });
+                Control::Apply(before, Vec::new())
+            }
+            None => {
+                self.frames = continuation.frames.clone();
+                Control::Return(value)
+            }
+        }
     }
 }
 
-fn make_definition(exps: &[Object], env: EnvRef) -> Result<Object, Object> {
-    let name = match &exps[1] {
-        Object::Symbol(name) => name.to_string(),
-        _ => return Err(Object::new_error("argument has wrong type")),
+/// Evaluates constants and variable references, which need no frames, or
+/// returns `None` for anything else.
+fn eval_simple(code: &Node, env: &EnvRef) -> Result<Option<Object>, Object> {
+    let (value, name) = match code {
+        Node::Constant(value) => return Ok(Some(value.clone())),
+        Node::Variable(name) => (env.borrow().lookup(*name), *name),
+        Node::Local(local) => (env.borrow().lookup_local(*local), local.name),
+        Node::Global(global) => (env.borrow().lookup_global(*global), global.name),
+        _ => return Ok(None),
     };
 
-    let value = eval(exps[2].clone(), env.clone())?;
+    match value {
+        Some(value) => Ok(Some(value)),
+        None => Err(Object::new_error_with(
+            ErrorKind::Unbound,
+            "unbound variable",
+            vec![Object::Symbol(name)],
+        )),
+    }
+}
 
-    env.borrow_mut()
-        .define(name.to_string(), value)
-        .and_then(|_| Ok(Object::Nil))
-        .or_else(|e| Err(Object::new_error(&format!("defining failed: {}", e))))
+/// Names an anonymous procedure after the variable it is defined as.
+pub(crate) fn named(value: Object, name: Symbol) -> Object {
+    match value {
+        Object::Callable(Function::Lambda(mut lambda)) if lambda.name.is_none() => {
+            lambda.name = Some(name);
+            Object::Callable(Function::Lambda(lambda))
+        }
+        Object::Callable(Function::Compiled(closure)) if closure.name.is_none() => {
+            vm::new_closure(closure.named(name))
+        }
+        value => value,
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::object::Object;
-    use crate::reader;
+fn branch(test: Object, consequent: &Code, alternative: &Option<Code>, env: EnvRef) -> Control {
+    match (test.is_truthy(), alternative) {
+        (true, _) => Control::Eval(consequent.clone(), env),
+        (false, Some(alternative)) => Control::Eval(alternative.clone(), env),
+        (false, None) => Control::Return(Object::Nil),
+    }
+}
 
-    macro_rules! assert_eval {
-        ( $input:expr, $expected:expr ) => {{
-            let env = Environment::new();
+pub fn apply(proc: &Object, args: &[Object], env: EnvRef) -> Result<Object, Object> {
+    check_nesting()?;
+    Machine::new(env).run(Control::Apply(proc.clone(), args.to_vec()))
+}
 
-            let objects = reader::read($input).unwrap();
+/// Evaluates `exp` after resolving its local variable references with
+/// `analysis::analyze`.
+pub fn eval(exp: Object, env: EnvRef) -> Result<Object, Object> {
+    eval_dynamic(analysis::analyze(exp), env)
+}
 
-            let mut result: Result<Object, Object> = Ok(Object::Nil);
-            for exp in objects.into_iter() {
-                result = eval(exp, env.clone())
-            }
+/// Evaluates `exp` as it is, so variables that were not resolved are
+/// looked up by name. This is the baseline the analysis is measured and
+/// tested against.
+pub fn eval_dynamic(exp: Object, env: EnvRef) -> Result<Object, Object> {
+    run(compiler::compile(&exp), env)
+}
 
-            assert_eq!(result, $expected);
-        }};
-    }
+/// Runs code that has already been compiled.
+pub fn run(code: Code, env: EnvRef) -> Result<Object, Object> {
+    check_nesting()?;
+    Machine::new(env.clone()).run(Control::Eval(code, env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{Arity, Object};
+    use crate::reader;
+    use crate::vm::test_util::assert_eval;
 
     #[test]
     fn test_self_evaluating() {
         assert_eval!("15", Ok(Object::Integer(15)));
     }
 
+    #[test]
+    fn test_self_evaluating_literals() {
+        assert_eval!("#t", Ok(Object::Boolean(true)));
+        assert_eval!("2.5", Ok(Object::Float(2.5)));
+        assert_eval!("\"foo\"", Ok(Object::String(String::from("foo"))));
+    }
+
     #[test]
     fn test_eval_builtin_arithmetic() {
         assert_eval!("(+ 1 2 3)", Ok(Object::Integer(6)));
@@ -169,7 +1352,22 @@ mod tests {
     fn test_eval_applying_non_callable() {
         assert_eval!(
             "(1)",
-            Err(Object::Error(String::from("cannot call non-function")))
+            Err(Object::type_error(
+                "cannot call non-function",
+                &Object::Integer(1)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eval!("'foo", Ok(Object::symbol("foo")));
+        assert_eval!(
+            "(quote (1 foo))",
+            Ok(Object::List(vec![
+                Object::Integer(1),
+                Object::symbol("foo")
+            ]))
         );
     }
 
@@ -188,9 +1386,353 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_native_closures() {
+        let env = Environment::new();
+        let offset = 10;
+        env.borrow_mut()
+            .define_native(
+                "add-offset",
+                Arity::Exact(1),
+                move |args, _env| match args[0] {
+                    Object::Integer(n) => Ok(Object::Integer(n + offset)),
+                    _ => Err(Object::new_error(
+                        ErrorKind::Type,
+                        "argument has wrong type",
+                    )),
+                },
+            );
+
+        let exp = reader::read("(add-offset (+ 1 2))").unwrap().remove(0);
+        assert_eq!(eval(exp, env.clone()), Ok(Object::Integer(13)));
+
+        let exp = reader::read("(add-offset 1 2)").unwrap().remove(0);
+        assert_eq!(
+            eval(exp, env),
+            Err(Object::new_error(
+                ErrorKind::Arity,
+                "add-offset: wrong number of arguments (expected 1, got 2)"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unbound_variables() {
+        assert_eval!(
+            "(+ 1 nope)",
+            Err(Object::new_error_with(
+                ErrorKind::Unbound,
+                "unbound variable",
+                vec![Object::symbol("nope")]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_error_traces() {
+        let env = Environment::new();
+        let code = "(define inner (lambda (x) (+ x #t)))
+            (define outer (lambda (x) (list (inner x))))
+            (outer 1)";
+
+        let mut result = Ok(Object::Nil);
+        for exp in reader::read(code).unwrap() {
+            result = eval(exp, env.clone());
+        }
+
+        match result {
+            Err(Object::Error(e)) => {
+                assert_eq!(e.kind, ErrorKind::Type);
+                assert_eq!(e.irritants, vec![Object::Boolean(true)]);
+                assert_eq!(e.trace, vec!["inner", "outer"]);
+            }
+            other => panic!("expected error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tail_calls_replace_trace_frames() {
+        let env = Environment::new();
+        let code = "(define inner (lambda (x) (+ x #t)))
+            (define outer (lambda (x) (inner x)))
+            (outer 1)";
+
+        let mut result = Ok(Object::Nil);
+        for exp in reader::read(code).unwrap() {
+            result = eval(exp, env.clone());
+        }
+
+        match result {
+            Err(Object::Error(e)) => assert_eq!(e.trace, vec!["inner"]),
+            other => panic!("expected error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lambda_arity() {
+        assert_eval!(
+            "(define f (lambda (a b) a)) (f 1)",
+            Err(Object::new_error(
+                ErrorKind::Arity,
+                "f: wrong number of arguments (expected 2, got 1)"
+            )
+            .with_frame("f"))
+        );
+    }
+
     #[test]
     fn test_lambdas() {
         assert_eval!("((lambda (x) (+ x 1)) 2)", Ok(Object::Integer(3)));
         assert_eval!("((lambda (a b c) (+ a b c)) 1 2 3)", Ok(Object::Integer(6)));
     }
+
+    #[test]
+    fn test_if_begin_and_set() {
+        assert_eval!("(if #t 1 2)", Ok(Object::Integer(1)));
+        assert_eval!("(if #f 1 2)", Ok(Object::Integer(2)));
+        assert_eval!("(if #f 1)", Ok(Object::Nil));
+        assert_eval!("(if 0 1 2)", Ok(Object::Integer(1)));
+        assert_eval!("(begin 1 2 3)", Ok(Object::Integer(3)));
+        assert_eval!("(define x 1) (set! x (+ x 1)) x", Ok(Object::Integer(2)));
+        assert_eval!(
+            "(set! nope 1)",
+            Err(Object::new_error_with(
+                ErrorKind::Unbound,
+                "unbound variable",
+                vec![Object::symbol("nope")]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_deep_recursion() {
+        assert_eval!(
+            "(define loop (lambda (n) (if (= n 0) 'done (loop (- n 1)))))
+            (loop 100000)",
+            Ok(Object::symbol("done"))
+        );
+        assert_eval!(
+            "(define count (lambda (n) (if (= n 0) 0 (+ 1 (count (- n 1))))))
+            (count 20000)",
+            Ok(Object::Integer(20000))
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        assert_eval!(
+            "(define f (lambda (n) (+ 1 (f n))))
+            (guard (e ((error-object? e) (error-object-message e))) (f 1))",
+            Ok(Object::String(String::from("stack overflow")))
+        );
+        assert_eval!(
+            "(define f (lambda (n) (guard (e (#f 0)) (+ 1 (f n)))))
+            (guard (e (#t 'caught)) (f 1))",
+            Ok(Object::symbol("caught"))
+        );
+        // Runs nested by natives are limited by the Rust stack they take.
+        assert_eval!(
+            "(define f (lambda (n) (vector-map (lambda (x) (+ 1 (f x))) (vector n))))
+            (guard (e (#t 'caught)) (f 1))",
+            Ok(Object::symbol("caught"))
+        );
+
+        let env = Environment::new();
+        let code = "(define f (lambda (n) (+ 1 (f n)))) (f 1)";
+        let mut result = Ok(Object::Nil);
+        for exp in reader::read(code).unwrap() {
+            result = eval(exp, env.clone());
+        }
+        match result {
+            Err(Object::Error(e)) => {
+                assert_eq!(e.message, "stack overflow");
+                assert_eq!(e.trace.len(), TRACE_LIMIT + 1);
+                assert_eq!(e.trace[TRACE_LIMIT], "...");
+            }
+            other => panic!("expected error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_call_cc_escapes() {
+        assert_eval!(
+            "(+ 1 (call/cc (lambda (k) (+ 10 (k 5)))))",
+            Ok(Object::Integer(6))
+        );
+        assert_eval!(
+            "(call-with-current-continuation (lambda (k) 3))",
+            Ok(Object::Integer(3))
+        );
+    }
+
+    #[test]
+    fn test_call_cc_reentry() {
+        assert_eval!(
+            "(define count 0)
+            (define k #f)
+            (define run
+              (lambda ()
+                (call/cc (lambda (c) (set! k c)))
+                (set! count (+ count 1))
+                (if (< count 5) (k 0) count)))
+            (run)",
+            Ok(Object::Integer(5))
+        );
+
+        // The continuation of an earlier top-level form can be resumed from
+        // a later one.
+        assert_eval!(
+            "(define k #f)
+            (define n (+ 100 (call/cc (lambda (c) (set! k c) 0))))
+            (define first n)
+            (k 5)
+            (list first n)",
+            Ok(Object::List(vec![
+                Object::Integer(100),
+                Object::Integer(105)
+            ]))
+        );
+    }
+
+    fn read_one(code: &str) -> Object {
+        reader::read(code).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_dynamic_wind() {
+        let prelude = "(define trail (list))
+            (define note (lambda (x) (set! trail (cons x trail))))";
+
+        assert_eval!(
+            &format!(
+                "{}
+                (dynamic-wind
+                  (lambda () (note 'in))
+                  (lambda () (note 'body) 7)
+                  (lambda () (note 'out)))",
+                prelude
+            ),
+            Ok(Object::Integer(7))
+        );
+
+        assert_eval!(
+            &format!(
+                "{}
+                (call/cc
+                  (lambda (k)
+                    (dynamic-wind
+                      (lambda () (note 'in))
+                      (lambda () (k 1) (note 'unreachable))
+                      (lambda () (note 'out)))))
+                trail",
+                prelude
+            ),
+            Ok(read_one("(out (in ()))"))
+        );
+
+        assert_eval!(
+            &format!(
+                "{}
+                (define k #f)
+                (dynamic-wind
+                  (lambda () (note 'in))
+                  (lambda () (call/cc (lambda (c) (set! k c))))
+                  (lambda () (note 'out)))
+                (k 0)
+                trail",
+                prelude
+            ),
+            Ok(read_one("(out (in (out (in ()))))"))
+        );
+
+        assert_eval!(
+            &format!(
+                "{}
+                (guard (e (#t trail))
+                  (dynamic-wind
+                    (lambda () (note 'in))
+                    (lambda () (raise 'oops))
+                    (lambda () (note 'out))))",
+                prelude
+            ),
+            Ok(read_one("(out (in ()))"))
+        );
+    }
+
+    #[test]
+    fn test_continuations_escape_nested_runs() {
+        let env = Environment::new();
+        env.borrow_mut()
+            .define_native("call-native", Arity::Exact(1), |args, env| {
+                apply(&args[0], &[], env)
+            });
+
+        let exp = read_one("(+ 1 (call/cc (lambda (k) (call-native (lambda () (k 41))))))");
+        assert_eq!(eval(exp, env), Ok(Object::Integer(42)));
+    }
+
+    #[test]
+    fn test_reset_and_shift() {
+        assert_eval!("(reset (+ 1 2))", Ok(Object::Integer(3)));
+        assert_eval!(
+            "(+ 1 (reset (+ 10 (shift k (k (k 1))))))",
+            Ok(Object::Integer(22))
+        );
+        assert_eval!(
+            "(define k (reset (+ 1 (shift c c))))
+            (list (k 10) (k 20))",
+            Ok(Object::List(vec![Object::Integer(11), Object::Integer(21)]))
+        );
+        assert_eval!(
+            "(shift k 1)",
+            Err(Object::new_error(
+                ErrorKind::Runtime,
+                "shift: no enclosing reset"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_shift_for_early_return() {
+        assert_eval!(
+            "(define product
+               (lambda (items)
+                 (if (null? items)
+                   1
+                   (if (= (car items) 0)
+                     (shift k 0)
+                     (* (car items) (product (cdr items)))))))
+            (list (reset (+ 100 (product '(1 2 3))))
+                  (reset (+ 100 (product '(1 2 0 4)))))",
+            Ok(Object::List(vec![Object::Integer(106), Object::Integer(0)]))
+        );
+    }
+
+    #[test]
+    fn test_shift_for_generators() {
+        assert_eval!(
+            "(define yield (lambda (x) (shift k (list x k))))
+            (define walk
+              (lambda (n limit)
+                (if (< n limit)
+                  (begin (yield n) (walk (+ n 1) limit))
+                  (list))))
+            (define sum
+              (lambda (gen total)
+                (if (null? gen)
+                  total
+                  (sum ((car (cdr gen)) #f) (+ total (car gen))))))
+            (sum (reset (walk 0 5)) 0)",
+            Ok(Object::Integer(10))
+        );
+
+        // Resuming a generator again replays it from the same point.
+        assert_eval!(
+            "(define yield (lambda (x) (shift k (list x k))))
+            (define gen (reset (yield 1) (yield 2) (list)))
+            (define resume (car (cdr gen)))
+            (list (car (resume #f)) (car (resume #f)))",
+            Ok(Object::List(vec![Object::Integer(2), Object::Integer(2)]))
+        );
+    }
 }
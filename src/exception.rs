@@ -0,0 +1,173 @@
+use crate::error::ErrorKind;
+use crate::object::{Arity, EnvRef, Object};
+use crate::symbol::Symbol;
+
+pub fn error(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    let message = match args.first() {
+        Some(Object::String(message)) => message,
+        Some(other) => return Err(Object::type_error("error: message is not a string", other)),
+        None => return Err(Arity::AtLeast(1).error("error", 0)),
+    };
+
+    Err(Object::new_error_with(
+        ErrorKind::User,
+        message,
+        args[1..].to_vec(),
+    ))
+}
+
+fn error_object_arg<'a>(name: &str, args: &'a [Object]) -> Result<&'a Object, Object> {
+    Arity::Exact(1).check(name, args)?;
+
+    match &args[0] {
+        obj @ Object::Error(_) => Ok(obj),
+        other => Err(Object::type_error(
+            &format!("{}: argument is not an error object", name),
+            other,
+        )),
+    }
+}
+
+pub fn is_error_object(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("error-object?", args)?;
+
+    Ok(Object::Boolean(matches!(args[0], Object::Error(_))))
+}
+
+pub fn error_object_message(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    match error_object_arg("error-object-message", args)? {
+        Object::Error(e) => Ok(Object::String(e.message.clone())),
+        _ => unreachable!(),
+    }
+}
+
+pub fn error_object_irritants(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    match error_object_arg("error-object-irritants", args)? {
+        Object::Error(e) => Ok(Object::List(e.irritants.clone())),
+        _ => unreachable!(),
+    }
+}
+
+/// Splits `(guard (var clause...) body...)` into the variable and its
+/// clauses. The clauses are `cond`-style: `(test expr...)`,
+/// `(test => receiver)` or `(else expr...)`.
+pub(crate) fn parse_guard(exps: &[Object]) -> Result<(Symbol, &[Object]), Object> {
+    match exps.get(1) {
+        Some(Object::List(spec)) => match spec.split_first() {
+            Some((Object::Symbol(var), clauses)) => Ok((*var, clauses)),
+            _ => Err(Object::new_error(
+                ErrorKind::Syntax,
+                "guard: malformed clauses",
+            )),
+        },
+        _ => Err(Object::new_error(
+            ErrorKind::Syntax,
+            "guard: malformed clauses",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ErrorKind;
+    use crate::object::Object;
+    use crate::vm::test_util::assert_eval;
+
+    #[test]
+    fn test_raise_without_handler() {
+        assert_eval!("(raise 5)", Err(Object::Integer(5)));
+        assert_eval!(
+            "(error \"boom\" 1 2)",
+            Err(Object::new_error_with(
+                ErrorKind::User,
+                "boom",
+                vec![Object::Integer(1), Object::Integer(2)]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_guard() {
+        assert_eval!("(guard (e (#t (+ e 1))) (raise 5))", Ok(Object::Integer(6)));
+        assert_eval!("(guard (e (#t 0)) (+ 1 2))", Ok(Object::Integer(3)));
+        assert_eval!(
+            "(guard (e ((error-object? e) (error-object-message e)))
+               (error \"boom\" 1 2))",
+            Ok(Object::String(String::from("boom")))
+        );
+        assert_eval!(
+            "(guard (e ((error-object? e) (error-object-irritants e)))
+               (error \"boom\" 1 2))",
+            Ok(Object::List(vec![Object::Integer(1), Object::Integer(2)]))
+        );
+        assert_eval!(
+            "(guard (e ((error-object? e) 1) (else 2)) (raise 5))",
+            Ok(Object::Integer(2))
+        );
+        assert_eval!(
+            "(guard (e (e => (lambda (x) (* x 2)))) (raise 21))",
+            Ok(Object::Integer(42))
+        );
+        assert_eval!(
+            "(guard (e ((error-object? e) 1)) (raise 5))",
+            Err(Object::Integer(5))
+        );
+    }
+
+    #[test]
+    fn test_guard_catches_native_errors() {
+        assert_eval!(
+            "(guard (e ((error-object? e) (error-object-message e))) (car 1))",
+            Ok(Object::String(String::from("argument has wrong type")))
+        );
+    }
+
+    #[test]
+    fn test_with_exception_handler() {
+        assert_eval!(
+            "(with-exception-handler
+               (lambda (e) 10)
+               (lambda () (+ 1 (raise-continuable 5))))",
+            Ok(Object::Integer(11))
+        );
+
+        assert_eval!(
+            "(guard (e ((error-object? e) (error-object-message e)))
+               (with-exception-handler
+                 (lambda (e) 0)
+                 (lambda () (raise 5))))",
+            Ok(Object::String(String::from(
+                "handler returned from non-continuable raise"
+            )))
+        );
+
+        assert_eval!(
+            "(guard (e (#t e))
+               (with-exception-handler
+                 (lambda (e) (raise (error-object-message e)))
+                 (lambda () (car 5))))",
+            Ok(Object::String(String::from("argument has wrong type")))
+        );
+    }
+
+    #[test]
+    fn test_handlers_run_outside_their_own_extent() {
+        assert_eval!(
+            "(with-exception-handler
+               (lambda (e) (* e 10))
+               (lambda ()
+                 (with-exception-handler
+                   (lambda (e) (+ (raise-continuable e) 1))
+                   (lambda () (raise-continuable 5)))))",
+            Ok(Object::Integer(51))
+        );
+
+        assert_eval!(
+            "(guard (e (#t e))
+               (with-exception-handler
+                 (lambda (e) (raise 7))
+                 (lambda () (raise-continuable 1))))",
+            Ok(Object::Integer(7))
+        );
+    }
+}
@@ -0,0 +1,437 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+use crate::object::{Arity, EnvRef, Function, Object};
+use crate::persistent::PersistentMap;
+
+/// Registering this many objects compacts the registry, and collects if
+/// the live objects have at least doubled since the last collection.
+const MIN_THRESHOLD: usize = 4096;
+
+/// The kinds of heap object that can form reference cycles, and so are
+/// tracked by the collector.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Environment,
+    Closure,
+    Cell,
+    Vector,
+    HashTable,
+    Promise,
+}
+
+const KINDS: [Kind; 6] = [
+    Kind::Environment,
+    Kind::Closure,
+    Kind::Cell,
+    Kind::Vector,
+    Kind::HashTable,
+    Kind::Promise,
+];
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Environment => "environments",
+            Kind::Closure => "closures",
+            Kind::Cell => "cells",
+            Kind::Vector => "vectors",
+            Kind::HashTable => "hash-tables",
+            Kind::Promise => "promises",
+        }
+    }
+}
+
+/// A heap object that the collector can look into and, if it turns out to
+/// be garbage, empty.
+pub(crate) trait Trace {
+    fn kind(&self) -> Kind;
+
+    /// Passes `tracer` everything the object holds. Returns false if the
+    /// object is borrowed and cannot be looked into, in which case it is
+    /// kept, along with everything it holds.
+    fn trace(&self, tracer: &mut Tracer) -> bool;
+
+    /// Drops what the object holds, breaking the cycles it is part of.
+    fn clear(&self);
+}
+
+/// Reports the tracked objects that an object holds references to.
+pub(crate) struct Tracer<'a> {
+    visit: &'a mut dyn FnMut(*const ()),
+}
+
+impl Tracer<'_> {
+    /// Reports a reference to a tracked object.
+    pub fn edge<T>(&mut self, object: &Rc<T>) {
+        (self.visit)(Rc::as_ptr(object).cast());
+    }
+
+    /// Reports the tracked objects that `object` holds, looking into lists
+    /// and errors, which it owns. Other shared structures are not looked
+    /// into, since they could be reached more than once; what they hold is
+    /// then counted as referenced from outside, and kept.
+    pub fn object(&mut self, object: &Object) {
+        match object {
+            Object::List(items) => items.iter().for_each(|item| self.object(item)),
+            Object::Callable(function) => self.function(function),
+            Object::Error(e) => {
+                e.irritants.iter().for_each(|item| self.object(item));
+                if let Some(cause) = &e.cause {
+                    self.object(cause);
+                }
+            }
+            Object::Promise(promise) => self.edge(promise),
+            Object::Vector(vector) => self.edge(vector),
+            Object::HashTable(table) => self.edge(table),
+            _ => {}
+        }
+    }
+
+    pub fn function(&mut self, function: &Function) {
+        match function {
+            Function::Lambda(lambda) => self.edge(&lambda.env),
+            Function::Compiled(closure) => self.edge(closure),
+            _ => {}
+        }
+    }
+}
+
+impl Trace for RefCell<Vec<Object>> {
+    fn kind(&self) -> Kind {
+        Kind::Vector
+    }
+
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        match self.try_borrow() {
+            Ok(items) => items.iter().for_each(|item| tracer.object(item)),
+            Err(_) => return false,
+        }
+        true
+    }
+
+    fn clear(&self) {
+        if let Ok(mut items) = self.try_borrow_mut() {
+            items.clear();
+        }
+    }
+}
+
+impl Trace for RefCell<HashMap<Object, Object>> {
+    fn kind(&self) -> Kind {
+        Kind::HashTable
+    }
+
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        match self.try_borrow() {
+            Ok(table) => table.iter().for_each(|(key, value)| {
+                tracer.object(key);
+                tracer.object(value);
+            }),
+            Err(_) => return false,
+        }
+        true
+    }
+
+    fn clear(&self) {
+        if let Ok(mut table) = self.try_borrow_mut() {
+            table.clear();
+        }
+    }
+}
+
+/// The tracked objects on this thread, and what the collector has done.
+struct Heap {
+    objects: Vec<Weak<dyn Trace>>,
+    threshold: usize,
+    /// The objects that survived the last collection.
+    survivors: usize,
+    collections: usize,
+    freed: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap {
+        objects: Vec::new(),
+        threshold: MIN_THRESHOLD,
+        survivors: 0,
+        collections: 0,
+        freed: 0,
+    });
+}
+
+/// Registers a new object with the collector. Now and then this drops the
+/// registry's entries for objects that were freed, and, when the live
+/// objects have grown enough, collects the cycles among them.
+pub(crate) fn track<T: Trace + 'static>(object: &Rc<T>) {
+    let weak: Weak<dyn Trace> = Rc::downgrade(object) as Weak<T>;
+    let grown = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(weak);
+        if heap.objects.len() < heap.threshold {
+            return false;
+        }
+        heap.objects.retain(|object| object.strong_count() > 0);
+        let live = heap.objects.len();
+        heap.threshold = (2 * live).max(MIN_THRESHOLD);
+        live >= 2 * heap.survivors.max(MIN_THRESHOLD / 2)
+    });
+    if grown {
+        collect();
+    }
+}
+
+/// Frees the tracked objects that are only reachable from each other, and
+/// returns how many there were.
+///
+/// Nothing points at the collector's roots, so it finds them by trial
+/// deletion: an object that more references point to than tracked objects
+/// account for is also referenced from outside, by the Rust stack, a
+/// structure the collector does not look into or a host, and is live.
+/// Everything reachable from a live object is live too. The rest is garbage,
+/// and is emptied so that its reference counts drop to zero.
+pub fn collect() -> usize {
+    let objects: Vec<Rc<dyn Trace>> = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(|object| object.strong_count() > 0);
+        heap.objects.iter().filter_map(Weak::upgrade).collect()
+    });
+    let index: HashMap<*const (), usize> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| (Rc::as_ptr(object).cast(), i))
+        .collect();
+
+    // `objects` holds a reference of its own to each.
+    let mut outside: Vec<usize> = objects.iter().map(|o| Rc::strong_count(o) - 1).collect();
+    let mut live = vec![false; objects.len()];
+    for (i, object) in objects.iter().enumerate() {
+        let mut visit = |child: *const ()| {
+            if let Some(&j) = index.get(&child) {
+                outside[j] = outside[j].saturating_sub(1);
+            }
+        };
+        if !object.trace(&mut Tracer { visit: &mut visit }) {
+            live[i] = true;
+        }
+    }
+
+    let mut pending: Vec<usize> = (0..objects.len())
+        .filter(|&i| live[i] || outside[i] > 0)
+        .collect();
+    for &i in &pending {
+        live[i] = true;
+    }
+    while let Some(i) = pending.pop() {
+        let mut visit = |child: *const ()| {
+            if let Some(&j) = index.get(&child) {
+                if !live[j] {
+                    live[j] = true;
+                    pending.push(j);
+                }
+            }
+        };
+        objects[i].trace(&mut Tracer { visit: &mut visit });
+    }
+
+    let mut freed = 0;
+    for (object, live) in objects.iter().zip(&live) {
+        if !live {
+            object.clear();
+            freed += 1;
+        }
+    }
+    drop(objects);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(|object| object.strong_count() > 0);
+        heap.survivors = heap.objects.len();
+        heap.threshold = (2 * heap.survivors).max(MIN_THRESHOLD);
+        heap.collections += 1;
+        heap.freed += freed;
+    });
+    freed
+}
+
+/// The collector's counts for this thread.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// The tracked objects that have not been freed, garbage included, by
+    /// kind in the order of `Kind`.
+    pub live: Vec<(Kind, usize)>,
+    pub collections: usize,
+    /// The objects freed by all collections so far.
+    pub freed: usize,
+}
+
+impl Stats {
+    pub fn total(&self) -> usize {
+        self.live.iter().map(|(_, count)| count).sum()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (kind, count) in &self.live {
+            write!(f, "{}: {}, ", kind.name(), count)?;
+        }
+        write!(
+            f,
+            "collections: {}, freed: {}",
+            self.collections, self.freed
+        )
+    }
+}
+
+pub fn stats() -> Stats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        let mut live: Vec<(Kind, usize)> = KINDS.iter().map(|kind| (*kind, 0)).collect();
+        for object in heap.objects.iter().filter_map(Weak::upgrade) {
+            let kind = object.kind();
+            if let Some(entry) = live.iter_mut().find(|(k, _)| *k == kind) {
+                entry.1 += 1;
+            }
+        }
+        Stats {
+            live,
+            collections: heap.collections,
+            freed: heap.freed,
+        }
+    })
+}
+
+/// `(gc)` collects garbage and returns how many objects it freed.
+pub fn gc(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(0).check("gc", args)?;
+    Ok(Object::Integer(collect() as i64))
+}
+
+/// `(gc-stats)` returns the collector's counts as a map, e.g.
+/// `{:environments 3 :closures 0 ... :collections 1 :freed 12}`.
+pub fn gc_stats(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(0).check("gc-stats", args)?;
+    let stats = stats();
+    let mut map = PersistentMap::new();
+    for (kind, count) in &stats.live {
+        map = map.insert(Object::keyword(kind.name()), Object::Integer(*count as i64));
+    }
+    map = map.insert(
+        Object::keyword("collections"),
+        Object::Integer(stats.collections as i64),
+    );
+    map = map.insert(
+        Object::keyword("freed"),
+        Object::Integer(stats.freed as i64),
+    );
+    Ok(Object::PersistentMap(map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator;
+    use crate::object::Environment;
+    use crate::reader;
+    use crate::vm::ENGINES;
+
+    const PROGRAMS: &str = "
+        (define make-loop (lambda (n) (define loop (lambda (i) (if (< i 1) n (loop (- i 1))))) loop))
+        (define make-promise-cycle (lambda () (define p (delay (list p))) (force p) 0))
+        (define make-vector-cycle (lambda () (define v (make-vector 1 0)) (vector-set! v 0 v) 0))";
+
+    fn run(code: &str, eval: fn(Object, EnvRef) -> Result<Object, Object>, env: &EnvRef) -> Object {
+        let mut result = Object::Nil;
+        for exp in reader::read(code).unwrap() {
+            result = eval(exp, env.clone()).unwrap();
+        }
+        result
+    }
+
+    #[test]
+    fn test_discarded_recursive_closures_do_not_accumulate() {
+        for (engine, eval) in ENGINES.iter() {
+            let env = Environment::new();
+            run(PROGRAMS, *eval, &env);
+            collect();
+            let baseline = stats().total();
+
+            let mut peak = 0;
+            for i in 0..20_000 {
+                let result = run("((make-loop 7) 3)", *eval, &env);
+                assert_eq!(result, Object::Integer(7));
+                if i % 1000 == 0 {
+                    peak = peak.max(stats().total());
+                }
+            }
+            assert!(
+                peak < baseline + 4 * MIN_THRESHOLD,
+                "{}: {} objects live at the peak, {} at the start",
+                engine,
+                peak,
+                baseline
+            );
+
+            for _ in 0..1000 {
+                run("(make-promise-cycle) (make-vector-cycle)", *eval, &env);
+            }
+            collect();
+            assert_eq!(stats().total(), baseline, "{}", engine);
+        }
+    }
+
+    #[test]
+    fn test_reachable_cycles_are_kept() {
+        for (engine, eval) in ENGINES.iter() {
+            let env = Environment::new();
+            let code = "(define make (lambda () (define self (lambda () self)) self))
+                        (define v (make-vector 1 0))
+                        (vector-set! v 0 v)
+                        (make)";
+            let f = run(code, *eval, &env);
+            collect();
+
+            assert_eq!(
+                evaluator::apply(&f, &[], env.clone()),
+                Ok(f.clone()),
+                "{}",
+                engine
+            );
+            let length = run("(vector-length (vector-ref v 0))", *eval, &env);
+            assert_eq!(length, Object::Integer(1), "{}", engine);
+
+            run("(set! v 0)", *eval, &env);
+            assert!(collect() >= 1, "{}", engine);
+        }
+    }
+
+    #[test]
+    fn test_dropped_environments_are_freed() {
+        collect();
+        let baseline = stats().total();
+        for _ in 0..10 {
+            Environment::new();
+        }
+        collect();
+        assert_eq!(stats().total(), baseline);
+    }
+
+    #[test]
+    fn test_gc_natives() {
+        let env = Environment::new();
+        run(
+            "(define f (lambda () (define g (lambda () g)) g)) (f) (f)",
+            crate::vm::eval,
+            &env,
+        );
+        assert!(matches!(run("(gc)", crate::vm::eval, &env), Object::Integer(n) if n >= 2));
+        let stats = run("(gc-stats)", crate::vm::eval, &env);
+        let environments = run("(get (gc-stats) :environments)", crate::vm::eval, &env);
+        assert_eq!(environments, Object::Integer(1));
+        assert!(matches!(stats, Object::PersistentMap(_)));
+        assert!(run("(get (gc-stats) :collections)", crate::vm::eval, &env) != Object::Integer(0));
+    }
+}
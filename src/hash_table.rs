@@ -0,0 +1,276 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::error::ErrorKind;
+use crate::evaluator::apply;
+use crate::gc;
+use crate::object::{Arity, EnvRef, Object};
+
+pub type TableRef = Rc<RefCell<HashMap<Object, Object>>>;
+
+pub fn new_table() -> Object {
+    let table = Rc::new(RefCell::new(HashMap::new()));
+    gc::track(&table);
+    Object::HashTable(table)
+}
+
+fn table_arg(name: &str, arg: &Object) -> Result<TableRef, Object> {
+    match arg {
+        Object::HashTable(table) => Ok(table.clone()),
+        other => Err(Object::type_error(
+            &format!("{}: argument is not a hash table", name),
+            other,
+        )),
+    }
+}
+
+fn key_arg(name: &str, key: &Object) -> Result<Object, Object> {
+    if !key.is_hashable() {
+        return Err(Object::type_error(
+            &format!("{}: a {} cannot be used as a key", name, key.type_name()),
+            key,
+        ));
+    }
+    Ok(key.clone())
+}
+
+fn missing_key(name: &str, key: &Object) -> Object {
+    Object::new_error_with(
+        ErrorKind::Runtime,
+        &format!("{}: no such key", name),
+        vec![key.clone()],
+    )
+}
+
+pub fn make_hash_table(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(0).check("make-hash-table", args)?;
+    Ok(new_table())
+}
+
+pub fn is_hash_table(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("hash-table?", args)?;
+    Ok(Object::Boolean(matches!(args[0], Object::HashTable(_))))
+}
+
+/// `(hash-ref table key [default])`
+pub fn hash_ref(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Range(2, 3).check("hash-ref", args)?;
+
+    let table = table_arg("hash-ref", &args[0])?;
+    let key = key_arg("hash-ref", &args[1])?;
+    let value = table.borrow().get(&key).cloned();
+    match (value, args.get(2)) {
+        (Some(value), _) => Ok(value),
+        (None, Some(default)) => Ok(default.clone()),
+        (None, None) => Err(missing_key("hash-ref", &key)),
+    }
+}
+
+pub fn hash_set(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(3).check("hash-set!", args)?;
+
+    let table = table_arg("hash-set!", &args[0])?;
+    let key = key_arg("hash-set!", &args[1])?;
+    table.borrow_mut().insert(key, args[2].clone());
+    Ok(Object::Nil)
+}
+
+pub fn hash_delete(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(2).check("hash-delete!", args)?;
+
+    let table = table_arg("hash-delete!", &args[0])?;
+    let key = key_arg("hash-delete!", &args[1])?;
+    table.borrow_mut().remove(&key);
+    Ok(Object::Nil)
+}
+
+pub fn hash_count(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("hash-count", args)?;
+
+    let table = table_arg("hash-count", &args[0])?;
+    let count = table.borrow().len();
+    Ok(Object::Integer(count as i64))
+}
+
+pub fn hash_keys(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("hash-keys", args)?;
+
+    let table = table_arg("hash-keys", &args[0])?;
+    let keys = table.borrow().keys().cloned().collect();
+    Ok(Object::List(keys))
+}
+
+pub fn hash_values(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("hash-values", args)?;
+
+    let table = table_arg("hash-values", &args[0])?;
+    let values = table.borrow().values().cloned().collect();
+    Ok(Object::List(values))
+}
+
+/// `(hash->list table)` returns the entries as `(key value)` lists.
+pub fn hash_to_list(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("hash->list", args)?;
+
+    let table = table_arg("hash->list", &args[0])?;
+    let entries = table
+        .borrow()
+        .iter()
+        .map(|(key, value)| Object::List(vec![key.clone(), value.clone()]))
+        .collect();
+    Ok(Object::List(entries))
+}
+
+/// `(hash-update! table key f [default])` stores `(f value)`, where `value`
+/// is the current value for `key`, or `default` if there is none.
+pub fn hash_update(args: &[Object], env: EnvRef) -> Result<Object, Object> {
+    Arity::Range(3, 4).check("hash-update!", args)?;
+
+    let table = table_arg("hash-update!", &args[0])?;
+    let key = key_arg("hash-update!", &args[1])?;
+    let current = table.borrow().get(&key).cloned();
+    let current = match (current, args.get(3)) {
+        (Some(value), _) => value,
+        (None, Some(default)) => default.clone(),
+        (None, None) => return Err(missing_key("hash-update!", &key)),
+    };
+
+    // The table is not borrowed while `f` runs, so it may use the table too.
+    let value = apply(&args[2], &[current], env)?;
+    table.borrow_mut().insert(key, value);
+    Ok(Object::Nil)
+}
+
+/// `(hash-for-each table f)` calls `f` with the key and value of every entry,
+/// in no particular order.
+pub fn hash_for_each(args: &[Object], env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(2).check("hash-for-each", args)?;
+
+    let table = table_arg("hash-for-each", &args[0])?;
+    let entries: Vec<(Object, Object)> = table
+        .borrow()
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    for (key, value) in entries {
+        apply(&args[1], &[key, value], env.clone())?;
+    }
+    Ok(Object::Nil)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::evaluator::eval;
+    use crate::object::{Environment, Object};
+    use crate::reader;
+    use crate::vm::test_util::assert_eval;
+
+    #[test]
+    fn test_hash_ref_and_set() {
+        assert_eval!(
+            "(define t (make-hash-table))
+            (hash-set! t 'a 1)
+            (hash-set! t \"b\" 2)
+            (hash-set! t '(1 2) 3)
+            (hash-set! t 'a 4)
+            (list (hash-ref t 'a) (hash-ref t \"b\") (hash-ref t (list 1 2)) (hash-count t))",
+            Ok(Object::List(vec![
+                Object::Integer(4),
+                Object::Integer(2),
+                Object::Integer(3),
+                Object::Integer(3)
+            ]))
+        );
+        assert_eval!(
+            "(hash-ref (make-hash-table) 'nope 0)",
+            Ok(Object::Integer(0))
+        );
+        assert_eval!(
+            "(hash-ref (make-hash-table) 'nope)",
+            Err(Object::new_error_with(
+                ErrorKind::Runtime,
+                "hash-ref: no such key",
+                vec![Object::symbol("nope")]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_hash_delete_and_keys() {
+        assert_eval!(
+            "(define t (make-hash-table))
+            (hash-set! t 1 'one)
+            (hash-set! t 2 'two)
+            (hash-delete! t 1)
+            (hash-delete! t 3)
+            (list (hash-keys t) (hash-values t) (hash->list t))",
+            Ok(Object::List(vec![
+                Object::List(vec![Object::Integer(2)]),
+                Object::List(vec![Object::symbol("two")]),
+                Object::List(vec![Object::List(vec![
+                    Object::Integer(2),
+                    Object::symbol("two")
+                ])]),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_hash_update_and_iteration() {
+        assert_eval!(
+            "(define t (make-hash-table))
+            (hash-update! t 'n (lambda (n) (+ n 1)) 0)
+            (hash-update! t 'n (lambda (n) (+ n 1)) 0)
+            (hash-set! t 'm 10)
+            (define total 0)
+            (hash-for-each t (lambda (k v) (set! total (+ total v))))
+            (list (hash-ref t 'n) total)",
+            Ok(Object::List(vec![Object::Integer(2), Object::Integer(12)]))
+        );
+        assert_eval!(
+            "(hash-update! (make-hash-table) 'n (lambda (n) n))",
+            Err(Object::new_error_with(
+                ErrorKind::Runtime,
+                "hash-update!: no such key",
+                vec![Object::symbol("n")]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unhashable_keys() {
+        let env = Environment::new();
+        let exp = reader::read("(hash-set! (make-hash-table) (lambda (x) x) 1)")
+            .unwrap()
+            .remove(0);
+        match eval(exp, env.clone()) {
+            Err(Object::Error(e)) => {
+                assert_eq!(e.kind, ErrorKind::Type);
+                assert_eq!(e.message, "hash-set!: a procedure cannot be used as a key");
+            }
+            other => panic!("expected type error, got {:?}", other),
+        }
+
+        let exp = reader::read("(hash-ref (make-hash-table) (list 1 #(2)))")
+            .unwrap()
+            .remove(0);
+        assert!(matches!(eval(exp, env), Err(Object::Error(ref e)) if e.kind == ErrorKind::Type));
+    }
+
+    #[test]
+    fn test_zero_floats_are_one_key() {
+        // The reader has no negative floats, but host code can make -0.0.
+        let env = Environment::new();
+        let table = new_table();
+        let args = [table.clone(), Object::Float(0.0), Object::symbol("zero")];
+        hash_set(&args, env.clone()).unwrap();
+        assert_eq!(
+            hash_ref(&[table, Object::Float(-0.0)], env),
+            Ok(Object::symbol("zero"))
+        );
+    }
+}
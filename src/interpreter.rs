@@ -0,0 +1,301 @@
+use std::error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::condition::RestartInfo;
+use crate::convert::{FromObject, IntoNative};
+use crate::error::ErrorKind;
+use crate::evaluator;
+use crate::module::Module;
+use crate::object::{Arity, EnvRef, Environment, Object};
+use crate::reader::{self, SpanTree};
+use crate::vm;
+
+#[derive(Debug)]
+pub enum Error {
+    Read(String),
+    Eval(Object),
+    Io(io::Error),
+    /// A compiled module that could not be loaded.
+    Load(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Read(message) => write!(f, "read error: {}", message),
+            Error::Eval(Object::Error(e)) => write!(f, "{}", e.report()),
+            Error::Eval(object) => write!(f, "uncaught exception: {}", object),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Load(message) => write!(f, "cannot load compiled module: {}", message),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// An `Interpreter` owns a global environment that persists across calls, so
+/// definitions made by one `eval_str` are visible to the next.
+pub struct Interpreter {
+    env: EnvRef,
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter {
+            env: Environment::new(),
+        }
+    }
+
+    pub fn env(&self) -> EnvRef {
+        self.env.clone()
+    }
+
+    /// Reads and evaluates every form in `code`, returning the value of the
+    /// last one.
+    pub fn eval_str(&self, code: &str) -> Result<Object, Error> {
+        let forms = reader::read_with_span_trees(code).map_err(Error::Read)?;
+        self.eval_forms(forms)
+    }
+
+    /// Evaluates forms read with `reader::read_with_span_trees`, e.g. after
+    /// `optimizer::optimize` has rewritten them.
+    pub fn eval_forms(&self, forms: Vec<(Object, SpanTree)>) -> Result<Object, Error> {
+        let mut result = Object::Nil;
+        for (object, spans) in forms {
+            result = vm::eval_with_spans(object, &spans, self.env.clone())
+                .map_err(|e| Error::Eval(e.with_span(spans.span)))?;
+        }
+        Ok(result)
+    }
+
+    pub fn eval_as<T: FromObject>(&self, code: &str) -> Result<T, Error> {
+        let result = self.eval_str(code)?;
+        T::from_object(&result).map_err(Error::Eval)
+    }
+
+    /// Evaluates a source file, or runs a module compiled to a `.rispc`
+    /// file.
+    pub fn eval_file<P: AsRef<Path>>(&self, path: P) -> Result<Object, Error> {
+        let path = path.as_ref();
+        if path.extension() == Some(OsStr::new("rispc")) {
+            let module = Module::from_bytes(&fs::read(path)?).map_err(Error::Load)?;
+            return self.eval_module(&module);
+        }
+
+        let code = fs::read_to_string(path)?;
+        self.eval_str(&code)
+    }
+
+    pub fn eval_module(&self, module: &Module) -> Result<Object, Error> {
+        module.run(self.env.clone()).map_err(Error::Eval)
+    }
+
+    /// Installs a callback that is offered unhandled conditions while their
+    /// restarts are still active, e.g. to show a restart menu. Each
+    /// interpreter has a debugger of its own.
+    pub fn set_debugger<F>(&self, debugger: F)
+    where
+        F: Fn(&Object, &[RestartInfo]) -> Option<(usize, Vec<Object>)> + 'static,
+    {
+        self.env.borrow_mut().set_debugger(Some(Rc::new(debugger)));
+    }
+
+    pub fn define_global(&self, name: &str, value: Object) {
+        // Defining in the global environment cannot fail.
+        let _ = self.env.borrow_mut().define(name.to_string(), value);
+    }
+
+    pub fn define_native<F>(&self, name: &str, arity: Arity, func: F)
+    where
+        F: Fn(&[Object], EnvRef) -> Result<Object, Object> + 'static,
+    {
+        self.env.borrow_mut().define_native(name, arity, func);
+    }
+
+    pub fn define_fn<Args, F: IntoNative<Args>>(&self, name: &str, func: F) {
+        self.env.borrow_mut().define_fn(name, func);
+    }
+
+    pub fn get_global(&self, name: &str) -> Object {
+        self.env.borrow().get(name)
+    }
+
+    pub fn call_function(&self, name: &str, args: &[Object]) -> Result<Object, Error> {
+        let function = match self.get_global(name) {
+            function @ Object::Callable(_) => function,
+            _ => {
+                return Err(Error::Eval(Object::new_error(
+                    ErrorKind::Type,
+                    &format!("{} is not a function", name),
+                )))
+            }
+        };
+
+        evaluator::apply(&function, args, self.env.clone()).map_err(Error::Eval)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Span;
+
+    #[test]
+    fn test_eval_str_keeps_definitions() {
+        let interpreter = Interpreter::new();
+        let result = interpreter.eval_str("(define x 5) (+ x 1)").unwrap();
+        assert_eq!(result, Object::Integer(6));
+
+        let result = interpreter.eval_str("(* x 2)").unwrap();
+        assert_eq!(result, Object::Integer(10));
+    }
+
+    #[test]
+    fn test_eval_str_errors() {
+        let interpreter = Interpreter::new();
+        match interpreter.eval_str("(1)") {
+            Err(Error::Eval(e)) => assert_eq!(
+                e,
+                Object::type_error("cannot call non-function", &Object::Integer(1))
+                    .with_span(Span { line: 1, column: 1 })
+            ),
+            other => panic!("expected eval error, got {:?}", other),
+        }
+
+        // Compiled code reports where in it the error happened.
+        let code = "(define f (lambda (x) (car x)))\n\n  (f 5)";
+        match interpreter.eval_str(code) {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "type-error: argument has wrong type 5\n  at line 1, column 23\n  in f"
+            ),
+            other => panic!("expected eval error, got {:?}", other),
+        }
+
+        match interpreter.eval_str("(+ 1 2))") {
+            Err(Error::Read(_)) => {}
+            other => panic!("expected read error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_interpreters_have_their_own_debuggers() {
+        let code = "(restart-case (car 1) (use-value (v) v))";
+        let first = Interpreter::new();
+        let second = Interpreter::new();
+        first.set_debugger(|_, _| Some((0, vec![Object::Integer(1)])));
+        second.set_debugger(|_, _| Some((0, vec![Object::Integer(2)])));
+
+        assert_eq!(first.eval_str(code).unwrap(), Object::Integer(1));
+        assert_eq!(second.eval_str(code).unwrap(), Object::Integer(2));
+        assert!(Interpreter::new().eval_str(code).is_err());
+    }
+
+    #[test]
+    fn test_typed_functions_and_results() {
+        let interpreter = Interpreter::new();
+        interpreter.define_fn("max2", |a: i64, b: i64| a.max(b));
+        interpreter.define_fn("greet", |name: String| format!("hello, {}", name));
+
+        assert_eq!(interpreter.eval_as::<i64>("(max2 3 (+ 4 5))").unwrap(), 9);
+        assert_eq!(
+            interpreter.eval_as::<String>("(greet \"risp\")").unwrap(),
+            "hello, risp"
+        );
+
+        match interpreter.eval_as::<i64>("(greet \"risp\")") {
+            Err(Error::Eval(Object::Error(e))) => {
+                assert_eq!(e.kind, ErrorKind::Type);
+                assert_eq!(e.message, "expected integer, got string");
+            }
+            other => panic!("expected eval error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_opaque_values_pass_through_lisp() {
+        struct Account {
+            balance: i64,
+        }
+
+        let interpreter = Interpreter::new();
+        interpreter.define_global("acct", Object::new_opaque(Account { balance: 40 }));
+        interpreter.define_fn("balance", |a: Rc<Account>| a.balance);
+
+        let result = interpreter
+            .eval_str("(define with-bonus (lambda (a) (+ (balance a) 2))) (with-bonus acct)")
+            .unwrap();
+        assert_eq!(result, Object::Integer(42));
+
+        let result = interpreter.eval_str("(list acct)").unwrap();
+        assert_eq!(format!("{}", result), "(<Account>)");
+    }
+
+    #[test]
+    fn test_eval_file() {
+        let path = std::env::temp_dir().join("risp_test_eval_file.risp");
+        fs::write(&path, "(define double (lambda (x) (* x 2)))\n(double 21)\n").unwrap();
+
+        let interpreter = Interpreter::new();
+        let result = interpreter.eval_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap(), Object::Integer(42));
+
+        match interpreter.eval_file("/does/not/exist.risp") {
+            Err(Error::Io(_)) => {}
+            other => panic!("expected io error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_define_global_and_call_function() {
+        let interpreter = Interpreter::new();
+        interpreter.define_global("base", Object::Integer(100));
+        interpreter
+            .eval_str("(define add-base (lambda (x) (+ x base)))")
+            .unwrap();
+
+        let result = interpreter.call_function("add-base", &[Object::Integer(5)]);
+        assert_eq!(result.unwrap(), Object::Integer(105));
+
+        let result = interpreter.call_function("+", &[Object::Integer(1), Object::Integer(2)]);
+        assert_eq!(result.unwrap(), Object::Integer(3));
+
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = calls.clone();
+        interpreter.define_native("log", Arity::AtLeast(0), move |args, _env| {
+            counter.set(counter.get() + 1);
+            Ok(Object::Integer(args.len() as i64))
+        });
+        let result = interpreter
+            .eval_str("(log 1 2 3) (add-base (log))")
+            .unwrap();
+        assert_eq!(result, Object::Integer(100));
+        assert_eq!(calls.get(), 2);
+
+        match interpreter.call_function("base", &[]) {
+            Err(Error::Eval(e)) => assert_eq!(
+                e,
+                Object::new_error(ErrorKind::Type, "base is not a function")
+            ),
+            other => panic!("expected eval error, got {:?}", other),
+        }
+    }
+}
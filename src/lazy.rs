@@ -0,0 +1,272 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::compiler::Code;
+use crate::evaluator::eval;
+use crate::gc::{self, Kind, Trace, Tracer};
+use crate::object::{Arity, EnvRef, Object};
+use crate::reader;
+
+#[derive(Clone)]
+pub enum PromiseState {
+    Done(Object),
+    /// Not forced yet. A `chained` promise comes from `delay-force`: its
+    /// expression yields another promise to force in its place.
+    Delayed {
+        exp: Code,
+        env: EnvRef,
+        chained: bool,
+    },
+}
+
+/// The value of `delay`, `delay-force` and `make-promise`. Following R7RS,
+/// the state is boxed separately so that a chain of `delay-force` promises
+/// can share one box and be forced in constant space.
+pub struct Promise {
+    state: RefCell<Rc<RefCell<PromiseState>>>,
+}
+
+/// Wraps a promise in an object, tracked by the collector.
+pub fn new_promise(promise: Promise) -> Object {
+    let promise = Rc::new(promise);
+    gc::track(&promise);
+    Object::Promise(promise)
+}
+
+impl Trace for Promise {
+    fn kind(&self) -> Kind {
+        Kind::Promise
+    }
+
+    /// The state is only looked into while no other promise of a
+    /// `delay-force` chain shares it.
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        let state = match self.state.try_borrow() {
+            Ok(state) => state,
+            Err(_) => return false,
+        };
+        if Rc::strong_count(&state) > 1 {
+            return true;
+        }
+        match state.try_borrow() {
+            Ok(state) => match &*state {
+                PromiseState::Done(value) => tracer.object(value),
+                PromiseState::Delayed { env, .. } => tracer.edge(env),
+            },
+            Err(_) => return false,
+        }
+        true
+    }
+
+    fn clear(&self) {
+        if let Ok(state) = self.state.try_borrow() {
+            if Rc::strong_count(&state) == 1 {
+                if let Ok(mut state) = state.try_borrow_mut() {
+                    *state = PromiseState::Done(Object::Nil);
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for Promise {
+    fn eq(&self, other: &Promise) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Promise {
+    fn new(state: PromiseState) -> Promise {
+        Promise {
+            state: RefCell::new(Rc::new(RefCell::new(state))),
+        }
+    }
+
+    pub fn done(value: Object) -> Promise {
+        Promise::new(PromiseState::Done(value))
+    }
+
+    pub fn delayed(exp: Code, env: EnvRef, chained: bool) -> Promise {
+        Promise::new(PromiseState::Delayed { exp, env, chained })
+    }
+
+    pub fn state(&self) -> PromiseState {
+        self.state.borrow().borrow().clone()
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state(), PromiseState::Done(_))
+    }
+
+    /// Stores the value, unless forcing the promise re-entrantly already did.
+    pub(crate) fn resolve(&self, value: Object) {
+        if !self.is_done() {
+            *self.state.borrow().borrow_mut() = PromiseState::Done(value);
+        }
+    }
+
+    /// Takes over the state of `other`, the promise a `delay-force` yielded,
+    /// and makes `other` share this promise's box from now on.
+    pub(crate) fn adopt(&self, other: &Promise) {
+        if self.is_done() {
+            return;
+        }
+
+        let state = other.state();
+        *self.state.borrow().borrow_mut() = state;
+        *other.state.borrow_mut() = self.state.borrow().clone();
+    }
+}
+
+pub fn make_promise(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("make-promise", args)?;
+
+    match &args[0] {
+        promise @ Object::Promise(_) => Ok(promise.clone()),
+        value => Ok(new_promise(Promise::done(value.clone()))),
+    }
+}
+
+pub fn is_promise(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("promise?", args)?;
+
+    Ok(Object::Boolean(matches!(args[0], Object::Promise(_))))
+}
+
+/// A stream is a promise of either the empty list or a list of two promises,
+/// one for the head and one for the rest of the stream, as built by
+/// `stream-cons`. The procedures returning streams use `delay-force`, so
+/// they do no work until the result is forced.
+const STREAMS: &str = "
+(define stream-null (make-promise (list)))
+
+(define stream-null? (lambda (s) (null? (force s))))
+
+(define stream-car (lambda (s) (force (car (force s)))))
+
+(define stream-cdr (lambda (s) (car (cdr (force s)))))
+
+(define stream-take
+  (lambda (n s)
+    (delay-force
+      (if (= n 0)
+        stream-null
+        (if (stream-null? s)
+          stream-null
+          (stream-cons (stream-car s) (stream-take (- n 1) (stream-cdr s))))))))
+
+(define stream-map
+  (lambda (f s)
+    (delay-force
+      (if (stream-null? s)
+        stream-null
+        (stream-cons (f (stream-car s)) (stream-map f (stream-cdr s)))))))
+
+(define stream->list
+  (lambda (s)
+    (if (stream-null? s)
+      (list)
+      (append (list (stream-car s)) (stream->list (stream-cdr s))))))
+";
+
+pub(crate) fn define_streams(env: &EnvRef) {
+    for exp in reader::read(STREAMS).expect("stream library does not parse") {
+        eval(exp, env.clone()).expect("stream library does not evaluate");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::Object;
+    use crate::vm::test_util::assert_eval;
+
+    #[test]
+    fn test_force_memoizes() {
+        assert_eval!(
+            "(define count 0)
+            (define p (delay (begin (set! count (+ count 1)) count)))
+            (list (force p) (force p) count)",
+            Ok(Object::List(vec![
+                Object::Integer(1),
+                Object::Integer(1),
+                Object::Integer(1)
+            ]))
+        );
+        assert_eval!("(force 5)", Ok(Object::Integer(5)));
+        assert_eval!("(force (make-promise 5))", Ok(Object::Integer(5)));
+        assert_eval!(
+            "(list (promise? (delay 1)) (promise? 1))",
+            Ok(Object::List(vec![
+                Object::Boolean(true),
+                Object::Boolean(false)
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_reentrant_force() {
+        // From R7RS: the first value computed for a promise is kept.
+        assert_eval!(
+            "(define count 0)
+            (define x 5)
+            (define p
+              (delay (begin (set! count (+ count 1))
+                            (if (< x count) count (force p)))))
+            (define first (force p))
+            (set! x 10)
+            (list first (force p))",
+            Ok(Object::List(vec![Object::Integer(6), Object::Integer(6)]))
+        );
+    }
+
+    #[test]
+    fn test_delay_force_chains() {
+        assert_eval!(
+            "(define loop
+              (lambda (n)
+                (delay-force (if (= n 0) (make-promise 'done) (loop (- n 1))))))
+            (force (loop 20000))",
+            Ok(Object::symbol("done"))
+        );
+    }
+
+    #[test]
+    fn test_streams() {
+        assert_eval!(
+            "(define from (lambda (n) (stream-cons n (from (+ n 1)))))
+            (stream->list (stream-take 5 (stream-map (lambda (x) (* x x)) (from 0))))",
+            Ok(Object::List(vec![
+                Object::Integer(0),
+                Object::Integer(1),
+                Object::Integer(4),
+                Object::Integer(9),
+                Object::Integer(16)
+            ]))
+        );
+        assert_eval!(
+            "(list (stream-null? stream-null)
+                   (stream-car (stream-cdr (stream-cons 1 (stream-cons 2 stream-null)))))",
+            Ok(Object::List(vec![
+                Object::Boolean(true),
+                Object::Integer(2)
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_stream_elements_are_lazy() {
+        assert_eval!(
+            "(define count 0)
+            (define s (stream-cons (begin (set! count (+ count 1)) 1) (car 5)))
+            (define mapped (stream-map (lambda (x) (set! count (+ count 1)) x) s))
+            (define before count)
+            (list before (stream-car s) (stream-car s) count)",
+            Ok(Object::List(vec![
+                Object::Integer(0),
+                Object::Integer(1),
+                Object::Integer(1),
+                Object::Integer(1)
+            ]))
+        );
+    }
+}
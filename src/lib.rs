@@ -0,0 +1,32 @@
+#![allow(dead_code)]
+
+pub mod analysis;
+pub mod bytecode;
+pub mod compiler;
+pub mod condition;
+pub mod convert;
+pub mod disassembler;
+pub mod error;
+pub mod evaluator;
+pub mod exception;
+pub mod gc;
+pub mod hash_table;
+pub mod interpreter;
+pub mod lazy;
+pub mod module;
+pub mod object;
+pub mod optimizer;
+pub mod parameters;
+pub mod persistent;
+pub mod reader;
+pub mod symbol;
+pub mod text;
+pub mod vector;
+pub mod vm;
+
+pub use condition::RestartInfo;
+pub use convert::{FromObject, IntoNative, IntoObject};
+pub use error::{ErrorKind, ErrorObject, Span};
+pub use interpreter::{Error, Interpreter};
+pub use object::{Arity, EnvRef, Environment, Function, Object};
+pub use symbol::Symbol;
@@ -1,27 +1,199 @@
-#![allow(dead_code)]
-
+use std::env;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process;
 
-mod evaluator;
-mod object;
-mod reader;
+mod editor;
 
-fn main() -> io::Result<()> {
-    const PROMPT: &str = "> ";
+use editor::Editor;
+use risp::module::Module;
+use risp::{optimizer, reader, vm, EnvRef, Interpreter, Object, RestartInfo};
+
+fn prompt(text: &str) -> Option<String> {
+    print!("{}", text);
+    io::stdout().flush().ok()?;
+
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(input),
+    }
+}
 
+fn read_restart_argument(name: &str, env: &EnvRef) -> Option<Object> {
     loop {
-        print!("{}", PROMPT);
-        io::stdout().flush()?;
+        let input = prompt(&format!("{}> ", name))?;
+        let exp = match reader::read(&input) {
+            Ok(mut objects) if objects.len() == 1 => objects.remove(0),
+            Ok(_) => {
+                println!("expected a single expression");
+                continue;
+            }
+            Err(e) => {
+                println!("read error: {}", e);
+                continue;
+            }
+        };
+
+        match vm::eval(exp, env.clone()) {
+            Ok(value) => return Some(value),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+/// Lets the user pick one of the active restarts for an unhandled condition.
+fn restart_menu(
+    env: &EnvRef,
+    condition: &Object,
+    restarts: &[RestartInfo],
+) -> Option<(usize, Vec<Object>)> {
+    match condition {
+        Object::Error(e) => println!("unhandled condition: {}", e),
+        other => println!("unhandled condition: {}", other),
+    }
+    println!("available restarts:");
+    for (i, restart) in restarts.iter().enumerate() {
+        println!(
+            "  {}: [{}] ({})",
+            i,
+            restart.name,
+            restart.parameters.join(" ")
+        );
+    }
+    println!("  {}: [abort] return to top level", restarts.len());
+
+    loop {
+        let choice = prompt("restart> ")?;
+        match choice.trim().parse::<usize>() {
+            Ok(i) if i < restarts.len() => {
+                let mut args = Vec::new();
+                for parameter in restarts[i].parameters.iter() {
+                    args.push(read_restart_argument(parameter, env)?);
+                }
+                return Some((i, args));
+            }
+            Ok(i) if i == restarts.len() => return None,
+            _ => println!("choose a restart between 0 and {}", restarts.len()),
+        }
+    }
+}
+
+/// A subcommand, given the arguments after its name.
+type Command = fn(&[String]) -> Result<(), String>;
+
+/// `risp compile foo.risp [-o foo.rispc]`: compiles a source file to a
+/// module that the runner can load without reading or compiling it again.
+fn compile(args: &[String]) -> Result<(), String> {
+    let (source, output) = match args {
+        [source] => (source, PathBuf::from(source).with_extension("rispc")),
+        [source, flag, output] if flag == "-o" => (source, PathBuf::from(output)),
+        _ => return Err(String::from("usage: risp compile <file> [-o <output>]")),
+    };
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+    let code = fs::read_to_string(source).map_err(|e| format!("{}: {}", source, e))?;
+    let module = Module::compile(&code).map_err(|e| format!("{}: read error: {}", source, e))?;
+    let bytes = module
+        .to_bytes()
+        .map_err(|e| format!("{}: {}", source, e))?;
+    fs::write(&output, bytes).map_err(|e| format!("{}: {}", output.display(), e))
+}
+
+fn disasm(args: &[String]) -> Result<(), String> {
+    let path = match args {
+        [path] => path,
+        _ => return Err(String::from("usage: risp disasm <file.rispc>")),
+    };
+
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let module = Module::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+    print!("{}", module.disassemble());
+    Ok(())
+}
 
-        match reader::read(&input) {
-            Ok(objects) => {
-                objects.iter().for_each(|object| println!("{}", object));
+/// `risp [-O] [--opt-report] foo.risp`: runs a file. `-O` optimizes a
+/// source file first, and `--opt-report` also prints what that changed.
+fn run(interpreter: &Interpreter, args: &[String]) -> Result<(), String> {
+    const USAGE: &str = "usage: risp [-O] [--opt-report] <file>";
+    let (mut optimize, mut report, mut path) = (false, false, None);
+    for arg in args {
+        match arg.as_str() {
+            "-O" => optimize = true,
+            "--opt-report" => {
+                optimize = true;
+                report = true;
             }
-            Err(e) => println!("Something went wrong: {}", e),
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => return Err(String::from(USAGE)),
+        }
+    }
+    let path = path.ok_or(USAGE)?;
+
+    if !optimize {
+        return match interpreter.eval_file(path) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{}: {}", path, e)),
         };
     }
+    if path.ends_with(".rispc") {
+        return Err(format!("{}: only source files can be optimized", path));
+    }
+    let code = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut forms =
+        reader::read_with_span_trees(&code).map_err(|e| format!("{}: read error: {}", path, e))?;
+    let changes = optimizer::optimize(&mut forms, &interpreter.env());
+    if report {
+        eprint!("{}", changes);
+    }
+    match interpreter.eval_forms(forms) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}: {}", path, e)),
+    }
+}
+
+fn main() -> io::Result<()> {
+    const PROMPT: &str = "> ";
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command: Option<Command> = match args.first().map(String::as_str) {
+        Some("compile") => Some(compile),
+        Some("disasm") => Some(disasm),
+        _ => None,
+    };
+    if let Some(command) = command {
+        if let Err(e) = command(&args[1..]) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let interpreter = Interpreter::new();
+
+    if !args.is_empty() {
+        if let Err(e) = run(&interpreter, &args) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let env = interpreter.env();
+    interpreter.set_debugger(move |condition, restarts| restart_menu(&env, condition, restarts));
+
+    let mut editor = Editor::new();
+    while let Some(input) = editor.read_line(PROMPT, &interpreter.env())? {
+        if input.trim().is_empty() {
+            continue;
+        }
+
+        match interpreter.eval_str(&input) {
+            Ok(result) => println!("{}", result),
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    Ok(())
 }
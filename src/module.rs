@@ -0,0 +1,782 @@
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::bytecode::{self, Capture, Op, Proto};
+use crate::disassembler;
+use crate::error::Span;
+use crate::evaluator;
+use crate::object::{EnvRef, Object};
+use crate::parameters::Parameters;
+use crate::persistent::{PersistentMap, PersistentVector};
+use crate::reader;
+use crate::symbol::{Symbol, SymbolMap};
+use crate::vector::new_vector;
+use crate::vm;
+
+/// The first bytes of every compiled module file.
+pub const MAGIC: &[u8; 5] = b"RISPC";
+/// The version of the file layout and of the instruction set. Files of any
+/// other version are rejected rather than misread.
+pub const VERSION: u16 = 2;
+
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOLEAN: u8 = 1;
+const TAG_INTEGER: u8 = 2;
+const TAG_FLOAT: u8 = 3;
+const TAG_CHAR: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_SYMBOL: u8 = 6;
+const TAG_KEYWORD: u8 = 7;
+const TAG_LIST: u8 = 8;
+const TAG_VECTOR: u8 = 9;
+const TAG_PERSISTENT_VECTOR: u8 = 10;
+const TAG_PERSISTENT_MAP: u8 = 11;
+
+const FORM_COMPILED: u8 = 0;
+const FORM_SOURCE: u8 = 1;
+
+/// A top-level form of a module.
+#[derive(Debug, PartialEq)]
+pub enum Form {
+    Compiled(Rc<Proto>),
+    /// A form that the compiler leaves to the evaluator.
+    Source(Object),
+}
+
+/// A compiled program: its top-level forms, in order, with where each one
+/// starts in the source.
+///
+/// A `.rispc` file holds the magic bytes `RISPC`, the version as a `u16`,
+/// then a CRC-32 of the rest: the symbol table, then the forms. Integers
+/// are little-endian; lengths and indices are `u32`s, except within code.
+#[derive(Debug, PartialEq)]
+pub struct Module {
+    pub forms: Vec<(Form, Span)>,
+}
+
+impl Module {
+    /// Reads and compiles every form in `code`.
+    pub fn compile(code: &str) -> Result<Module, String> {
+        let forms = reader::read_with_span_trees(code)?
+            .into_iter()
+            .map(|(exp, spans)| {
+                let form = match bytecode::compile_with_spans(&exp, &spans) {
+                    Some(proto) => Form::Compiled(proto),
+                    None => Form::Source(exp),
+                };
+                (form, spans.span)
+            })
+            .collect();
+        Ok(Module { forms })
+    }
+
+    /// Runs the forms in order and returns the value of the last one.
+    /// Errors carry the span of the form that raised them.
+    pub fn run(&self, env: EnvRef) -> Result<Object, Object> {
+        let mut result = Object::Nil;
+        for (form, span) in &self.forms {
+            result = match form {
+                Form::Compiled(proto) => vm::run(proto.clone(), env.clone()),
+                Form::Source(exp) => evaluator::eval(exp.clone(), env.clone()),
+            }
+            .map_err(|e| e.with_span(*span))?;
+        }
+        Ok(result)
+    }
+
+    /// Lists the code of every form, numbered in order. Forms left to the
+    /// evaluator are shown as source.
+    pub fn disassemble(&self) -> String {
+        let listings: Vec<String> = self
+            .forms
+            .iter()
+            .enumerate()
+            .map(|(i, (form, span))| match form {
+                Form::Compiled(proto) => {
+                    let title = format!("form {}", i);
+                    format!("; {}\n{}", span, disassembler::disassemble(proto, &title))
+                }
+                Form::Source(exp) => format!("; {}\n== form {} ==\nsource: {}\n", span, i, exp),
+            })
+            .collect();
+        listings.join("\n")
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut body = Encoder::default();
+        body.len(self.forms.len())?;
+        for (form, span) in &self.forms {
+            body.len(span.line)?;
+            body.len(span.column)?;
+            match form {
+                Form::Compiled(proto) => {
+                    body.u8(FORM_COMPILED);
+                    body.proto(proto)?;
+                }
+                Form::Source(exp) => {
+                    body.u8(FORM_SOURCE);
+                    body.object(exp)?;
+                }
+            }
+        }
+
+        let mut payload = Encoder::default();
+        payload.len(body.symbols.len())?;
+        for symbol in &body.symbols {
+            payload.string(&symbol.to_string())?;
+        }
+        payload.bytes.extend_from_slice(&body.bytes);
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&crc32(&payload.bytes).to_le_bytes());
+        bytes.extend_from_slice(&payload.bytes);
+        Ok(bytes)
+    }
+
+    /// Loads a module written by `to_bytes`, checking its version and
+    /// checksum, and that its code only refers to constants, variables and
+    /// functions that it has.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Module, String> {
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(String::from("not a compiled risp module"));
+        }
+        let version = u16::from_le_bytes([bytes[5], bytes[6]]);
+        if version != VERSION {
+            return Err(format!(
+                "unsupported module version {} (expected {})",
+                version, VERSION
+            ));
+        }
+        let checksum = u32::from_le_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]);
+        let payload = &bytes[HEADER_LEN..];
+        if crc32(payload) != checksum {
+            return Err(String::from("checksum mismatch, the module is corrupt"));
+        }
+
+        let mut decoder = Decoder {
+            bytes: payload,
+            pos: 0,
+            symbols: Vec::new(),
+        };
+        for _ in 0..decoder.len()? {
+            let name = decoder.string()?;
+            decoder.symbols.push(Symbol::intern(&name));
+        }
+
+        let count = decoder.len()?;
+        let mut forms = Vec::new();
+        for _ in 0..count {
+            let span = Span {
+                line: decoder.len()?,
+                column: decoder.len()?,
+            };
+            let form = match decoder.u8()? {
+                FORM_COMPILED => {
+                    let proto = decoder.proto()?;
+                    if !proto.upvalues.is_empty() {
+                        return Err(String::from("top-level form has upvalues"));
+                    }
+                    Form::Compiled(Rc::new(proto))
+                }
+                FORM_SOURCE => Form::Source(decoder.object()?),
+                tag => return Err(format!("unknown form tag {}", tag)),
+            };
+            forms.push((form, span));
+        }
+        if decoder.pos != payload.len() {
+            return Err(String::from("trailing bytes after the last form"));
+        }
+        Ok(Module { forms })
+    }
+}
+
+/// CRC-32 as used by zip and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Writes values and collects the symbols they use, which are written by
+/// index into the symbol table.
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+    symbols: Vec<Symbol>,
+    indices: SymbolMap<u32>,
+}
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) -> Result<(), String> {
+        let len = u32::try_from(len).map_err(|_| String::from("module is too large"))?;
+        self.u32(len);
+        Ok(())
+    }
+
+    fn string(&mut self, string: &str) -> Result<(), String> {
+        self.len(string.len())?;
+        self.bytes.extend_from_slice(string.as_bytes());
+        Ok(())
+    }
+
+    fn symbol(&mut self, symbol: Symbol) {
+        let next = self.symbols.len() as u32;
+        let index = *self.indices.entry(symbol).or_insert(next);
+        if index == next {
+            self.symbols.push(symbol);
+        }
+        self.u32(index);
+    }
+
+    fn objects<'a>(
+        &mut self,
+        objects: impl ExactSizeIterator<Item = &'a Object>,
+    ) -> Result<(), String> {
+        self.len(objects.len())?;
+        for object in objects {
+            self.object(object)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a constant. Only data that the reader can produce is
+    /// supported, which is all that compiled code holds.
+    fn object(&mut self, object: &Object) -> Result<(), String> {
+        match object {
+            Object::Nil => self.u8(TAG_NIL),
+            Object::Boolean(value) => {
+                self.u8(TAG_BOOLEAN);
+                self.u8(*value as u8);
+            }
+            Object::Integer(value) => {
+                self.u8(TAG_INTEGER);
+                self.bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            Object::Float(value) => {
+                self.u8(TAG_FLOAT);
+                self.bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+            }
+            Object::Char(value) => {
+                self.u8(TAG_CHAR);
+                self.u32(*value as u32);
+            }
+            Object::String(value) => {
+                self.u8(TAG_STRING);
+                self.string(value)?;
+            }
+            Object::Symbol(symbol) => {
+                self.u8(TAG_SYMBOL);
+                self.symbol(*symbol);
+            }
+            Object::Keyword(symbol) => {
+                self.u8(TAG_KEYWORD);
+                self.symbol(*symbol);
+            }
+            Object::List(items) => {
+                self.u8(TAG_LIST);
+                self.objects(items.iter())?;
+            }
+            Object::Vector(items) => {
+                self.u8(TAG_VECTOR);
+                self.objects(items.borrow().iter())?;
+            }
+            Object::PersistentVector(items) => {
+                self.u8(TAG_PERSISTENT_VECTOR);
+                self.len(items.len())?;
+                for item in items.iter() {
+                    self.object(item)?;
+                }
+            }
+            Object::PersistentMap(map) => {
+                self.u8(TAG_PERSISTENT_MAP);
+                let pairs = map.pairs();
+                self.len(pairs.len())?;
+                for (key, value) in pairs {
+                    self.object(key)?;
+                    self.object(value)?;
+                }
+            }
+            other => {
+                return Err(format!(
+                    "cannot write a {} constant: {}",
+                    other.type_name(),
+                    other
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn parameters(&mut self, parameters: &Parameters) -> Result<(), String> {
+        self.len(parameters.required.len())?;
+        for name in &parameters.required {
+            self.symbol(*name);
+        }
+        for section in &[&parameters.optional, &parameters.keys] {
+            self.len(section.len())?;
+            for (name, default) in section.iter() {
+                self.symbol(*name);
+                self.object(default)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn proto(&mut self, proto: &Proto) -> Result<(), String> {
+        self.u8(proto.toplevel as u8);
+        self.parameters(&proto.parameters)?;
+        self.len(proto.locals.len())?;
+        for name in &proto.locals {
+            self.symbol(*name);
+        }
+        self.len(proto.upvalues.len())?;
+        for (name, capture) in &proto.upvalues {
+            self.symbol(*name);
+            let (kind, index) = match capture {
+                Capture::Local(index) => (0, index),
+                Capture::Upvalue(index) => (1, index),
+            };
+            self.u8(kind);
+            self.bytes.extend_from_slice(&index.to_le_bytes());
+        }
+        self.objects(proto.constants.iter())?;
+        self.len(proto.protos.len())?;
+        for inner in &proto.protos {
+            self.proto(inner)?;
+        }
+        self.len(proto.code.len())?;
+        self.bytes.extend_from_slice(&proto.code);
+        self.len(proto.spans.len())?;
+        for (offset, span) in &proto.spans {
+            self.len(*offset)?;
+            self.len(span.line)?;
+            self.len(span.column)?;
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    symbols: Vec<Symbol>,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| String::from("module is truncated"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        self.u32().map(|len| len as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| String::from("string is not UTF-8"))
+    }
+
+    fn symbol(&mut self) -> Result<Symbol, String> {
+        let index = self.len()?;
+        self.symbols
+            .get(index)
+            .copied()
+            .ok_or_else(|| format!("symbol {} is not in the symbol table", index))
+    }
+
+    fn objects(&mut self) -> Result<Vec<Object>, String> {
+        let len = self.len()?;
+        let mut objects = Vec::new();
+        for _ in 0..len {
+            objects.push(self.object()?);
+        }
+        Ok(objects)
+    }
+
+    fn object(&mut self) -> Result<Object, String> {
+        Ok(match self.u8()? {
+            TAG_NIL => Object::Nil,
+            TAG_BOOLEAN => Object::Boolean(self.u8()? != 0),
+            TAG_INTEGER => Object::Integer(self.u64()? as i64),
+            TAG_FLOAT => Object::Float(f64::from_bits(self.u64()?)),
+            TAG_CHAR => {
+                let code = self.u32()?;
+                Object::Char(
+                    std::char::from_u32(code).ok_or_else(|| format!("invalid char {}", code))?,
+                )
+            }
+            TAG_STRING => Object::String(self.string()?),
+            TAG_SYMBOL => Object::Symbol(self.symbol()?),
+            TAG_KEYWORD => Object::Keyword(self.symbol()?),
+            TAG_LIST => Object::List(self.objects()?),
+            TAG_VECTOR => new_vector(self.objects()?),
+            TAG_PERSISTENT_VECTOR => {
+                Object::PersistentVector(self.objects()?.into_iter().collect::<PersistentVector>())
+            }
+            TAG_PERSISTENT_MAP => {
+                let mut map = PersistentMap::new();
+                for _ in 0..self.len()? {
+                    let key = self.object()?;
+                    if !key.is_hashable() {
+                        return Err(format!("map constant has an unhashable key: {}", key));
+                    }
+                    map = map.insert(key, self.object()?);
+                }
+                Object::PersistentMap(map)
+            }
+            tag => return Err(format!("unknown constant tag {}", tag)),
+        })
+    }
+
+    fn parameters(&mut self) -> Result<Parameters, String> {
+        let mut parameters = Parameters::default();
+        for _ in 0..self.len()? {
+            parameters.required.push(self.symbol()?);
+        }
+        for _ in 0..self.len()? {
+            parameters.optional.push((self.symbol()?, self.object()?));
+        }
+        for _ in 0..self.len()? {
+            parameters.keys.push((self.symbol()?, self.object()?));
+        }
+        Ok(parameters)
+    }
+
+    fn proto(&mut self) -> Result<Proto, String> {
+        let toplevel = self.u8()? != 0;
+        let parameters = self.parameters()?;
+        let mut locals = Vec::new();
+        for _ in 0..self.len()? {
+            locals.push(self.symbol()?);
+        }
+        let mut upvalues = Vec::new();
+        for _ in 0..self.len()? {
+            let name = self.symbol()?;
+            let capture = match (self.u8()?, self.u16()?) {
+                (0, index) => Capture::Local(index),
+                (1, index) => Capture::Upvalue(index),
+                (kind, _) => return Err(format!("unknown capture kind {}", kind)),
+            };
+            upvalues.push((name, capture));
+        }
+        let constants = self.objects()?;
+        let mut protos = Vec::new();
+        for _ in 0..self.len()? {
+            protos.push(Rc::new(self.proto()?));
+        }
+        let len = self.len()?;
+        let code = self.take(len)?.to_vec();
+        let mut spans = Vec::new();
+        for _ in 0..self.len()? {
+            let offset = self.len()?;
+            let span = Span {
+                line: self.len()?,
+                column: self.len()?,
+            };
+            spans.push((offset, span));
+        }
+
+        let proto = Proto {
+            parameters,
+            locals,
+            upvalues,
+            constants,
+            protos,
+            code,
+            spans,
+            toplevel,
+        };
+        verify(&proto)?;
+        Ok(proto)
+    }
+}
+
+/// Checks that every instruction is whole, that its operands are in range,
+/// that jumps land on instructions and that the stack holds the values each
+/// instruction takes, as many whichever way it is reached, so that running
+/// the code cannot index out of bounds.
+fn verify(proto: &Proto) -> Result<(), String> {
+    let code = &proto.code;
+    let locals = proto.locals.len();
+    let mut starts = Vec::new();
+    let mut targets = Vec::new();
+    let mut instructions = Vec::new();
+    let mut last = None;
+    let mut ip = 0;
+
+    while ip < code.len() {
+        let op = Op::from_byte(code[ip])
+            .ok_or_else(|| format!("unknown instruction {} at {}", code[ip], ip))?;
+        let size: usize = op.operands().iter().sum();
+        let operands = code
+            .get(ip + 1..ip + 1 + size)
+            .ok_or_else(|| format!("truncated instruction at {}", ip))?;
+        let operand = |i: usize| u16::from_le_bytes([operands[i], operands[i + 1]]) as usize;
+        let in_range = match op {
+            Op::Constant => operand(0) < proto.constants.len(),
+            Op::GetGlobal | Op::SetGlobal | Op::DefineGlobal => {
+                matches!(proto.constants.get(operand(0)), Some(Object::Symbol(_)))
+            }
+            Op::GetLocal
+            | Op::BindLocal
+            | Op::MakeCell
+            | Op::GetCell
+            | Op::SetCell
+            | Op::DefineCell => operand(0) < locals,
+            Op::GetUpvalue | Op::SetUpvalue => operand(0) < proto.upvalues.len(),
+            Op::Closure => operand(0) < proto.protos.len(),
+            Op::Jump | Op::JumpIfFalse => {
+                targets.push(operand(0));
+                true
+            }
+            Op::JumpIfBound => {
+                targets.push(operand(2));
+                operand(0) < locals
+            }
+            Op::Pop | Op::Call | Op::TailCall | Op::Return => true,
+        };
+        if !in_range {
+            return Err(format!("operand out of range at {}", ip));
+        }
+        let argc = match op {
+            Op::Call | Op::TailCall => operands[0] as usize,
+            _ => 0,
+        };
+        let target = match op {
+            Op::Jump | Op::JumpIfFalse | Op::JumpIfBound => targets.last().copied(),
+            _ => None,
+        };
+        starts.push(ip);
+        instructions.push((op, argc, target));
+        last = Some(op);
+        ip += 1 + size;
+    }
+
+    if last != Some(Op::Return) {
+        return Err(String::from("code does not end with a return"));
+    }
+    if let Some(target) = targets.iter().find(|t| starts.binary_search(t).is_err()) {
+        return Err(format!("jump to {} is not to an instruction", target));
+    }
+
+    // Follows every path through the code with the number of values on the
+    // stack above the locals.
+    let mut heights = vec![None; instructions.len()];
+    let mut pending = vec![(0, 0)];
+    while let Some((index, height)) = pending.pop() {
+        match heights[index] {
+            Some(known) if known == height => continue,
+            Some(known) => {
+                return Err(format!(
+                    "stack height at {} is either {} or {}",
+                    starts[index], known, height
+                ))
+            }
+            None => heights[index] = Some(height),
+        }
+
+        let (op, argc, target) = instructions[index];
+        let (takes, leaves) = stack_effect(op, argc);
+        if height < takes {
+            return Err(format!("stack underflow at {}", starts[index]));
+        }
+        let height = height - takes + leaves;
+        if let Some(target) = target {
+            // Every target was found among the starts above.
+            let target = starts.binary_search(&target).unwrap();
+            pending.push((target, height));
+        }
+        if op != Op::Jump && op != Op::Return {
+            pending.push((index + 1, height));
+        }
+    }
+    for inner in &proto.protos {
+        for (_, capture) in &inner.upvalues {
+            let in_range = match *capture {
+                Capture::Local(index) => (index as usize) < locals,
+                Capture::Upvalue(index) => (index as usize) < proto.upvalues.len(),
+            };
+            if !in_range {
+                return Err(String::from(
+                    "closure captures a variable that does not exist",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How many values an instruction takes from the stack, and how many it
+/// leaves there.
+fn stack_effect(op: Op, argc: usize) -> (usize, usize) {
+    match op {
+        Op::Constant
+        | Op::GetGlobal
+        | Op::GetLocal
+        | Op::GetCell
+        | Op::GetUpvalue
+        | Op::Closure => (0, 1),
+        Op::SetGlobal | Op::DefineGlobal | Op::SetCell | Op::DefineCell | Op::SetUpvalue => (1, 1),
+        Op::BindLocal | Op::Pop | Op::JumpIfFalse | Op::Return => (1, 0),
+        Op::MakeCell | Op::Jump | Op::JumpIfBound => (0, 0),
+        Op::Call | Op::TailCall => (argc + 1, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Environment;
+
+    const PROGRAM: &str = "(define fact (lambda (n &optional (acc 1))
+  (if (< n 2) acc (fact (- n 1) (* n acc)))))
+(define data '(1 2.5 #\\a \"s\" :k [1 2] {:a 1} #(3)))
+(guard (e (#t 'caught)) (raise 'oops))
+(list (fact 10) data)";
+
+    fn round_trip(code: &str) -> Module {
+        let module = Module::compile(code).unwrap();
+        let loaded = Module::from_bytes(&module.to_bytes().unwrap()).unwrap();
+        assert_eq!(loaded, module);
+        loaded
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let module = round_trip(PROGRAM);
+        assert!(matches!(module.forms[0].0, Form::Compiled(_)));
+        assert!(matches!(module.forms[2].0, Form::Source(_)));
+        assert_eq!(module.forms[3].1, Span { line: 5, column: 1 });
+
+        let env = Environment::new();
+        let loaded = module.run(env.clone()).unwrap();
+        let mut expected = Object::Nil;
+        for exp in reader::read(PROGRAM).unwrap() {
+            expected = evaluator::eval(exp, env.clone()).unwrap();
+        }
+        assert_eq!(loaded.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn test_errors_carry_the_span_of_the_failing_code() {
+        let module = round_trip("(define f (lambda () (car 1)))\n(f)");
+        match module.run(Environment::new()) {
+            Err(Object::Error(e)) => assert_eq!(
+                e.span,
+                Some(Span {
+                    line: 1,
+                    column: 22
+                })
+            ),
+            other => panic!("expected an error, got {:?}", other),
+        }
+
+        // Code left to the evaluator is only located by its top-level form.
+        let module = round_trip("(define f (lambda () (guard (e (#f 0)) (car 1))))\n(f)");
+        match module.run(Environment::new()) {
+            Err(Object::Error(e)) => assert_eq!(e.span, Some(Span { line: 2, column: 1 })),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_disassembly_lists_every_form() {
+        let listing = round_trip(PROGRAM).disassemble();
+        assert!(listing.starts_with("; line 1, column 1\n== form 0 ==\n"));
+        assert!(listing.contains("== form 0/0 (n &optional (acc 1)) =="));
+        assert!(listing.contains("     2  get-global"));
+        assert!(listing.contains("; line 4, column 1\n== form 2 ==\nsource: (guard"));
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let bytes = Module::compile("(+ 1 2)").unwrap().to_bytes().unwrap();
+        assert_eq!(
+            Module::from_bytes(b"(+ 1 2)"),
+            Err(String::from("not a compiled risp module"))
+        );
+
+        let mut newer = bytes.clone();
+        newer[5] = 3;
+        assert_eq!(
+            Module::from_bytes(&newer),
+            Err(String::from("unsupported module version 3 (expected 2)"))
+        );
+
+        let mut corrupt = bytes.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert_eq!(
+            Module::from_bytes(&corrupt),
+            Err(String::from("checksum mismatch, the module is corrupt"))
+        );
+
+        assert!(Module::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        // A call that takes more arguments than the code pushed, in a file
+        // whose checksum is right.
+        let mut underflow = bytes.clone();
+        let call = underflow
+            .windows(3)
+            .rposition(|w| w == [Op::TailCall as u8, 2, Op::Return as u8])
+            .unwrap();
+        underflow[call + 1] = 4;
+        let checksum = crc32(&underflow[HEADER_LEN..]);
+        underflow[7..HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            Module::from_bytes(&underflow),
+            Err(String::from("stack underflow at 9"))
+        );
+    }
+}
@@ -1,20 +1,91 @@
+use std::any::{self, Any};
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
 use std::rc::Rc;
 
+use crate::analysis::{Global, Local};
+use crate::compiler::Template;
+use crate::condition::Debugger;
+use crate::convert::IntoNative;
+use crate::disassembler;
+use crate::error::{ErrorKind, ErrorObject, Span};
+use crate::evaluator::{Continuation, Primitive};
+use crate::exception;
+use crate::gc::{self, Kind, Trace, Tracer};
+use crate::hash_table::{self, TableRef};
+use crate::lazy::{self, Promise};
+use crate::persistent::{self, PersistentMap, PersistentVector};
+use crate::symbol::{self, Symbol, SymbolMap};
+use crate::text;
+use crate::vector::{self, VectorRef};
+use crate::vm::Closure;
+
 pub struct Environment {
     parent: Option<EnvRef>,
-    entries: HashMap<String, Object>,
+    /// The outermost environment this one is within, and how many
+    /// environments down from it this one is, so that globals can be looked
+    /// up without walking the chain.
+    root: Option<EnvRef>,
+    depth: usize,
+    entries: SymbolMap<Object>,
+    /// The frame of a lambda application: its parameters and internal
+    /// definitions, in the order `analysis::frame_layout` gives them. A slot
+    /// is empty until its variable is bound.
+    slot_names: Rc<[Symbol]>,
+    slots: Vec<Option<Object>>,
+    /// Offered the conditions that go unhandled in code run in this
+    /// environment, or in those within it. Only set on a global one.
+    debugger: Option<Debugger>,
 }
 
 pub type EnvRef = Rc<RefCell<Environment>>;
 
+impl Trace for RefCell<Environment> {
+    fn kind(&self) -> Kind {
+        Kind::Environment
+    }
+
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        let env = match self.try_borrow() {
+            Ok(env) => env,
+            Err(_) => return false,
+        };
+        if let Some(parent) = &env.parent {
+            tracer.edge(parent);
+        }
+        if let Some(root) = &env.root {
+            tracer.edge(root);
+        }
+        env.entries.values().for_each(|value| tracer.object(value));
+        env.slots
+            .iter()
+            .flatten()
+            .for_each(|value| tracer.object(value));
+        true
+    }
+
+    fn clear(&self) {
+        if let Ok(mut env) = self.try_borrow_mut() {
+            env.parent = None;
+            env.root = None;
+            env.entries.clear();
+            env.slots.iter_mut().for_each(|slot| *slot = None);
+        }
+    }
+}
+
 impl Environment {
     pub fn new() -> EnvRef {
         let mut env = Environment {
             parent: None,
-            entries: HashMap::new(),
+            root: None,
+            depth: 0,
+            entries: SymbolMap::default(),
+            slot_names: Rc::from(Vec::new()),
+            slots: Vec::new(),
+            debugger: None,
         };
 
         let native_functions = &[
@@ -24,51 +95,403 @@ impl Environment {
             ("list", Function::Native(list)),
             ("cons", Function::Native(cons)),
             ("car", Function::Native(car)),
+            ("cdr", Function::Native(cdr)),
+            ("null?", Function::Native(is_null)),
+            ("append", Function::Native(append)),
+            ("=", Function::Native(num_eq)),
+            ("<", Function::Native(less_than)),
+            ("error", Function::Native(exception::error)),
+            (
+                "error-object?",
+                Function::Native(exception::is_error_object),
+            ),
+            (
+                "error-object-message",
+                Function::Native(exception::error_object_message),
+            ),
+            (
+                "error-object-irritants",
+                Function::Native(exception::error_object_irritants),
+            ),
+            ("make-promise", Function::Native(lazy::make_promise)),
+            ("vector", Function::Native(vector::vector)),
+            ("vector?", Function::Native(vector::is_vector)),
+            ("make-vector", Function::Native(vector::make_vector)),
+            ("vector-ref", Function::Native(vector::vector_ref)),
+            ("vector-set!", Function::Native(vector::vector_set)),
+            ("vector-length", Function::Native(vector::vector_length)),
+            ("vector->list", Function::Native(vector::vector_to_list)),
+            ("vector-map", Function::Native(vector::vector_map)),
+            ("vector-fill!", Function::Native(vector::vector_fill)),
+            ("promise?", Function::Native(lazy::is_promise)),
+            (
+                "make-hash-table",
+                Function::Native(hash_table::make_hash_table),
+            ),
+            ("hash-table?", Function::Native(hash_table::is_hash_table)),
+            ("hash-ref", Function::Native(hash_table::hash_ref)),
+            ("hash-set!", Function::Native(hash_table::hash_set)),
+            ("hash-delete!", Function::Native(hash_table::hash_delete)),
+            ("hash-update!", Function::Native(hash_table::hash_update)),
+            ("hash-count", Function::Native(hash_table::hash_count)),
+            ("hash-keys", Function::Native(hash_table::hash_keys)),
+            ("hash-values", Function::Native(hash_table::hash_values)),
+            ("hash->list", Function::Native(hash_table::hash_to_list)),
+            ("hash-for-each", Function::Native(hash_table::hash_for_each)),
+            ("symbol?", Function::Native(symbol::is_symbol)),
+            ("gensym", Function::Native(symbol::gensym)),
+            ("keyword?", Function::Native(symbol::is_keyword)),
+            ("symbol->string", Function::Native(symbol::symbol_to_string)),
+            ("string->symbol", Function::Native(symbol::string_to_symbol)),
+            ("char?", Function::Native(text::is_char)),
+            ("char->integer", Function::Native(text::char_to_integer)),
+            ("integer->char", Function::Native(text::integer_to_char)),
+            ("char-upcase", Function::Native(text::char_upcase)),
+            ("char-downcase", Function::Native(text::char_downcase)),
+            (
+                "char-alphabetic?",
+                Function::Native(text::is_char_alphabetic),
+            ),
+            ("string-length", Function::Native(text::string_length)),
+            ("string-ref", Function::Native(text::string_ref)),
+            ("assoc", Function::Native(persistent::assoc)),
+            ("dissoc", Function::Native(persistent::dissoc)),
+            ("conj", Function::Native(persistent::conj)),
+            ("get", Function::Native(persistent::get)),
+            (
+                "disassemble",
+                Function::Native(disassembler::disassemble_native),
+            ),
+            ("gc", Function::Native(gc::gc)),
+            ("gc-stats", Function::Native(gc::gc_stats)),
         ];
 
-        for (name, func) in native_functions.into_iter() {
+        for (name, func) in native_functions.iter() {
             env.define(name.to_string(), Object::Callable(func.clone()))
                 .unwrap();
         }
 
-        Rc::new(RefCell::new(env))
+        let primitives = &[
+            ("call/cc", Primitive::CallCC),
+            ("call-with-current-continuation", Primitive::CallCC),
+            ("dynamic-wind", Primitive::DynamicWind),
+            ("with-exception-handler", Primitive::WithExceptionHandler),
+            ("raise", Primitive::Raise),
+            ("raise-continuable", Primitive::RaiseContinuable),
+            ("signal", Primitive::Signal),
+            ("invoke-restart", Primitive::InvokeRestart),
+            ("compute-restarts", Primitive::ComputeRestarts),
+            ("force", Primitive::Force),
+        ];
+
+        for (name, primitive) in primitives.iter() {
+            env.define(
+                name.to_string(),
+                Object::Callable(Function::Primitive(*primitive)),
+            )
+            .unwrap();
+        }
+
+        let env = Rc::new(RefCell::new(env));
+        gc::track(&env);
+        lazy::define_streams(&env);
+        env
     }
 
     pub fn new_child(parent: EnvRef) -> EnvRef {
+        Environment::new_frame(parent, Rc::from(Vec::new()), Vec::new())
+    }
+
+    /// Makes the environment of a lambda application, with `values` in its
+    /// first slots and the rest empty.
+    pub fn new_frame(parent: EnvRef, slot_names: Rc<[Symbol]>, values: Vec<Object>) -> EnvRef {
+        let mut slots: Vec<Option<Object>> = values.into_iter().map(Some).collect();
+        slots.resize(slot_names.len(), None);
+        let (root, depth) = {
+            let parent_env = parent.borrow();
+            let root = parent_env.root.clone().unwrap_or_else(|| parent.clone());
+            (root, parent_env.depth + 1)
+        };
         let env = Environment {
             parent: Some(parent),
-            entries: HashMap::new(),
+            root: Some(root),
+            depth,
+            entries: SymbolMap::default(),
+            slot_names,
+            slots,
+            debugger: None,
         };
 
-        Rc::new(RefCell::new(env))
+        let env = Rc::new(RefCell::new(env));
+        gc::track(&env);
+        env
     }
 
-    pub fn define(&mut self, key: String, obj: Object) -> Result<Object, Object> {
-        self.entries.insert(key, obj);
+    pub fn set_debugger(&mut self, debugger: Option<Debugger>) {
+        self.debugger = debugger;
+    }
+
+    /// The debugger of the global environment this one is within.
+    pub(crate) fn debugger(&self) -> Option<Debugger> {
+        match &self.root {
+            Some(root) => root.borrow().debugger.clone(),
+            None => self.debugger.clone(),
+        }
+    }
+
+    fn slot(&self, key: Symbol) -> Option<usize> {
+        self.slot_names.iter().position(|name| *name == key)
+    }
+
+    pub fn define(&mut self, key: impl Into<Symbol>, obj: Object) -> Result<Object, Object> {
+        let key = key.into();
+        match self.slot(key) {
+            Some(index) => self.slots[index] = Some(obj),
+            None => {
+                self.entries.insert(key, obj);
+            }
+        }
         Ok(Object::Nil)
     }
 
-    pub fn get(&self, key: &String) -> Object {
-        match self.entries.get(key) {
-            Some(val) => val.clone(),
+    pub fn define_native<F>(&mut self, name: &str, arity: Arity, func: F)
+    where
+        F: Fn(&[Object], EnvRef) -> Result<Object, Object> + 'static,
+    {
+        let closure = NativeClosure {
+            name: name.to_string(),
+            arity,
+            func: Rc::new(func),
+        };
+        self.entries.insert(
+            Symbol::intern(name),
+            Object::Callable(Function::NativeClosure(closure)),
+        );
+    }
+
+    /// Defines a typed Rust function, e.g. `|a: i64, b: i64| a + b`, whose
+    /// arguments and result are converted automatically.
+    pub fn define_fn<Args, F: IntoNative<Args>>(&mut self, name: &str, func: F) {
+        let closure = func.into_native(name);
+        self.entries.insert(
+            Symbol::intern(name),
+            Object::Callable(Function::NativeClosure(closure)),
+        );
+    }
+
+    /// Assigns to an existing binding, in this environment or the nearest
+    /// parent that has one.
+    pub fn set(&mut self, key: impl Into<Symbol>, obj: Object) -> Result<(), Object> {
+        let key = key.into();
+        if let Some(Some(slot)) = self.slot(key).map(|index| &mut self.slots[index]) {
+            *slot = obj;
+            return Ok(());
+        }
+        if let Some(entry) = self.entries.get_mut(&key) {
+            *entry = obj;
+            return Ok(());
+        }
+
+        match self.parent {
+            Some(ref parent) => parent.borrow_mut().set(key, obj),
+            None => Err(Object::new_error_with(
+                ErrorKind::Unbound,
+                "unbound variable",
+                vec![Object::Symbol(key)],
+            )),
+        }
+    }
+
+    pub fn get(&self, key: impl Into<Symbol>) -> Object {
+        self.lookup(key).unwrap_or(Object::Nil)
+    }
+
+    /// Like `get`, but tells an unbound name apart from one bound to nil.
+    pub fn lookup(&self, key: impl Into<Symbol>) -> Option<Object> {
+        self.lookup_symbol(key.into())
+    }
+
+    fn lookup_symbol(&self, key: Symbol) -> Option<Object> {
+        if let Some(Some(value)) = self.slot(key).map(|index| &self.slots[index]) {
+            return Some(value.clone());
+        }
+        match self.entries.get(&key) {
+            Some(val) => Some(val.clone()),
             None => match self.parent {
-                Some(ref parent) => parent.borrow().get(key),
-                None => Object::Nil,
+                Some(ref parent) => parent.borrow().lookup_symbol(key),
+                None => None,
             },
         }
     }
+
+    /// Looks up a reference resolved by `analysis::analyze`, falling back to
+    /// a lookup by name while its slot is empty.
+    pub fn lookup_local(&self, local: Local) -> Option<Object> {
+        if local.depth > 0 {
+            let local = Local {
+                depth: local.depth - 1,
+                ..local
+            };
+            return self.parent.as_ref()?.borrow().lookup_local(local);
+        }
+
+        match self.slots.get(local.index) {
+            Some(Some(value)) => Some(value.clone()),
+            _ => self.lookup_symbol(local.name),
+        }
+    }
+
+    /// Looks up a reference resolved by `analysis::analyze` by name, starting
+    /// `depth` environments up, which for code evaluated in the outermost
+    /// environment is that environment itself.
+    pub fn lookup_global(&self, global: Global) -> Option<Object> {
+        match &self.root {
+            Some(root) if self.depth == global.depth => {
+                return root.borrow().lookup_symbol(global.name)
+            }
+            _ => {}
+        }
+        if global.depth > 0 {
+            let global = Global {
+                depth: global.depth - 1,
+                ..global
+            };
+            return self.parent.as_ref()?.borrow().lookup_global(global);
+        }
+        self.lookup_symbol(global.name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.entries.keys().map(|key| key.to_string()).collect();
+        for (name, slot) in self.slot_names.iter().zip(self.slots.iter()) {
+            if slot.is_some() {
+                names.push(name.to_string());
+            }
+        }
+        if let Some(ref parent) = self.parent {
+            for name in parent.borrow().names() {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names.sort();
+        names
+    }
 }
 
 pub type BuiltinFunction = fn(&[Object], EnvRef) -> Result<Object, Object>;
 
+pub type NativeFn = Rc<dyn Fn(&[Object], EnvRef) -> Result<Object, Object>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+    Range(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, n: usize) -> bool {
+        match *self {
+            Arity::Exact(expected) => n == expected,
+            Arity::AtLeast(min) => n >= min,
+            Arity::Range(min, max) => n >= min && n <= max,
+        }
+    }
+
+    /// The error for a call of `name` with `got` arguments, which this
+    /// arity does not accept.
+    pub fn error(&self, name: &str, got: usize) -> Object {
+        Object::new_error(
+            ErrorKind::Arity,
+            &format!(
+                "{}: wrong number of arguments (expected {}, got {})",
+                name, self, got
+            ),
+        )
+    }
+
+    /// Checks that a call of `name` was given as many arguments as this
+    /// arity accepts.
+    pub fn check(&self, name: &str, args: &[Object]) -> Result<(), Object> {
+        match self.accepts(args.len()) {
+            true => Ok(()),
+            false => Err(self.error(name, args.len())),
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exact(expected) => write!(f, "{}", expected),
+            Arity::AtLeast(min) => write!(f, "at least {}", min),
+            Arity::Range(min, max) => write!(f, "{} to {}", min, max),
+        }
+    }
+}
+
+/// A native function that, unlike `Function::Native`, can capture host state.
+#[derive(Clone)]
+pub struct NativeClosure {
+    pub name: String,
+    pub arity: Arity,
+    pub func: NativeFn,
+}
+
+impl NativeClosure {
+    pub fn call(&self, args: &[Object], env: EnvRef) -> Result<Object, Object> {
+        self.arity.check(&self.name, args)?;
+        (self.func)(args, env)
+    }
+}
+
+#[derive(Clone)]
+pub struct Lambda {
+    pub name: Option<Symbol>,
+    pub template: Rc<Template>,
+    pub env: EnvRef,
+}
+
+impl Lambda {
+    pub fn display_name(&self) -> &'static str {
+        self.name.map_or("<lambda>", Symbol::as_str)
+    }
+}
+
 pub enum Function {
     Native(BuiltinFunction),
-    Lambda(Vec<Object>, Vec<Object>, EnvRef),
+    NativeClosure(NativeClosure),
+    Lambda(Lambda),
+    Primitive(Primitive),
+    Continuation(Rc<Continuation>),
+    Compiled(Rc<Closure>),
 }
 
 impl PartialEq for Function {
     fn eq(&self, other: &Function) -> bool {
-        self == other
+        match (self, other) {
+            (Function::Native(a), Function::Native(b)) => *a as usize == *b as usize,
+            (Function::NativeClosure(a), Function::NativeClosure(b)) => {
+                Rc::ptr_eq(&a.func, &b.func)
+            }
+            (Function::Lambda(a), Function::Lambda(b)) => {
+                a.template == b.template && Rc::ptr_eq(&a.env, &b.env)
+            }
+            (Function::Primitive(a), Function::Primitive(b)) => a == b,
+            (Function::Continuation(a), Function::Continuation(b)) => Rc::ptr_eq(a, b),
+            (Function::Compiled(a), Function::Compiled(b)) => {
+                Rc::ptr_eq(&a.proto, &b.proto)
+                    && Rc::ptr_eq(&a.env, &b.env)
+                    && a.upvalues.len() == b.upvalues.len()
+                    && a.upvalues
+                        .iter()
+                        .zip(&b.upvalues)
+                        .all(|(x, y)| Rc::ptr_eq(x, y))
+            }
+            _ => false,
+        }
     }
 }
 
@@ -76,7 +499,17 @@ impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Function::Native(_) => write!(f, "<native>"),
-            Function::Lambda(_, _, _) => write!(f, "<lambda>"),
+            Function::NativeClosure(closure) => write!(f, "<native {}>", closure.name),
+            Function::Lambda(lambda) => match lambda.name {
+                Some(name) => write!(f, "<lambda {}>", name),
+                None => write!(f, "<lambda>"),
+            },
+            Function::Primitive(primitive) => write!(f, "<primitive {}>", primitive.name()),
+            Function::Continuation(_) => write!(f, "<continuation>"),
+            Function::Compiled(closure) => match closure.name {
+                Some(name) => write!(f, "<lambda {}>", name),
+                None => write!(f, "<lambda>"),
+            },
         }
     }
 }
@@ -85,54 +518,348 @@ impl Clone for Function {
     fn clone(&self) -> Function {
         match *self {
             Function::Native(ref func) => Function::Native(*func),
-            Function::Lambda(ref parameters, ref body, ref env) => {
-                Function::Lambda(parameters.clone(), body.clone(), env.clone())
+            Function::NativeClosure(ref closure) => Function::NativeClosure(closure.clone()),
+            Function::Lambda(ref lambda) => Function::Lambda(lambda.clone()),
+            Function::Primitive(primitive) => Function::Primitive(primitive),
+            Function::Continuation(ref continuation) => {
+                Function::Continuation(continuation.clone())
             }
+            Function::Compiled(ref closure) => Function::Compiled(closure.clone()),
         }
     }
 }
 
-#[derive(Clone, PartialEq)]
+/// A host value that Lisp code can pass around but not look into.
+#[derive(Clone)]
+pub struct Opaque {
+    pub type_name: &'static str,
+    pub value: Rc<dyn Any>,
+}
+
+impl Opaque {
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.value.downcast_ref::<T>()
+    }
+
+    pub fn downcast_rc<T: Any>(&self) -> Option<Rc<T>> {
+        self.value.clone().downcast::<T>().ok()
+    }
+}
+
+impl PartialEq for Opaque {
+    fn eq(&self, other: &Opaque) -> bool {
+        Rc::ptr_eq(&self.value, &other.value)
+    }
+}
+
+#[derive(Clone)]
 pub enum Object {
     Nil,
+    Boolean(bool),
     Integer(i64),
-    Symbol(String),
+    Float(f64),
+    Char(char),
+    String(String),
+    Symbol(Symbol),
+    /// A variable reference resolved by `analysis::analyze`. Only ever
+    /// found in code, never produced as a value.
+    Local(Local),
+    /// A global reference resolved by `analysis::analyze`, likewise only
+    /// found in code.
+    Global(Global),
+    /// `:name`, stored without the colon.
+    Keyword(Symbol),
     List(Vec<Object>),
     Callable(Function),
-    Error(String),
+    Opaque(Opaque),
+    Error(Box<ErrorObject>),
+    Promise(Rc<Promise>),
+    Vector(VectorRef),
+    HashTable(TableRef),
+    PersistentVector(PersistentVector),
+    PersistentMap(PersistentMap),
 }
 
 impl Object {
-    pub fn new_error(message: &str) -> Object {
-        Object::Error(String::from(message))
+    pub fn new_error(kind: ErrorKind, message: &str) -> Object {
+        Object::new_error_with(kind, message, Vec::new())
     }
 
-    pub fn has_symbol_value(&self, s: &str) -> Option<bool> {
+    pub fn new_error_with(kind: ErrorKind, message: &str, irritants: Vec<Object>) -> Object {
+        Object::Error(Box::new(ErrorObject::new(kind, message, irritants)))
+    }
+
+    pub fn symbol(name: &str) -> Object {
+        Object::Symbol(Symbol::intern(name))
+    }
+
+    pub fn keyword(name: &str) -> Object {
+        Object::Keyword(Symbol::intern(name))
+    }
+
+    pub fn type_error(message: &str, irritant: &Object) -> Object {
+        Object::new_error_with(ErrorKind::Type, message, vec![irritant.clone()])
+    }
+
+    /// Records that this error unwound through the function `name`.
+    pub fn with_frame(self, name: &str) -> Object {
+        match self {
+            Object::Error(mut e) => {
+                e.trace.push(name.to_string());
+                Object::Error(e)
+            }
+            other => other,
+        }
+    }
+
+    /// Sets the error's source location unless a more precise one is known.
+    pub fn with_span(self, span: Span) -> Object {
         match self {
-            Object::Symbol(sym) => Some(sym == s),
+            Object::Error(mut e) => {
+                e.span.get_or_insert(span);
+                Object::Error(e)
+            }
+            other => other,
+        }
+    }
+
+    pub fn with_cause(self, cause: Object) -> Object {
+        match self {
+            Object::Error(mut e) => {
+                e.cause = Some(cause);
+                Object::Error(e)
+            }
+            other => other,
+        }
+    }
+
+    pub fn new_opaque<T: Any>(value: T) -> Object {
+        Object::new_opaque_rc(Rc::new(value))
+    }
+
+    pub fn new_opaque_rc<T: Any>(value: Rc<T>) -> Object {
+        Object::Opaque(Opaque {
+            type_name: short_type_name::<T>(),
+            value,
+        })
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        match self {
+            Object::Opaque(opaque) => opaque.downcast_ref::<T>(),
             _ => None,
         }
     }
+
+    pub fn downcast_rc<T: Any>(&self) -> Option<Rc<T>> {
+        match self {
+            Object::Opaque(opaque) => opaque.downcast_rc::<T>(),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::Nil => "nil",
+            Object::Boolean(_) => "boolean",
+            Object::Integer(_) => "integer",
+            Object::Float(_) => "float",
+            Object::Char(_) => "char",
+            Object::String(_) => "string",
+            Object::Symbol(_) | Object::Local(_) | Object::Global(_) => "symbol",
+            Object::Keyword(_) => "keyword",
+            Object::List(_) => "list",
+            Object::Callable(_) => "procedure",
+            Object::Opaque(opaque) => opaque.type_name,
+            Object::Error(_) => "error",
+            Object::Promise(_) => "promise",
+            Object::Vector(_) => "vector",
+            Object::HashTable(_) => "hash-table",
+            Object::PersistentVector(_) => "persistent-vector",
+            Object::PersistentMap(_) => "persistent-map",
+        }
+    }
+
+    /// Everything but `#f` counts as true in conditionals.
+    pub fn is_truthy(&self) -> bool {
+        *self != Object::Boolean(false)
+    }
+
+    /// Whether this object can be a hash table key: an immutable value that
+    /// is equal to itself. NaN is not, and mutable containers could change
+    /// their hash while stored.
+    pub fn is_hashable(&self) -> bool {
+        match self {
+            Object::Nil
+            | Object::Boolean(_)
+            | Object::Integer(_)
+            | Object::Char(_)
+            | Object::String(_)
+            | Object::Symbol(_)
+            | Object::Keyword(_) => true,
+            Object::Float(num) => !num.is_nan(),
+            Object::List(items) => items.iter().all(Object::is_hashable),
+            Object::PersistentVector(v) => v.iter().all(Object::is_hashable),
+            Object::PersistentMap(map) => map.pairs().iter().all(|(_, v)| v.is_hashable()),
+            _ => false,
+        }
+    }
+
+    pub fn has_symbol_value(&self, s: Symbol) -> Option<bool> {
+        match self {
+            Object::Symbol(sym) => Some(*sym == s),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Object) -> bool {
+        match (self, other) {
+            (Object::Nil, Object::Nil) => true,
+            (Object::Boolean(a), Object::Boolean(b)) => a == b,
+            (Object::Integer(a), Object::Integer(b)) => a == b,
+            (Object::Float(a), Object::Float(b)) => a == b,
+            (Object::Char(a), Object::Char(b)) => a == b,
+            (Object::String(a), Object::String(b)) => a == b,
+            (Object::Symbol(a), Object::Symbol(b)) => a == b,
+            (Object::Local(a), Object::Local(b)) => a == b,
+            (Object::Global(a), Object::Global(b)) => a == b,
+            (Object::Keyword(a), Object::Keyword(b)) => a == b,
+            (Object::List(a), Object::List(b)) => a == b,
+            (Object::Callable(a), Object::Callable(b)) => a == b,
+            (Object::Opaque(a), Object::Opaque(b)) => a == b,
+            (Object::Error(a), Object::Error(b)) => a == b,
+            (Object::Promise(a), Object::Promise(b)) => a == b,
+            (Object::Vector(a), Object::Vector(b)) => same_contents(a, b),
+            (Object::HashTable(a), Object::HashTable(b)) => same_contents(a, b),
+            (Object::PersistentVector(a), Object::PersistentVector(b)) => a == b,
+            (Object::PersistentMap(a), Object::PersistentMap(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+thread_local! {
+    /// The pairs of containers being compared, and the vectors being
+    /// printed, further up the stack.
+    static COMPARING: RefCell<Vec<(usize, usize)>> = const { RefCell::new(Vec::new()) };
+    static PRINTING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Compares two mutable containers by contents. A container can hold
+/// itself, so a pair that is already being compared further up is taken to
+/// be equal rather than compared again forever.
+fn same_contents<T: PartialEq>(a: &Rc<RefCell<T>>, b: &Rc<RefCell<T>>) -> bool {
+    if Rc::ptr_eq(a, b) {
+        return true;
+    }
+    let pair = (Rc::as_ptr(a) as usize, Rc::as_ptr(b) as usize);
+    let started = COMPARING.with(|comparing| {
+        let mut comparing = comparing.borrow_mut();
+        if comparing.contains(&pair) {
+            return false;
+        }
+        comparing.push(pair);
+        true
+    });
+    if !started {
+        return true;
+    }
+    let equal = *a.borrow() == *b.borrow();
+    COMPARING.with(|comparing| comparing.borrow_mut().pop());
+    equal
+}
+
+// `Eq` asks that every object equal itself. NaN does not, nor does anything
+// that holds it, but none of those is hashable, and hash tables, persistent
+// maps and the reader all check `is_hashable` before using an object as a
+// key. `Hash` agrees with `==` for every object.
+impl Eq for Object {}
+
+impl Hash for Object {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Object::Boolean(b) => b.hash(state),
+            Object::Integer(num) => num.hash(state),
+            // -0.0 == 0.0, so they must hash alike.
+            Object::Float(num) if *num == 0.0 => 0f64.to_bits().hash(state),
+            Object::Float(num) if num.is_nan() => f64::NAN.to_bits().hash(state),
+            Object::Float(num) => num.to_bits().hash(state),
+            Object::Char(c) => c.hash(state),
+            Object::String(s) => s.hash(state),
+            Object::Symbol(sym) | Object::Keyword(sym) => sym.hash(state),
+            Object::List(items) => items.hash(state),
+            Object::PersistentVector(v) => v.hash(state),
+            Object::PersistentMap(map) => map.hash(state),
+            _ => {}
+        }
+    }
+}
+
+fn write_items<'a, I>(f: &mut fmt::Formatter, open: &str, items: I, close: &str) -> fmt::Result
+where
+    I: IntoIterator<Item = &'a Object>,
+{
+    write!(f, "{}", open)?;
+    for (i, item) in items.into_iter().enumerate() {
+        if i != 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    write!(f, "{}", close)
+}
+
+/// Writes a vector, or `#<cycle>` for one that holds itself and is already
+/// being written further up.
+fn write_vector(f: &mut fmt::Formatter, items: &VectorRef) -> fmt::Result {
+    let address = Rc::as_ptr(items) as usize;
+    let started = PRINTING.with(|printing| {
+        let mut printing = printing.borrow_mut();
+        if printing.contains(&address) {
+            return false;
+        }
+        printing.push(address);
+        true
+    });
+    if !started {
+        return write!(f, "#<cycle>");
+    }
+    let result = write_items(f, "#(", items.borrow().iter(), ")");
+    PRINTING.with(|printing| printing.borrow_mut().pop());
+    result
+}
+
+fn write_map(f: &mut fmt::Formatter, map: &PersistentMap) -> fmt::Result {
+    let pairs = map.pairs();
+    write_items(f, "{", pairs.iter().flat_map(|&(k, v)| [k, v]), "}")
 }
 
 impl fmt::Display for Object {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Object::Nil => write!(f, "<nil>"),
+            Object::Boolean(true) => write!(f, "#t"),
+            Object::Boolean(false) => write!(f, "#f"),
             Object::Integer(num) => write!(f, "{}", num),
+            Object::Float(num) => write!(f, "{:?}", num),
+            Object::Char(c) => write!(f, "{}", text::char_literal(*c)),
+            Object::String(s) => write!(f, "{:?}", s),
             Object::Symbol(sym) => write!(f, "{}", sym),
-            Object::Error(sym) => write!(f, "Error({})", sym),
+            Object::Local(local) => write!(f, "{}", local.name),
+            Object::Global(global) => write!(f, "{}", global.name),
+            Object::Keyword(name) => write!(f, ":{}", name),
+            Object::Error(e) => write!(f, "Error({})", e),
             Object::Callable(_) => write!(f, "<callable>"),
-            Object::List(items) => {
-                write!(f, "(")?;
-                for (i, item) in items.iter().enumerate() {
-                    write!(f, "{}", item)?;
-                    if i != items.len() - 1 {
-                        write!(f, " ")?;
-                    }
-                }
-                write!(f, ")")
-            }
+            Object::Opaque(opaque) => write!(f, "<{}>", opaque.type_name),
+            Object::Promise(_) => write!(f, "<promise>"),
+            Object::List(items) => write_items(f, "(", items, ")"),
+            Object::Vector(items) => write_vector(f, items),
+            Object::PersistentVector(v) => write_items(f, "[", v.iter(), "]"),
+            Object::PersistentMap(map) => write_map(f, map),
+            Object::HashTable(table) => write!(f, "<hash-table {}>", table.borrow().len()),
         }
     }
 }
@@ -141,52 +868,82 @@ impl fmt::Debug for Object {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Object::Nil => write!(f, "Object::Nil"),
+            Object::Boolean(b) => write!(f, "Object::Boolean({})", b),
             Object::Integer(num) => write!(f, "Object::Integer({})", num),
+            Object::Float(num) => write!(f, "Object::Float({:?})", num),
+            Object::Char(c) => write!(f, "Object::Char({:?})", c),
+            Object::String(s) => write!(f, "Object::String({:?})", s),
             Object::Symbol(sym) => write!(f, "Object::Symbol({})", sym),
-            Object::Error(sym) => write!(f, "Object::Error({})", sym),
+            Object::Local(local) => write!(
+                f,
+                "Object::Local({}@{}:{})",
+                local.name, local.depth, local.index
+            ),
+            Object::Global(global) => {
+                write!(f, "Object::Global({}@{})", global.name, global.depth)
+            }
+            Object::Keyword(name) => write!(f, "Object::Keyword(:{})", name),
+            Object::Error(e) => write!(f, "Object::Error({:?})", e),
             Object::Callable(_) => write!(f, "Object::Callable(<callable>)"),
-            Object::List(items) => {
-                write!(f, "(")?;
-                for (i, item) in items.iter().enumerate() {
-                    write!(f, "{}", item)?;
-                    if i != items.len() - 1 {
-                        write!(f, " ")?;
-                    }
-                }
-                write!(f, ")")
+            Object::Opaque(opaque) => write!(f, "Object::Opaque(<{}>)", opaque.type_name),
+            Object::Promise(_) => write!(f, "Object::Promise(<promise>)"),
+            Object::List(items) => write_items(f, "(", items, ")"),
+            Object::Vector(items) => write_vector(f, items),
+            Object::PersistentVector(v) => write_items(f, "[", v.iter(), "]"),
+            Object::PersistentMap(map) => write_map(f, map),
+            Object::HashTable(table) => {
+                write!(f, "Object::HashTable(<{} entries>)", table.borrow().len())
             }
         }
     }
 }
 
+/// Strips the module path from `std::any::type_name`, so that `my::db::Conn`
+/// prints as `Conn`.
+pub fn short_type_name<T: Any>() -> &'static str {
+    let name = any::type_name::<T>();
+    let base = name.split('<').next().unwrap_or(name);
+    match base.rfind("::") {
+        Some(i) => &name[i + 2..],
+        None => name,
+    }
+}
+
+/// The error for an arithmetic call whose result does not fit in an integer.
+fn overflow(name: &str, args: &[Object]) -> Object {
+    Object::new_error_with(
+        ErrorKind::Runtime,
+        &format!("{}: integer overflow", name),
+        args.to_vec(),
+    )
+}
+
 pub fn plus(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
-    let mut sum = 0;
+    let mut sum: i64 = 0;
     for i in args.iter() {
         if let Object::Integer(val) = i {
-            sum += val;
+            sum = sum.checked_add(*val).ok_or_else(|| overflow("+", args))?;
         } else {
-            return Ok(Object::Nil);
+            return Err(Object::type_error("+: argument is not an integer", i));
         }
     }
     Ok(Object::Integer(sum))
 }
 
 pub fn minus(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
-    if args.len() < 2 {
-        return Err(Object::new_error("not enough arguments"));
-    }
+    Arity::AtLeast(2).check("-", args)?;
 
     let mut iter = args.iter();
     let mut sum = match iter.next().unwrap() {
         Object::Integer(first) => *first,
-        _ => return Err(Object::new_error("argument has wrong type")),
+        other => return Err(Object::type_error("-: argument is not an integer", other)),
     };
 
     for i in iter {
         if let Object::Integer(val) = i {
-            sum -= val;
+            sum = sum.checked_sub(*val).ok_or_else(|| overflow("-", args))?;
         } else {
-            return Err(Object::Nil);
+            return Err(Object::type_error("-: argument is not an integer", i));
         }
     }
 
@@ -194,12 +951,12 @@ pub fn minus(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
 }
 
 pub fn multiply(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
-    let mut sum = 1;
+    let mut sum: i64 = 1;
     for o in args.iter() {
         if let Object::Integer(val) = o {
-            sum *= val;
+            sum = sum.checked_mul(*val).ok_or_else(|| overflow("*", args))?;
         } else {
-            return Err(Object::Nil);
+            return Err(Object::type_error("*: argument is not an integer", o));
         }
     }
     Ok(Object::Integer(sum))
@@ -211,32 +968,85 @@ pub fn list(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
 }
 
 pub fn cons(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
-    if args.len() != 2 {
-        return Err(Object::new_error("wrong number of arguments"));
-    }
+    Arity::Exact(2).check("cons", args)?;
 
     let items = args.to_vec();
     Ok(Object::List(items))
 }
 
-pub fn car(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
-    if args.len() != 1 {
-        return Err(Object::new_error("wrong number of arguments"));
+fn integer_args(name: &str, args: &[Object]) -> Result<Vec<i64>, Object> {
+    args.iter()
+        .map(|arg| match arg {
+            Object::Integer(n) => Ok(*n),
+            other => Err(Object::type_error(
+                &format!("{}: argument is not an integer", name),
+                other,
+            )),
+        })
+        .collect()
+}
+
+pub fn num_eq(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    let numbers = integer_args("=", args)?;
+    Ok(Object::Boolean(numbers.windows(2).all(|w| w[0] == w[1])))
+}
+
+pub fn less_than(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    let numbers = integer_args("<", args)?;
+    Ok(Object::Boolean(numbers.windows(2).all(|w| w[0] < w[1])))
+}
+
+pub fn append(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    let mut result = Vec::new();
+    for arg in args.iter() {
+        match arg {
+            Object::List(items) => result.extend(items.iter().cloned()),
+            other => return Err(Object::type_error("append: argument is not a list", other)),
+        }
     }
+    Ok(Object::List(result))
+}
+
+pub fn car(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("car", args)?;
 
     let items = match &args[0] {
         Object::List(items) => items,
-        _ => return Err(Object::new_error("argument has wrong type")),
+        other => return Err(Object::type_error("argument has wrong type", other)),
     };
 
     if items.is_empty() {
-        return Err(Object::new_error("empty list"));
+        return Err(Object::new_error(ErrorKind::Runtime, "empty list"));
     }
 
     Ok(items[0].clone())
 }
 
+pub fn cdr(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("cdr", args)?;
+
+    let items = match &args[0] {
+        Object::List(items) => items,
+        other => return Err(Object::type_error("argument has wrong type", other)),
+    };
+
+    if items.is_empty() {
+        return Err(Object::new_error(ErrorKind::Runtime, "empty list"));
+    }
+
+    Ok(Object::List(items[1..].to_vec()))
+}
+
+pub fn is_null(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("null?", args)?;
+
+    Ok(Object::Boolean(
+        matches!(&args[0], Object::List(items) if items.is_empty()),
+    ))
+}
+
 #[cfg(test)]
+#[allow(clippy::vec_init_then_push)] // integer_vec! pushes one element at a time
 mod tests {
     use super::*;
 
@@ -271,6 +1081,48 @@ mod tests {
         assert_eq!(multiply_result, Ok(Object::Integer(6)));
     }
 
+    #[test]
+    fn test_arithmetic_type_errors() {
+        let args = vec![Object::Integer(1), Object::Boolean(true)];
+        let expected = Err(Object::type_error(
+            "+: argument is not an integer",
+            &Object::Boolean(true),
+        ));
+        assert_eq!(plus(&args, Environment::new()), expected);
+
+        let result = minus(&args, Environment::new());
+        match result {
+            Err(Object::Error(e)) => {
+                assert_eq!(e.kind, ErrorKind::Type);
+                assert_eq!(e.irritants, vec![Object::Boolean(true)]);
+            }
+            other => panic!("expected type error, got {:?}", other),
+        }
+
+        let result = multiply(&args, Environment::new());
+        assert!(matches!(result, Err(Object::Error(ref e)) if e.kind == ErrorKind::Type));
+    }
+
+    #[test]
+    fn test_arithmetic_overflow() {
+        for (f, args) in [
+            (plus as BuiltinFunction, integer_vec![i64::MAX, 1]),
+            (minus, integer_vec![i64::MIN, 1]),
+            (multiply, integer_vec![i64::MAX, 2]),
+        ]
+        .iter()
+        {
+            match f(args, Environment::new()) {
+                Err(Object::Error(e)) => {
+                    assert_eq!(e.kind, ErrorKind::Runtime);
+                    assert!(e.message.ends_with("integer overflow"));
+                    assert_eq!(&e.irritants, args);
+                }
+                other => panic!("expected overflow error, got {:?}", other),
+            }
+        }
+    }
+
     #[test]
     fn test_cons() {
         let args = integer_vec![1, 2];
@@ -281,7 +1133,10 @@ mod tests {
         let cons_result = cons(&args, Environment::new());
         assert_eq!(
             cons_result,
-            Err(Object::Error(String::from("wrong number of arguments")))
+            Err(Object::new_error(
+                ErrorKind::Arity,
+                "cons: wrong number of arguments (expected 2, got 4)"
+            ))
         );
     }
 
@@ -293,14 +1148,136 @@ mod tests {
 
         let args = vec![Object::List(Vec::new())];
         let car_result = car(&args, Environment::new());
-        assert_eq!(car_result, Err(Object::Error(String::from("empty list"))));
+        assert_eq!(
+            car_result,
+            Err(Object::new_error(ErrorKind::Runtime, "empty list"))
+        );
 
         let args = vec![Object::Integer(1)];
         let car_result = car(&args, Environment::new());
         assert_eq!(
             car_result,
-            Err(Object::Error(String::from("argument has wrong type")))
+            Err(Object::type_error(
+                "argument has wrong type",
+                &Object::Integer(1)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_cdr_and_null() {
+        let args = vec![Object::List(integer_vec![1, 2, 3])];
+        assert_eq!(
+            cdr(&args, Environment::new()),
+            Ok(Object::List(integer_vec![2, 3]))
+        );
+        assert_eq!(
+            is_null(&args, Environment::new()),
+            Ok(Object::Boolean(false))
+        );
+
+        let args = vec![Object::List(Vec::new())];
+        assert_eq!(
+            cdr(&args, Environment::new()),
+            Err(Object::new_error(ErrorKind::Runtime, "empty list"))
+        );
+        assert_eq!(
+            is_null(&args, Environment::new()),
+            Ok(Object::Boolean(true))
+        );
+    }
+
+    #[test]
+    fn test_define_native_captures_state() {
+        let env = Environment::new();
+        let counter = Rc::new(RefCell::new(0));
+
+        let captured = counter.clone();
+        env.borrow_mut()
+            .define_native("count!", Arity::Exact(0), move |_args, _env| {
+                *captured.borrow_mut() += 1;
+                Ok(Object::Integer(*captured.borrow()))
+            });
+
+        let closure = match env.borrow().get("count!") {
+            Object::Callable(Function::NativeClosure(closure)) => closure,
+            other => panic!("expected native closure, got {:?}", other),
+        };
+        assert_eq!(closure.name, "count!");
+        assert_eq!(closure.arity, Arity::Exact(0));
+
+        assert_eq!(closure.call(&[], env.clone()), Ok(Object::Integer(1)));
+        assert_eq!(closure.call(&[], env.clone()), Ok(Object::Integer(2)));
+        assert_eq!(*counter.borrow(), 2);
+
+        assert_eq!(
+            closure.call(&[Object::Integer(1)], env.clone()),
+            Err(Object::new_error(
+                ErrorKind::Arity,
+                "count!: wrong number of arguments (expected 0, got 1)"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_arity_accepts() {
+        assert!(Arity::Exact(2).accepts(2));
+        assert!(!Arity::Exact(2).accepts(3));
+        assert!(Arity::AtLeast(1).accepts(5));
+        assert!(!Arity::AtLeast(1).accepts(0));
+        assert!(Arity::Range(1, 2).accepts(2));
+        assert!(!Arity::Range(1, 2).accepts(3));
+    }
+
+    #[test]
+    fn test_arity_errors_name_the_counts() {
+        let env = Environment::new();
+        let check = |code: &str| match crate::vm::eval(
+            crate::reader::read(code).unwrap().remove(0),
+            env.clone(),
+        ) {
+            Err(Object::Error(e)) => (e.kind, e.message),
+            other => panic!("expected an arity error, got {:?}", other),
+        };
+        assert_eq!(
+            check("(car)"),
+            (
+                ErrorKind::Arity,
+                String::from("car: wrong number of arguments (expected 1, got 0)")
+            )
+        );
+        assert_eq!(
+            check("(vector-ref #(1) 0 1)").1,
+            "vector-ref: wrong number of arguments (expected 2, got 3)"
+        );
+        assert_eq!(
+            check("(hash-ref)").1,
+            "hash-ref: wrong number of arguments (expected 2 to 3, got 0)"
         );
+        assert_eq!(
+            check("(-)").1,
+            "-: wrong number of arguments (expected at least 2, got 0)"
+        );
+    }
+
+    #[test]
+    fn test_opaque_objects() {
+        struct Connection {
+            port: u16,
+        }
+
+        let conn = Object::new_opaque(Connection { port: 5432 });
+        assert_eq!(conn.type_name(), "Connection");
+        assert_eq!(format!("{}", conn), "<Connection>");
+        assert_eq!(conn.downcast_ref::<Connection>().unwrap().port, 5432);
+        assert!(conn.downcast_ref::<String>().is_none());
+        assert!(Object::Integer(1).downcast_ref::<Connection>().is_none());
+
+        assert_eq!(conn, conn.clone());
+        assert_ne!(conn, Object::new_opaque(Connection { port: 5432 }));
+
+        let rc = conn.downcast_rc::<Connection>().unwrap();
+        assert_eq!(Rc::strong_count(&rc), 2);
     }
 
     #[test]
@@ -338,4 +1315,47 @@ mod tests {
         assert_eq!(child.borrow_mut().get(&only_in_child), Object::Integer(99));
         assert_eq!(parent.borrow_mut().get(&only_in_child), Object::Nil);
     }
+
+    #[test]
+    fn test_environment_set() {
+        let parent = Environment::new();
+        parent
+            .borrow_mut()
+            .define("x".to_string(), Object::Integer(1))
+            .unwrap();
+        let child = Environment::new_child(parent.clone());
+
+        assert_eq!(child.borrow_mut().set("x", Object::Integer(2)), Ok(()));
+        assert_eq!(parent.borrow().get("x"), Object::Integer(2));
+        assert_eq!(
+            child.borrow_mut().set("y", Object::Integer(3)),
+            Err(Object::new_error_with(
+                ErrorKind::Unbound,
+                "unbound variable",
+                vec![Object::symbol("y")]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let env = Environment::new();
+
+        let args = integer_vec![2, 2, 2];
+        assert_eq!(num_eq(&args, env.clone()), Ok(Object::Boolean(true)));
+        let args = integer_vec![2, 3];
+        assert_eq!(num_eq(&args, env.clone()), Ok(Object::Boolean(false)));
+        let args = integer_vec![1, 2, 3];
+        assert_eq!(less_than(&args, env.clone()), Ok(Object::Boolean(true)));
+        let args = integer_vec![1, 3, 2];
+        assert_eq!(less_than(&args, env.clone()), Ok(Object::Boolean(false)));
+
+        assert_eq!(
+            less_than(&[Object::Boolean(true)], env),
+            Err(Object::type_error(
+                "<: argument is not an integer",
+                &Object::Boolean(true)
+            ))
+        );
+    }
 }
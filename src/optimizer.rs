@@ -0,0 +1,720 @@
+use std::fmt;
+use std::mem;
+
+use crate::analysis;
+use crate::object::{self, EnvRef, Function, Object};
+use crate::parameters::Parameters;
+use crate::reader::SpanTree;
+use crate::symbol::{self, Symbol, SymbolMap};
+
+/// The largest lambda body, counted in atoms and lists, that is inlined.
+const INLINE_SIZE: usize = 16;
+
+type Native = fn(&[Object], EnvRef) -> Result<Object, Object>;
+type Fold = fn(&[i64]) -> Option<Object>;
+
+/// The builtins that calls on integer constants are folded for. A call is
+/// left alone when the builtin would raise an error, an overflow included,
+/// so that it still raises it at run time.
+const FOLDABLE: &[(&str, Native, Fold)] = &[
+    ("+", object::plus, fold_plus),
+    ("-", object::minus, fold_minus),
+    ("*", object::multiply, fold_multiply),
+    ("=", object::num_eq, fold_num_eq),
+    ("<", object::less_than, fold_less_than),
+];
+
+fn fold_plus(numbers: &[i64]) -> Option<Object> {
+    let sum = numbers
+        .iter()
+        .try_fold(0i64, |sum, n| sum.checked_add(*n))?;
+    Some(Object::Integer(sum))
+}
+
+fn fold_minus(numbers: &[i64]) -> Option<Object> {
+    let (first, rest) = numbers.split_first().filter(|(_, rest)| !rest.is_empty())?;
+    let difference = rest.iter().try_fold(*first, |sum, n| sum.checked_sub(*n))?;
+    Some(Object::Integer(difference))
+}
+
+fn fold_multiply(numbers: &[i64]) -> Option<Object> {
+    let product = numbers
+        .iter()
+        .try_fold(1i64, |product, n| product.checked_mul(*n))?;
+    Some(Object::Integer(product))
+}
+
+fn fold_num_eq(numbers: &[i64]) -> Option<Object> {
+    Some(Object::Boolean(numbers.windows(2).all(|w| w[0] == w[1])))
+}
+
+fn fold_less_than(numbers: &[i64]) -> Option<Object> {
+    Some(Object::Boolean(numbers.windows(2).all(|w| w[0] < w[1])))
+}
+
+/// Something the optimizer did to a program.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// A call of a builtin on constants, replaced by its value.
+    Folded { call: Object, value: Object },
+    /// A call replaced by the body of the function it calls: a top-level
+    /// function, or a lambda written in place, which has no name.
+    Inlined(Option<Symbol>),
+    /// An `if` whose test is a constant, replaced by the branch it takes.
+    Pruned { test: Object },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Folded { call, value } => write!(f, "folded {} to {}", call, value),
+            Change::Inlined(Some(name)) => write!(f, "inlined {}", name),
+            Change::Inlined(None) => write!(f, "inlined a lambda"),
+            Change::Pruned { test } => write!(f, "pruned an if whose test is always {}", test),
+        }
+    }
+}
+
+/// The changes `optimize` made, each with the source line it made it at.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub changes: Vec<(usize, Change)>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (line, change) in &self.changes {
+            writeln!(f, "line {}: {}", line, change)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rewrites a program, read with `reader::read_with_span_trees`, into one
+/// that computes the same values with less work, keeping the span trees in
+/// step so that compiled code still maps back to source lines:
+///
+/// - calls of `+`, `-`, `*`, `=` and `<` on integer constants are folded,
+///   where the name is provably the builtin: bound to it in `env`, not
+///   shadowed by a local variable and not defined or assigned anywhere in
+///   the program;
+/// - calls of small lambdas that only call and branch are replaced by their
+///   bodies, when each argument is a constant or a parameter that nothing
+///   assigns. This covers lambdas written in place, and functions defined
+///   once at top level, which are inlined in the forms after their
+///   definition unless they call themselves;
+/// - an `if` whose test is a constant is replaced by the branch it takes.
+///
+/// Forms with scopes of their own besides `lambda` (`guard`, `handler-bind`,
+/// `restart-case` and `shift`) are left as they are. The program is assumed
+/// to run in `env` and to be the only code that rebinds its globals.
+pub fn optimize(forms: &mut [(Object, SpanTree)], env: &EnvRef) -> Report {
+    let mut optimizer = Optimizer {
+        env,
+        bindings: SymbolMap::default(),
+        inlinable: SymbolMap::default(),
+        scopes: Vec::new(),
+        inlining: Vec::new(),
+        report: Report::default(),
+    };
+    for (exp, _) in forms.iter() {
+        optimizer.count_bindings(exp);
+    }
+    for (exp, spans) in forms.iter_mut() {
+        optimizer.optimize(exp, spans);
+        optimizer.register(exp);
+    }
+    optimizer.report
+}
+
+/// A top-level function whose calls can be replaced by its body.
+struct Inline {
+    parameters: Vec<Symbol>,
+    body: Object,
+    /// The globals the body refers to, which must not be shadowed where it
+    /// is inlined.
+    free: Vec<Symbol>,
+}
+
+/// The variables of a lambda: its parameters, which are bound on entry,
+/// then its internal definitions.
+struct Scope {
+    names: Vec<Symbol>,
+    parameters: usize,
+}
+
+struct Optimizer<'a> {
+    env: &'a EnvRef,
+    /// How many `define` and `set!` forms in the program bind each name.
+    bindings: SymbolMap<usize>,
+    inlinable: SymbolMap<Inline>,
+    scopes: Vec<Scope>,
+    /// The functions being inlined, so that functions calling each other
+    /// are not expanded forever.
+    inlining: Vec<Symbol>,
+    report: Report,
+}
+
+impl<'a> Optimizer<'a> {
+    fn count_bindings(&mut self, exp: &Object) {
+        let items = match exp {
+            Object::List(items) => items,
+            _ => return,
+        };
+        match (items.first(), items.get(1)) {
+            (Some(Object::Symbol(symbol::QUOTE)), _) => return,
+            (Some(Object::Symbol(symbol::DEFINE)), Some(Object::Symbol(name)))
+            | (Some(Object::Symbol(symbol::SET)), Some(Object::Symbol(name))) => {
+                *self.bindings.entry(*name).or_insert(0) += 1;
+            }
+            _ => {}
+        }
+        for item in items {
+            self.count_bindings(item);
+        }
+    }
+
+    /// Whether `name` is a local variable where the optimizer is, and if
+    /// so, whether it is a parameter.
+    fn local(&self, name: Symbol) -> Option<bool> {
+        self.scopes.iter().rev().find_map(|scope| {
+            let index = scope.names.iter().position(|n| *n == name)?;
+            Some(index < scope.parameters)
+        })
+    }
+
+    fn is_builtin(&self, name: Symbol, native: Native) -> bool {
+        self.local(name).is_none()
+            && !self.bindings.contains_key(&name)
+            && self.env.borrow().lookup(name) == Some(Object::Callable(Function::Native(native)))
+    }
+
+    /// Whether an argument can stand in for a parameter wherever the body
+    /// uses it, any number of times, without changing what it computes.
+    fn is_trivial(&self, arg: &Object) -> bool {
+        match arg {
+            Object::Symbol(name) => {
+                self.local(*name) == Some(true) && !self.bindings.contains_key(name)
+            }
+            other => constant(other).is_some(),
+        }
+    }
+
+    fn optimize(&mut self, exp: &mut Object, spans: &mut SpanTree) {
+        let items = match exp {
+            Object::List(items) if !items.is_empty() => items,
+            _ => return,
+        };
+        if spans.items.len() < items.len() {
+            spans.items.resize(items.len(), SpanTree::at(spans.span));
+        }
+
+        match items[0] {
+            Object::Symbol(symbol::QUOTE)
+            | Object::Symbol(symbol::GUARD)
+            | Object::Symbol(symbol::HANDLER_BIND)
+            | Object::Symbol(symbol::RESTART_CASE)
+            | Object::Symbol(symbol::SHIFT) => {}
+            Object::Symbol(symbol::DEFINE) | Object::Symbol(symbol::SET) => {
+                self.optimize_all(items, spans, 2)
+            }
+            Object::Symbol(symbol::LAMBDA) => self.optimize_lambda(items, spans),
+            Object::Symbol(symbol::IF) => {
+                self.optimize_all(items, spans, 1);
+                self.prune(exp, spans);
+            }
+            Object::Symbol(symbol::BEGIN)
+            | Object::Symbol(symbol::RESET)
+            | Object::Symbol(symbol::DELAY)
+            | Object::Symbol(symbol::DELAY_FORCE)
+            | Object::Symbol(symbol::STREAM_CONS) => self.optimize_all(items, spans, 1),
+            _ => {
+                self.optimize_all(items, spans, 0);
+                if !self.inline(exp, spans) {
+                    self.fold(exp, spans);
+                }
+            }
+        }
+    }
+
+    /// Optimizes the items of a list from `start` on.
+    fn optimize_all(&mut self, items: &mut [Object], spans: &mut SpanTree, start: usize) {
+        for (item, spans) in items.iter_mut().zip(&mut spans.items).skip(start) {
+            self.optimize(item, spans);
+        }
+    }
+
+    /// `(lambda params body...)`. Defaults are left as they are, and so are
+    /// malformed lambdas, for the evaluator to report.
+    fn optimize_lambda(&mut self, items: &mut [Object], spans: &mut SpanTree) {
+        let parameters = match items.get(1) {
+            Some(Object::List(list)) => match Parameters::parse(list) {
+                Ok(parameters) => parameters,
+                Err(_) => return,
+            },
+            _ => return,
+        };
+
+        self.scopes.push(Scope {
+            names: analysis::frame_layout(&parameters, &items[2..]),
+            parameters: parameters.required.len()
+                + parameters.optional.len()
+                + parameters.keys.len(),
+        });
+        self.optimize_all(items, spans, 2);
+        self.scopes.pop();
+    }
+
+    fn prune(&mut self, exp: &mut Object, spans: &mut SpanTree) {
+        let items = match exp {
+            Object::List(items) if items.len() == 3 || items.len() == 4 => items,
+            _ => return,
+        };
+        let test = match constant(&items[1]) {
+            Some(test) => test,
+            None => return,
+        };
+
+        let branch = if test.is_truthy() { 2 } else { 3 };
+        let change = Change::Pruned {
+            test: items[1].clone(),
+        };
+        if branch < items.len() {
+            let taken = mem::replace(&mut items[branch], Object::Nil);
+            *spans = mem::replace(&mut spans.items[branch], SpanTree::at(spans.span));
+            *exp = taken;
+        } else {
+            *exp = Object::Nil;
+            spans.items.clear();
+        }
+        self.report.changes.push((spans.span.line, change));
+    }
+
+    fn fold(&mut self, exp: &mut Object, spans: &mut SpanTree) {
+        let items = match exp {
+            Object::List(items) => items,
+            _ => return,
+        };
+        let name = match items[0] {
+            Object::Symbol(name) => name,
+            _ => return,
+        };
+        let (native, fold) = match FOLDABLE.iter().find(|(n, _, _)| *n == name.as_str()) {
+            Some((_, native, fold)) => (*native, *fold),
+            None => return,
+        };
+        let numbers: Option<Vec<i64>> = items[1..]
+            .iter()
+            .map(|arg| match arg {
+                Object::Integer(n) => Some(*n),
+                _ => None,
+            })
+            .collect();
+        let value = match numbers.and_then(|numbers| fold(&numbers)) {
+            Some(value) if self.is_builtin(name, native) => value,
+            _ => return,
+        };
+
+        let call = mem::replace(exp, value.clone());
+        spans.items.clear();
+        self.report
+            .changes
+            .push((spans.span.line, Change::Folded { call, value }));
+    }
+
+    /// Replaces a call by the body of the function it calls, and optimizes
+    /// the result. Returns whether it did.
+    fn inline(&mut self, exp: &mut Object, spans: &mut SpanTree) -> bool {
+        let items = match exp {
+            Object::List(items) => items,
+            _ => return false,
+        };
+        let args = &items[1..];
+        if !args.iter().all(|arg| self.is_trivial(arg)) {
+            return false;
+        }
+
+        let (name, body) = match &items[0] {
+            Object::Symbol(name) => {
+                let inline = match self.inlinable.get(name) {
+                    Some(inline) => inline,
+                    None => return false,
+                };
+                if self.local(*name).is_some()
+                    || self.inlining.contains(name)
+                    || inline.parameters.len() != args.len()
+                    || inline.free.iter().any(|free| self.local(*free).is_some())
+                {
+                    return false;
+                }
+                (
+                    Some(*name),
+                    substitute(&inline.body, &inline.parameters, args),
+                )
+            }
+            Object::List(lambda) => match inlinable_lambda(lambda) {
+                Some((parameters, body)) if parameters.len() == args.len() => {
+                    (None, substitute(body, &parameters, args))
+                }
+                _ => return false,
+            },
+            _ => return false,
+        };
+
+        *exp = body;
+        *spans = SpanTree::at(spans.span);
+        self.report
+            .changes
+            .push((spans.span.line, Change::Inlined(name)));
+        match name {
+            Some(name) => {
+                self.inlining.push(name);
+                self.optimize(exp, spans);
+                self.inlining.pop();
+            }
+            None => self.optimize(exp, spans),
+        }
+        true
+    }
+
+    /// Makes a top-level `(define name (lambda ...))` available for
+    /// inlining in the forms after it.
+    fn register(&mut self, exp: &Object) {
+        let (name, lambda) = match exp {
+            Object::List(items) if items.len() == 3 => match (&items[0], &items[1], &items[2]) {
+                (Object::Symbol(symbol::DEFINE), Object::Symbol(name), Object::List(lambda)) => {
+                    (*name, lambda)
+                }
+                _ => return,
+            },
+            _ => return,
+        };
+        let (parameters, body) = match inlinable_lambda(lambda) {
+            Some(lambda) => lambda,
+            None => return,
+        };
+        if self.bindings.get(&name) != Some(&1) || mentions(body, name) {
+            return;
+        }
+
+        let mut free = Vec::new();
+        free_variables(body, &parameters, &mut free);
+        let inline = Inline {
+            parameters,
+            body: body.clone(),
+            free,
+        };
+        self.inlinable.insert(name, inline);
+    }
+}
+
+/// The value of `exp` if it is a constant.
+fn constant(exp: &Object) -> Option<Object> {
+    match exp {
+        Object::Symbol(_) | Object::Local(_) | Object::Global(_) => None,
+        Object::List(items) => match &items[..] {
+            [Object::Symbol(symbol::QUOTE), quoted] => Some(quoted.clone()),
+            _ => None,
+        },
+        other => Some(other.clone()),
+    }
+}
+
+/// The parameters and body of `(lambda (params...) body)` if it is small
+/// enough to inline: distinct required parameters and a single expression
+/// that only calls, branches and quotes, so substituting into it cannot
+/// capture a variable.
+fn inlinable_lambda(lambda: &[Object]) -> Option<(Vec<Symbol>, &Object)> {
+    let (list, body) = match lambda {
+        [Object::Symbol(symbol::LAMBDA), Object::List(list), body] => (list, body),
+        _ => return None,
+    };
+    let parameters = Parameters::parse(list).ok()?;
+    let names = parameters.required;
+    let distinct = names
+        .iter()
+        .enumerate()
+        .all(|(i, n)| !names[..i].contains(n));
+    if !parameters.optional.is_empty()
+        || !parameters.keys.is_empty()
+        || !distinct
+        || size(body) > INLINE_SIZE
+        || !only_calls(body)
+    {
+        return None;
+    }
+    Some((names, body))
+}
+
+fn size(exp: &Object) -> usize {
+    match exp {
+        Object::List(items) => 1 + items.iter().map(size).sum::<usize>(),
+        _ => 1,
+    }
+}
+
+fn only_calls(exp: &Object) -> bool {
+    let items = match exp {
+        Object::List(items) => items,
+        _ => return true,
+    };
+    match items.first() {
+        None => false,
+        Some(Object::Symbol(symbol::QUOTE)) => true,
+        Some(Object::Symbol(symbol::IF)) => {
+            (items.len() == 3 || items.len() == 4) && items[1..].iter().all(only_calls)
+        }
+        Some(Object::Symbol(form)) if is_special_form(*form) => false,
+        Some(_) => items.iter().all(only_calls),
+    }
+}
+
+fn is_special_form(name: Symbol) -> bool {
+    matches!(
+        name,
+        symbol::QUOTE
+            | symbol::DEFINE
+            | symbol::SET
+            | symbol::LAMBDA
+            | symbol::IF
+            | symbol::BEGIN
+            | symbol::GUARD
+            | symbol::HANDLER_BIND
+            | symbol::RESTART_CASE
+            | symbol::RESET
+            | symbol::SHIFT
+            | symbol::DELAY
+            | symbol::DELAY_FORCE
+            | symbol::STREAM_CONS
+    )
+}
+
+/// Whether `exp`, a body that `only_calls`, refers to `name`.
+fn mentions(exp: &Object, name: Symbol) -> bool {
+    let mut free = Vec::new();
+    free_variables(exp, &[], &mut free);
+    free.contains(&name)
+}
+
+/// Collects the variables that `exp`, a body that `only_calls`, refers to
+/// besides `bound`.
+fn free_variables(exp: &Object, bound: &[Symbol], free: &mut Vec<Symbol>) {
+    match exp {
+        Object::Symbol(name) if !bound.contains(name) && !free.contains(name) => free.push(*name),
+        Object::List(items) => match items.first() {
+            Some(Object::Symbol(symbol::QUOTE)) => {}
+            Some(Object::Symbol(symbol::IF)) => {
+                for item in &items[1..] {
+                    free_variables(item, bound, free);
+                }
+            }
+            _ => {
+                for item in items {
+                    free_variables(item, bound, free);
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+/// Replaces the parameters in `body`, which `only_calls`, by the arguments.
+fn substitute(body: &Object, parameters: &[Symbol], args: &[Object]) -> Object {
+    match body {
+        Object::Symbol(name) => match parameters.iter().position(|p| p == name) {
+            Some(i) => args[i].clone(),
+            None => body.clone(),
+        },
+        Object::List(items) => match items.first() {
+            Some(Object::Symbol(symbol::QUOTE)) => body.clone(),
+            Some(Object::Symbol(symbol::IF)) => {
+                let mut substituted = vec![items[0].clone()];
+                substituted.extend(
+                    items[1..]
+                        .iter()
+                        .map(|item| substitute(item, parameters, args)),
+                );
+                Object::List(substituted)
+            }
+            _ => Object::List(
+                items
+                    .iter()
+                    .map(|item| substitute(item, parameters, args))
+                    .collect(),
+            ),
+        },
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Environment;
+    use crate::reader;
+    use crate::vm::ENGINES;
+
+    fn optimize_str(code: &str) -> (Vec<Object>, Report) {
+        let mut forms = reader::read_with_span_trees(code).unwrap();
+        let report = optimize(&mut forms, &Environment::new());
+        (forms.into_iter().map(|(exp, _)| exp).collect(), report)
+    }
+
+    fn read(code: &str) -> Vec<Object> {
+        reader::read(code).unwrap()
+    }
+
+    #[test]
+    fn test_folds_builtin_arithmetic() {
+        let (forms, report) = optimize_str("(list (+ 1 2 3) (- 10 (* 2 3)) (< 1 2))");
+        assert_eq!(forms, read("(list 6 4 #t)"));
+        assert_eq!(report.changes.len(), 4);
+        assert_eq!(
+            report.changes[0],
+            (
+                1,
+                Change::Folded {
+                    call: read("(+ 1 2 3)").remove(0),
+                    value: Object::Integer(6),
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_only_provable_builtins_are_folded() {
+        let programs = [
+            "(lambda (+) (+ 1 2))",
+            "(lambda () (define * list) (* 1 2))",
+            "(define f (lambda () (set! - +))) (- 1 2)",
+            "(+ 1 'a)",
+            "(- 1)",
+            "(* 9223372036854775807 2)",
+        ];
+        for program in programs.iter() {
+            let (forms, report) = optimize_str(program);
+            assert_eq!(forms, read(program), "{}", program);
+            assert!(report.changes.is_empty(), "{}", program);
+        }
+
+        let env = Environment::new();
+        env.borrow_mut()
+            .define("+".to_string(), Object::Nil)
+            .unwrap();
+        let mut forms = reader::read_with_span_trees("(+ 1 2)").unwrap();
+        assert!(optimize(&mut forms, &env).changes.is_empty());
+    }
+
+    #[test]
+    fn test_inlines_small_functions() {
+        let (forms, report) = optimize_str(
+            "(define sq (lambda (x) (* x x)))
+             (sq 5)
+             (define f (lambda (y) (list (sq y) (sq (g y)))))
+             ((lambda (a b) (if a b 0)) #f 1)",
+        );
+        assert_eq!(forms[1], Object::Integer(25));
+        assert_eq!(
+            forms[2],
+            read("(define f (lambda (y) (list (* y y) (sq (g y)))))").remove(0)
+        );
+        assert_eq!(forms[3], Object::Integer(0));
+        assert!(report
+            .changes
+            .contains(&(2, Change::Inlined(Some(Symbol::intern("sq"))))));
+        assert!(report.changes.contains(&(4, Change::Inlined(None))));
+    }
+
+    #[test]
+    fn test_functions_that_may_change_are_not_inlined() {
+        let programs = [
+            "(define fact (lambda (n) (if (< n 2) 1 (* n (fact (- n 1)))))) (fact 5)",
+            "(define sq (lambda (x) (* x x))) (set! sq car) (sq 5)",
+            "(define sq (lambda (x) (* x x))) (lambda (sq) (sq 5))",
+            "(define sq (lambda (x) (* x x))) (lambda (*) (sq 5))",
+            "(define sq (lambda (x) (* x x))) (lambda (x) (set! x 1) (sq x))",
+            "(sq 5) (define sq (lambda (x) (* x x)))",
+        ];
+        for program in programs.iter() {
+            let (_, report) = optimize_str(program);
+            assert!(
+                !report
+                    .changes
+                    .iter()
+                    .any(|(_, c)| matches!(c, Change::Inlined(_))),
+                "{}",
+                program
+            );
+        }
+    }
+
+    #[test]
+    fn test_functions_calling_each_other_terminate() {
+        let (_, report) = optimize_str(
+            "(define a (lambda (x) (b (+ x 1))))
+             (define b (lambda (x) (a (+ x 1))))
+             (a 1)",
+        );
+        assert!(report.changes.len() < 10);
+    }
+
+    #[test]
+    fn test_prunes_constant_branches() {
+        let (forms, report) = optimize_str("(if (< 2 1) 'a (if 'x 1 2))\n(if #f 1)");
+        assert_eq!(forms, vec![Object::Integer(1), Object::Nil]);
+        assert_eq!(
+            report.to_string(),
+            "line 1: folded (< 2 1) to #f\n\
+             line 1: pruned an if whose test is always (quote x)\n\
+             line 1: pruned an if whose test is always #f\n\
+             line 2: pruned an if whose test is always #f\n"
+        );
+    }
+
+    #[test]
+    fn test_optimized_programs_agree() {
+        let programs = [
+            "(define sq (lambda (x) (* x x)))
+             (define sum-sq (lambda (a b) (+ (sq a) (sq b))))
+             (list (sum-sq 3 4) (sq (+ 1 2)) (if (< (sq 2) 5) 'small 'big))",
+            "(define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))
+             (fib (+ 5 5))",
+            "(define abs (lambda (x) (if (< x 0) (- 0 x) x)))
+             (define f (lambda (y &optional (z (abs -3))) (list (abs y) z (abs (- y 10)))))
+             (list (f -4) (f 4 2))",
+            "(define + -)
+             (+ 10 3)",
+            "(define twice (lambda (f x) (f (f x))))
+             (twice (lambda (n) (* n 2)) 5)",
+            "(define counter 0)
+             (define bump (lambda () (set! counter (+ counter 1)) counter))
+             (define id (lambda (x) x))
+             (list (id (bump)) (id (bump)) counter)",
+            "(define f (lambda (x) (guard (e (#t (+ 1 2))) (raise x))))
+             (list (f 1) ((lambda (a) (if #t a)) 7))",
+            "(define a (lambda (x) (if (< x 0) x (b (- x 1)))))
+             (define b (lambda (x) (a (- x 1))))
+             (a 10)",
+        ];
+
+        for program in programs.iter() {
+            let forms = reader::read_with_span_trees(program).unwrap();
+            for (engine, eval) in ENGINES.iter() {
+                let plain = Environment::new();
+                let mut expected = Ok(Object::Nil);
+                for (exp, _) in forms.clone() {
+                    expected = eval(exp, plain.clone());
+                }
+
+                let env = Environment::new();
+                let mut optimized = forms.clone();
+                optimize(&mut optimized, &env);
+                let mut result = Ok(Object::Nil);
+                for (exp, _) in optimized {
+                    result = eval(exp, env.clone());
+                }
+                assert_eq!(result, expected, "{} ({})", program, engine);
+            }
+        }
+    }
+}
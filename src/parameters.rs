@@ -0,0 +1,340 @@
+use crate::error::ErrorKind;
+use crate::object::{Arity, Object};
+use crate::symbol::Symbol;
+
+/// A lambda list: required parameters, then optionally `&optional` and
+/// `&key` sections (or `#:optional` and `#:key`), whose parameters are
+/// either a name or a `(name default)` list. A missing default is nil.
+///
+/// ```text
+/// (lambda (path &optional (mode 'read) &key (verbose #f) encoding) ...)
+/// ```
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Parameters {
+    pub required: Vec<Symbol>,
+    pub optional: Vec<(Symbol, Object)>,
+    pub keys: Vec<(Symbol, Object)>,
+}
+
+/// How a parameter gets its value in a call: from an argument, or by
+/// evaluating its default, numbered across the optional and then the key
+/// parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Argument {
+    Given(Object),
+    Default(usize),
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Section {
+    Required,
+    Optional,
+    Key,
+}
+
+fn section_marker(obj: &Object) -> Option<Section> {
+    match obj {
+        Object::Symbol(symbol) if !symbol.is_gensym() => match symbol.as_str() {
+            "&optional" | "#:optional" => Some(Section::Optional),
+            "&key" | "#:key" => Some(Section::Key),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl Parameters {
+    pub fn parse(list: &[Object]) -> Result<Parameters, Object> {
+        let mut parameters = Parameters::default();
+        let mut section = Section::Required;
+
+        for p in list {
+            if let Some(marker) = section_marker(p) {
+                if marker <= section {
+                    return Err(Object::new_error_with(
+                        ErrorKind::Syntax,
+                        "lambda: misplaced marker in parameter list",
+                        vec![p.clone()],
+                    ));
+                }
+                section = marker;
+                continue;
+            }
+
+            let (name, default) = match (section, p) {
+                (_, Object::Symbol(name)) => (*name, Object::Nil),
+                (Section::Optional | Section::Key, Object::List(items)) => match &items[..] {
+                    [Object::Symbol(name), default] => (*name, default.clone()),
+                    _ => {
+                        return Err(Object::new_error_with(
+                            ErrorKind::Syntax,
+                            "lambda: a parameter with a default must be (name default)",
+                            vec![p.clone()],
+                        ))
+                    }
+                },
+                _ => return Err(Object::type_error("lambda: parameter is not a symbol", p)),
+            };
+
+            match section {
+                Section::Required => parameters.required.push(name),
+                Section::Optional => parameters.optional.push((name, default)),
+                Section::Key => parameters.keys.push((name, default)),
+            }
+        }
+
+        Ok(parameters)
+    }
+
+    /// Whether every parameter is required, so arguments bind in order.
+    pub fn is_simple(&self) -> bool {
+        self.optional.is_empty() && self.keys.is_empty()
+    }
+
+    /// The number of positional arguments accepted, ignoring keywords.
+    pub fn arity(&self) -> Arity {
+        let min = self.required.len();
+        if !self.keys.is_empty() {
+            Arity::AtLeast(min)
+        } else if !self.optional.is_empty() {
+            Arity::Range(min, min + self.optional.len())
+        } else {
+            Arity::Exact(min)
+        }
+    }
+
+    pub fn arity_error(&self, name: &str, got: usize) -> Object {
+        self.arity().error(name, got)
+    }
+
+    /// Matches `args` against the parameters, in order. Keyword arguments
+    /// start at the first keyword after the required arguments, so optional
+    /// parameters cannot take keywords as values when there are `&key`
+    /// parameters, or after the last optional one, so that a value too many
+    /// is reported as not being a keyword.
+    pub fn bind(&self, name: &str, args: Vec<Object>) -> Result<Vec<(Symbol, Argument)>, Object> {
+        let max_positional = self.required.len() + self.optional.len();
+        let positional = if self.keys.is_empty() {
+            args.len()
+        } else {
+            args.iter()
+                .skip(self.required.len())
+                .position(|arg| matches!(arg, Object::Keyword(_)))
+                .map_or(args.len(), |i| i + self.required.len())
+                .min(max_positional)
+        };
+        if positional < self.required.len() || positional > max_positional {
+            return Err(self.arity_error(name, args.len()));
+        }
+
+        let mut args = args.into_iter();
+        let mut bindings = Vec::with_capacity(max_positional + self.keys.len());
+        for &p in &self.required {
+            bindings.push((p, Argument::Given(args.next().unwrap())));
+        }
+        for (i, (p, _)) in self.optional.iter().enumerate() {
+            let argument = if self.required.len() + i < positional {
+                Argument::Given(args.next().unwrap())
+            } else {
+                Argument::Default(i)
+            };
+            bindings.push((*p, argument));
+        }
+
+        let mut given = vec![None; self.keys.len()];
+        while let Some(arg) = args.next() {
+            let keyword = match arg {
+                Object::Keyword(keyword) => keyword,
+                other => {
+                    return Err(Object::new_error_with(
+                        ErrorKind::Arity,
+                        &format!("{}: expected a keyword argument", name),
+                        vec![other],
+                    ))
+                }
+            };
+            let index = match self.keys.iter().position(|(p, _)| *p == keyword) {
+                Some(index) => index,
+                None => {
+                    return Err(Object::new_error_with(
+                        ErrorKind::Arity,
+                        &format!("{}: unexpected keyword argument", name),
+                        vec![Object::Keyword(keyword)],
+                    ))
+                }
+            };
+            let value = args.next().ok_or_else(|| {
+                Object::new_error_with(
+                    ErrorKind::Arity,
+                    &format!("{}: missing value for keyword argument", name),
+                    vec![Object::Keyword(keyword)],
+                )
+            })?;
+            // As in Common Lisp, the leftmost occurrence of a keyword wins.
+            given[index].get_or_insert(value);
+        }
+
+        for (i, ((p, _), value)) in self.keys.iter().zip(given).enumerate() {
+            let argument = match value {
+                Some(value) => Argument::Given(value),
+                None => Argument::Default(self.optional.len() + i),
+            };
+            bindings.push((*p, argument));
+        }
+        Ok(bindings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader;
+    use crate::vm::test_util::assert_eval;
+
+    fn parse(code: &str) -> Result<Parameters, Object> {
+        match reader::read(code).unwrap().remove(0) {
+            Object::List(list) => Parameters::parse(&list),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let parameters = parse("(a &optional (b 2) c #:key (d (+ 1 2)))").unwrap();
+        assert_eq!(parameters.required, vec![Symbol::intern("a")]);
+        assert_eq!(
+            parameters.optional,
+            vec![
+                (Symbol::intern("b"), Object::Integer(2)),
+                (Symbol::intern("c"), Object::Nil)
+            ]
+        );
+        assert_eq!(parameters.keys[0].0, Symbol::intern("d"));
+        assert_eq!(parameters.arity(), Arity::AtLeast(1));
+
+        assert!(parse("(a &key b &optional c)").is_err());
+        assert!(parse("((a 1))").is_err());
+        assert!(parse("(&optional (a))").is_err());
+        assert!(parse("(1)").is_err());
+    }
+
+    #[test]
+    fn test_optional_arguments() {
+        assert_eval!(
+            "(define f (lambda (a &optional (b (+ a 1)) c) (list a b c)))
+            (list (f 1) (f 1 5) (f 1 5 6))",
+            Ok(Object::List(vec![
+                Object::List(vec![Object::Integer(1), Object::Integer(2), Object::Nil]),
+                Object::List(vec![Object::Integer(1), Object::Integer(5), Object::Nil]),
+                Object::List(vec![
+                    Object::Integer(1),
+                    Object::Integer(5),
+                    Object::Integer(6)
+                ]),
+            ]))
+        );
+        assert_eval!(
+            "(define f (lambda (a &optional b) a))
+            (f 1 2 3)",
+            Err(Object::new_error(
+                ErrorKind::Arity,
+                "f: wrong number of arguments (expected 1 to 2, got 3)"
+            )
+            .with_frame("f"))
+        );
+    }
+
+    #[test]
+    fn test_keyword_arguments() {
+        assert_eval!(
+            "(define open (lambda (path &key (mode :read) verbose) (list path mode verbose)))
+            (list (open \"a\") (open \"a\" :verbose #t) (open \"a\" :verbose 1 :mode :write :verbose 2))",
+            Ok(Object::List(vec![
+                Object::List(vec![
+                    Object::String(String::from("a")),
+                    Object::keyword("read"),
+                    Object::Nil
+                ]),
+                Object::List(vec![
+                    Object::String(String::from("a")),
+                    Object::keyword("read"),
+                    Object::Boolean(true)
+                ]),
+                Object::List(vec![
+                    Object::String(String::from("a")),
+                    Object::keyword("write"),
+                    Object::Integer(1)
+                ]),
+            ]))
+        );
+        assert_eval!(
+            "(define f (lambda (a &optional (b 10) &key (c (* b 2))) (list a b c)))
+            (list (f 1 :c 3) (f 1 2))",
+            Ok(Object::List(vec![
+                Object::List(vec![
+                    Object::Integer(1),
+                    Object::Integer(10),
+                    Object::Integer(3)
+                ]),
+                Object::List(vec![
+                    Object::Integer(1),
+                    Object::Integer(2),
+                    Object::Integer(4)
+                ]),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_keyword_argument_errors() {
+        assert_eval!(
+            "(define f (lambda (&key verbose) verbose))
+            (f :verbsoe #t)",
+            Err(Object::new_error_with(
+                ErrorKind::Arity,
+                "f: unexpected keyword argument",
+                vec![Object::keyword("verbsoe")]
+            )
+            .with_frame("f"))
+        );
+        assert_eval!(
+            "(define f (lambda (&key verbose) verbose))
+            (f :verbose)",
+            Err(Object::new_error_with(
+                ErrorKind::Arity,
+                "f: missing value for keyword argument",
+                vec![Object::keyword("verbose")]
+            )
+            .with_frame("f"))
+        );
+        assert_eval!(
+            "(define f (lambda (&key verbose) verbose))
+            (f :verbose 1 2)",
+            Err(Object::new_error_with(
+                ErrorKind::Arity,
+                "f: expected a keyword argument",
+                vec![Object::Integer(2)]
+            )
+            .with_frame("f"))
+        );
+        assert_eval!(
+            "(define f (lambda (&key a) a))
+            (f 1 2)",
+            Err(Object::new_error_with(
+                ErrorKind::Arity,
+                "f: expected a keyword argument",
+                vec![Object::Integer(1)]
+            )
+            .with_frame("f"))
+        );
+        assert_eval!(
+            "(define f (lambda (a &key verbose) verbose))
+            (f)",
+            Err(Object::new_error(
+                ErrorKind::Arity,
+                "f: wrong number of arguments (expected at least 1, got 0)"
+            )
+            .with_frame("f"))
+        );
+    }
+}
@@ -0,0 +1,854 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::rc::Rc;
+
+use crate::error::ErrorKind;
+use crate::object::{Arity, EnvRef, Object};
+use crate::symbol::Symbol;
+
+const BITS: u32 = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+enum VectorNode {
+    Branch(Vec<Rc<VectorNode>>),
+    Leaf(Vec<Object>),
+}
+
+impl VectorNode {
+    fn children(&self) -> &[Rc<VectorNode>] {
+        match self {
+            VectorNode::Branch(children) => children,
+            VectorNode::Leaf(_) => unreachable!("leaf in branch position"),
+        }
+    }
+
+    fn items(&self) -> &[Object] {
+        match self {
+            VectorNode::Leaf(items) => items,
+            VectorNode::Branch(_) => unreachable!("branch in leaf position"),
+        }
+    }
+}
+
+/// An immutable vector that shares structure between versions: a trie of
+/// 32-way nodes, plus a tail of up to 32 items so that `conj` usually only
+/// copies the tail.
+#[derive(Clone)]
+pub struct PersistentVector {
+    len: usize,
+    shift: u32,
+    root: Rc<VectorNode>,
+    tail: Rc<Vec<Object>>,
+}
+
+impl Default for PersistentVector {
+    fn default() -> PersistentVector {
+        PersistentVector {
+            len: 0,
+            shift: BITS,
+            root: Rc::new(VectorNode::Branch(Vec::new())),
+            tail: Rc::new(Vec::new()),
+        }
+    }
+}
+
+impl PersistentVector {
+    pub fn new() -> PersistentVector {
+        PersistentVector::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The index of the first item in the tail.
+    fn tail_offset(&self) -> usize {
+        if self.len < WIDTH {
+            0
+        } else {
+            ((self.len - 1) >> BITS) << BITS
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Object> {
+        if index >= self.len {
+            return None;
+        }
+        if index >= self.tail_offset() {
+            return self.tail.get(index - self.tail_offset());
+        }
+
+        let mut node = &self.root;
+        let mut level = self.shift;
+        while level > 0 {
+            node = &node.children()[(index >> level) & MASK];
+            level -= BITS;
+        }
+        node.items().get(index & MASK)
+    }
+
+    pub fn push(&self, value: Object) -> PersistentVector {
+        if self.len - self.tail_offset() < WIDTH {
+            let mut tail = (*self.tail).clone();
+            tail.push(value);
+            return PersistentVector {
+                len: self.len + 1,
+                shift: self.shift,
+                root: self.root.clone(),
+                tail: Rc::new(tail),
+            };
+        }
+
+        // The tail is full, so it moves into the trie, which grows a level
+        // when its root is full too.
+        let leaf = Rc::new(VectorNode::Leaf((*self.tail).clone()));
+        let (root, shift) = if (self.len >> BITS) > (1 << self.shift) {
+            let path = new_path(self.shift, leaf);
+            let root = VectorNode::Branch(vec![self.root.clone(), path]);
+            (Rc::new(root), self.shift + BITS)
+        } else {
+            (self.push_leaf(self.shift, &self.root, leaf), self.shift)
+        };
+
+        PersistentVector {
+            len: self.len + 1,
+            shift,
+            root,
+            tail: Rc::new(vec![value]),
+        }
+    }
+
+    fn push_leaf(&self, level: u32, parent: &VectorNode, leaf: Rc<VectorNode>) -> Rc<VectorNode> {
+        let index = ((self.len - 1) >> level) & MASK;
+        let mut children = parent.children().to_vec();
+        let child = if level == BITS {
+            leaf
+        } else if let Some(child) = children.get(index) {
+            self.push_leaf(level - BITS, child, leaf)
+        } else {
+            new_path(level - BITS, leaf)
+        };
+
+        if index < children.len() {
+            children[index] = child;
+        } else {
+            children.push(child);
+        }
+        Rc::new(VectorNode::Branch(children))
+    }
+
+    /// Replaces the item at `index`, which must be in bounds.
+    pub fn set(&self, index: usize, value: Object) -> PersistentVector {
+        assert!(index < self.len, "index out of bounds");
+
+        let mut result = self.clone();
+        if index >= self.tail_offset() {
+            let mut tail = (*self.tail).clone();
+            tail[index - self.tail_offset()] = value;
+            result.tail = Rc::new(tail);
+        } else {
+            result.root = set_in(&self.root, self.shift, index, value);
+        }
+        result
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Object> + '_ {
+        (0..self.len).map(move |i| self.get(i).unwrap())
+    }
+}
+
+fn new_path(level: u32, node: Rc<VectorNode>) -> Rc<VectorNode> {
+    if level == 0 {
+        node
+    } else {
+        Rc::new(VectorNode::Branch(vec![new_path(level - BITS, node)]))
+    }
+}
+
+fn set_in(node: &VectorNode, level: u32, index: usize, value: Object) -> Rc<VectorNode> {
+    if level == 0 {
+        let mut items = node.items().to_vec();
+        items[index & MASK] = value;
+        return Rc::new(VectorNode::Leaf(items));
+    }
+
+    let mut children = node.children().to_vec();
+    let i = (index >> level) & MASK;
+    children[i] = set_in(&children[i], level - BITS, index, value);
+    Rc::new(VectorNode::Branch(children))
+}
+
+impl FromIterator<Object> for PersistentVector {
+    fn from_iter<I: IntoIterator<Item = Object>>(items: I) -> PersistentVector {
+        items
+            .into_iter()
+            .fold(PersistentVector::new(), |v, item| v.push(item))
+    }
+}
+
+impl PartialEq for PersistentVector {
+    fn eq(&self, other: &PersistentVector) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl Hash for PersistentVector {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+        for item in self.iter() {
+            item.hash(state);
+        }
+    }
+}
+
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The index into a map node of the hash at the given depth.
+fn fragment(hash: u64, shift: u32) -> u32 {
+    ((hash >> shift) & MASK as u64) as u32
+}
+
+#[derive(Clone)]
+enum MapEntry {
+    Pair(Object, Object),
+    Node(Rc<MapNode>),
+}
+
+enum MapNode {
+    /// Holds an entry for each bit set in `bitmap`, in bit order.
+    Branch { bitmap: u32, entries: Vec<MapEntry> },
+    /// Keys whose hashes are equal in all 64 bits.
+    Collision {
+        hash: u64,
+        pairs: Vec<(Object, Object)>,
+    },
+}
+
+impl MapNode {
+    fn get(&self, hash: u64, shift: u32, key: &Object) -> Option<&Object> {
+        match self {
+            MapNode::Branch { bitmap, entries } => {
+                let bit = 1 << fragment(hash, shift);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+                match &entries[(bitmap & (bit - 1)).count_ones() as usize] {
+                    MapEntry::Pair(k, v) if k == key => Some(v),
+                    MapEntry::Pair(_, _) => None,
+                    MapEntry::Node(node) => node.get(hash, shift + BITS, key),
+                }
+            }
+            MapNode::Collision { pairs, .. } => {
+                pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+            }
+        }
+    }
+
+    /// Returns the new node and whether the key was not there before.
+    fn insert(
+        self: &Rc<Self>,
+        hash: u64,
+        shift: u32,
+        key: Object,
+        value: Object,
+    ) -> (Rc<MapNode>, bool) {
+        match &**self {
+            MapNode::Branch { bitmap, entries } => {
+                let bit = 1 << fragment(hash, shift);
+                let index = (bitmap & (bit - 1)).count_ones() as usize;
+                let mut entries = entries.clone();
+                if bitmap & bit == 0 {
+                    entries.insert(index, MapEntry::Pair(key, value));
+                    let node = MapNode::Branch {
+                        bitmap: bitmap | bit,
+                        entries,
+                    };
+                    return (Rc::new(node), true);
+                }
+
+                let added = match entries[index].clone() {
+                    MapEntry::Pair(k, _) if k == key => {
+                        entries[index] = MapEntry::Pair(key, value);
+                        false
+                    }
+                    MapEntry::Pair(k, v) => {
+                        let old = (hash_of(&k), k, v);
+                        let node = pair_node(shift + BITS, old, (hash, key, value));
+                        entries[index] = MapEntry::Node(node);
+                        true
+                    }
+                    MapEntry::Node(node) => {
+                        let (node, added) = node.insert(hash, shift + BITS, key, value);
+                        entries[index] = MapEntry::Node(node);
+                        added
+                    }
+                };
+                let node = MapNode::Branch {
+                    bitmap: *bitmap,
+                    entries,
+                };
+                (Rc::new(node), added)
+            }
+            MapNode::Collision {
+                hash: shared,
+                pairs,
+            } if *shared == hash => {
+                let mut pairs = pairs.clone();
+                let added = match pairs.iter().position(|(k, _)| *k == key) {
+                    Some(i) => {
+                        pairs[i] = (key, value);
+                        false
+                    }
+                    None => {
+                        pairs.push((key, value));
+                        true
+                    }
+                };
+                let node = MapNode::Collision {
+                    hash: *shared,
+                    pairs,
+                };
+                (Rc::new(node), added)
+            }
+            MapNode::Collision { hash: shared, .. } => {
+                // The new key only shares a prefix of the hash, so this node
+                // moves down under a branch that can tell them apart.
+                let branch = Rc::new(MapNode::Branch {
+                    bitmap: 1 << fragment(*shared, shift),
+                    entries: vec![MapEntry::Node(self.clone())],
+                });
+                branch.insert(hash, shift, key, value)
+            }
+        }
+    }
+
+    /// Returns `None` if the key is not there, or else the node without it,
+    /// which is `None` itself once empty.
+    fn remove(&self, hash: u64, shift: u32, key: &Object) -> Option<Option<Rc<MapNode>>> {
+        match self {
+            MapNode::Branch { bitmap, entries } => {
+                let bit = 1 << fragment(hash, shift);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+                let index = (bitmap & (bit - 1)).count_ones() as usize;
+                let replacement = match &entries[index] {
+                    MapEntry::Pair(k, _) if k == key => None,
+                    MapEntry::Pair(_, _) => return None,
+                    MapEntry::Node(node) => node.remove(hash, shift + BITS, key)?.map(collapse),
+                };
+
+                let mut entries = entries.clone();
+                let bitmap = match replacement {
+                    Some(entry) => {
+                        entries[index] = entry;
+                        *bitmap
+                    }
+                    None => {
+                        entries.remove(index);
+                        bitmap & !bit
+                    }
+                };
+                if entries.is_empty() {
+                    return Some(None);
+                }
+                Some(Some(Rc::new(MapNode::Branch { bitmap, entries })))
+            }
+            MapNode::Collision {
+                hash: shared,
+                pairs,
+            } => {
+                let index = pairs.iter().position(|(k, _)| k == key)?;
+                let mut pairs = pairs.clone();
+                pairs.remove(index);
+                let node = MapNode::Collision {
+                    hash: *shared,
+                    pairs,
+                };
+                Some(Some(Rc::new(node)))
+            }
+        }
+    }
+
+    fn collect_pairs<'a>(&'a self, pairs: &mut Vec<(&'a Object, &'a Object)>) {
+        match self {
+            MapNode::Branch { entries, .. } => {
+                for entry in entries {
+                    match entry {
+                        MapEntry::Pair(k, v) => pairs.push((k, v)),
+                        MapEntry::Node(node) => node.collect_pairs(pairs),
+                    }
+                }
+            }
+            MapNode::Collision { pairs: own, .. } => {
+                pairs.extend(own.iter().map(|(k, v)| (k, v)));
+            }
+        }
+    }
+}
+
+/// Builds the smallest node holding two pairs with different keys.
+fn pair_node(shift: u32, a: (u64, Object, Object), b: (u64, Object, Object)) -> Rc<MapNode> {
+    if a.0 == b.0 {
+        return Rc::new(MapNode::Collision {
+            hash: a.0,
+            pairs: vec![(a.1, a.2), (b.1, b.2)],
+        });
+    }
+
+    let (fa, fb) = (fragment(a.0, shift), fragment(b.0, shift));
+    let node = if fa == fb {
+        MapNode::Branch {
+            bitmap: 1 << fa,
+            entries: vec![MapEntry::Node(pair_node(shift + BITS, a, b))],
+        }
+    } else {
+        let (first, second) = if fa < fb { (a, b) } else { (b, a) };
+        MapNode::Branch {
+            bitmap: (1 << fa) | (1 << fb),
+            entries: vec![
+                MapEntry::Pair(first.1, first.2),
+                MapEntry::Pair(second.1, second.2),
+            ],
+        }
+    };
+    Rc::new(node)
+}
+
+/// Inlines a node left holding a single pair into its parent.
+fn collapse(node: Rc<MapNode>) -> MapEntry {
+    match &*node {
+        MapNode::Branch { entries, .. } if entries.len() == 1 => match &entries[0] {
+            pair @ MapEntry::Pair(_, _) => pair.clone(),
+            MapEntry::Node(_) => MapEntry::Node(node),
+        },
+        MapNode::Collision { pairs, .. } if pairs.len() == 1 => {
+            MapEntry::Pair(pairs[0].0.clone(), pairs[0].1.clone())
+        }
+        _ => MapEntry::Node(node),
+    }
+}
+
+/// An immutable hash map that shares structure between versions: a hash
+/// array mapped trie, branching on 5 bits of the key's hash per level.
+/// Keys must be hashable, see `Object::is_hashable`.
+#[derive(Clone, Default)]
+pub struct PersistentMap {
+    len: usize,
+    root: Option<Rc<MapNode>>,
+}
+
+impl PersistentMap {
+    pub fn new() -> PersistentMap {
+        PersistentMap::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &Object) -> Option<&Object> {
+        self.root.as_ref()?.get(hash_of(key), 0, key)
+    }
+
+    pub fn insert(&self, key: Object, value: Object) -> PersistentMap {
+        let hash = hash_of(&key);
+        let (root, added) = match &self.root {
+            Some(root) => root.insert(hash, 0, key, value),
+            None => {
+                let root = MapNode::Branch {
+                    bitmap: 1 << fragment(hash, 0),
+                    entries: vec![MapEntry::Pair(key, value)],
+                };
+                (Rc::new(root), true)
+            }
+        };
+
+        PersistentMap {
+            len: self.len + added as usize,
+            root: Some(root),
+        }
+    }
+
+    pub fn remove(&self, key: &Object) -> PersistentMap {
+        let removed = match &self.root {
+            Some(root) => root.remove(hash_of(key), 0, key),
+            None => None,
+        };
+
+        match removed {
+            Some(root) => PersistentMap {
+                len: self.len - 1,
+                root,
+            },
+            None => self.clone(),
+        }
+    }
+
+    /// The entries, in an order that depends only on the keys' hashes.
+    pub fn pairs(&self) -> Vec<(&Object, &Object)> {
+        let mut pairs = Vec::with_capacity(self.len);
+        if let Some(root) = &self.root {
+            root.collect_pairs(&mut pairs);
+        }
+        pairs
+    }
+}
+
+impl PartialEq for PersistentMap {
+    fn eq(&self, other: &PersistentMap) -> bool {
+        self.len == other.len
+            && self
+                .pairs()
+                .into_iter()
+                .all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl Hash for PersistentMap {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Order-independent, as equal maps may be shaped differently.
+        let sum = self
+            .pairs()
+            .into_iter()
+            .fold(0u64, |sum, pair| sum.wrapping_add(hash_of(&pair)));
+        self.len.hash(state);
+        sum.hash(state);
+    }
+}
+
+fn key_arg(name: &str, key: &Object) -> Result<Object, Object> {
+    if !key.is_hashable() {
+        return Err(Object::type_error(
+            &format!("{}: a {} cannot be used as a key", name, key.type_name()),
+            key,
+        ));
+    }
+    Ok(key.clone())
+}
+
+/// Reads an index into `v`, which may be one past the end when `inclusive`
+/// is set, to append.
+fn index_arg(
+    name: &str,
+    arg: &Object,
+    v: &PersistentVector,
+    inclusive: bool,
+) -> Result<usize, Object> {
+    let index = match arg {
+        Object::Integer(index) => *index,
+        other => {
+            return Err(Object::type_error(
+                &format!("{}: index is not an integer", name),
+                other,
+            ))
+        }
+    };
+
+    let limit = if inclusive { v.len() + 1 } else { v.len() };
+    if index < 0 || index as usize >= limit {
+        return Err(Object::new_error_with(
+            ErrorKind::Runtime,
+            &format!("{}: index out of range", name),
+            vec![arg.clone()],
+        ));
+    }
+    Ok(index as usize)
+}
+
+fn not_a_collection(name: &str, arg: &Object) -> Object {
+    Object::type_error(
+        &format!("{}: argument is not a persistent map or vector", name),
+        arg,
+    )
+}
+
+/// `(assoc coll key value...)` returns `coll` with each key set to its
+/// value. Vectors take indexes as keys, up to one past the end to append.
+pub fn assoc(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::AtLeast(3).check("assoc", args)?;
+    if args.len().is_multiple_of(2) {
+        return Err(Object::new_error(
+            ErrorKind::Arity,
+            "assoc: missing value for the last key",
+        ));
+    }
+
+    let mut coll = args[0].clone();
+    for pair in args[1..].chunks(2) {
+        coll = match coll {
+            Object::PersistentMap(map) => {
+                let key = key_arg("assoc", &pair[0])?;
+                Object::PersistentMap(map.insert(key, pair[1].clone()))
+            }
+            Object::PersistentVector(v) => {
+                let index = index_arg("assoc", &pair[0], &v, true)?;
+                if index == v.len() {
+                    Object::PersistentVector(v.push(pair[1].clone()))
+                } else {
+                    Object::PersistentVector(v.set(index, pair[1].clone()))
+                }
+            }
+            other => return Err(not_a_collection("assoc", &other)),
+        };
+    }
+    Ok(coll)
+}
+
+/// `(dissoc map key...)`
+pub fn dissoc(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::AtLeast(1).check("dissoc", args)?;
+
+    let mut map = match &args[0] {
+        Object::PersistentMap(map) => map.clone(),
+        other => {
+            return Err(Object::type_error(
+                "dissoc: argument is not a persistent map",
+                other,
+            ))
+        }
+    };
+    for key in &args[1..] {
+        map = map.remove(key);
+    }
+    Ok(Object::PersistentMap(map))
+}
+
+/// `(conj coll item...)` appends to a vector, or adds `[key value]` entries
+/// to a map.
+pub fn conj(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::AtLeast(1).check("conj", args)?;
+
+    match &args[0] {
+        Object::PersistentVector(v) => {
+            let v = args[1..]
+                .iter()
+                .fold(v.clone(), |v, item| v.push(item.clone()));
+            Ok(Object::PersistentVector(v))
+        }
+        Object::PersistentMap(map) => {
+            let mut map = map.clone();
+            for entry in &args[1..] {
+                let (key, value) = match entry {
+                    Object::PersistentVector(pair) if pair.len() == 2 => {
+                        (pair.get(0).unwrap(), pair.get(1).unwrap())
+                    }
+                    Object::List(pair) if pair.len() == 2 => (&pair[0], &pair[1]),
+                    other => {
+                        return Err(Object::type_error(
+                            "conj: map entry is not a [key value] pair",
+                            other,
+                        ))
+                    }
+                };
+                map = map.insert(key_arg("conj", key)?, value.clone());
+            }
+            Ok(Object::PersistentMap(map))
+        }
+        other => Err(not_a_collection("conj", other)),
+    }
+}
+
+/// `(get coll key [default])` looks up a key in a map or an index in a
+/// vector, returning `default`, or nil, when it is missing.
+pub fn get(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Range(2, 3).check("get", args)?;
+
+    let found = match &args[0] {
+        Object::PersistentMap(map) => map.get(&args[1]).cloned(),
+        Object::PersistentVector(v) => match args[1] {
+            Object::Integer(index) if index >= 0 => v.get(index as usize).cloned(),
+            _ => None,
+        },
+        other => return Err(not_a_collection("get", other)),
+    };
+    Ok(found.unwrap_or_else(|| args.get(2).cloned().unwrap_or(Object::Nil)))
+}
+
+/// Applies a keyword as a function: `(:k m [default])` is `(get m :k default)`
+/// for a persistent map, and looks the keyword up in a hash table as well.
+pub fn apply_keyword(keyword: Symbol, args: &[Object]) -> Result<Object, Object> {
+    let key = Object::Keyword(keyword);
+    let found = match args {
+        [Object::PersistentMap(map), rest @ ..] if rest.len() <= 1 => map.get(&key).cloned(),
+        [Object::HashTable(table), rest @ ..] if rest.len() <= 1 => {
+            table.borrow().get(&key).cloned()
+        }
+        [other] | [other, _] => {
+            return Err(Object::type_error(
+                &format!(":{}: argument is not a map", keyword),
+                other,
+            ))
+        }
+        _ => return Err(Arity::Range(1, 2).error(&format!(":{}", keyword), args.len())),
+    };
+    Ok(found.unwrap_or_else(|| args.get(1).cloned().unwrap_or(Object::Nil)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader;
+    use crate::vm::test_util::assert_eval;
+
+    fn read_one(code: &str) -> Object {
+        reader::read(code).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_vector_push_get_and_set() {
+        let mut versions = vec![PersistentVector::new()];
+        for i in 0..2000 {
+            let next = versions.last().unwrap().push(Object::Integer(i));
+            versions.push(next);
+        }
+
+        let v = versions.last().unwrap();
+        assert_eq!(v.len(), 2000);
+        assert!(v
+            .iter()
+            .eq((0..2000).map(Object::Integer).collect::<Vec<_>>().iter()));
+        assert_eq!(versions[33].len(), 33);
+        assert_eq!(versions[33].get(32), Some(&Object::Integer(32)));
+        assert_eq!(versions[33].get(33), None);
+
+        let changed = v.set(1000, Object::Nil).set(1999, Object::Nil);
+        assert_eq!(changed.get(1000), Some(&Object::Nil));
+        assert_eq!(changed.get(1999), Some(&Object::Nil));
+        assert_eq!(v.get(1000), Some(&Object::Integer(1000)));
+        assert!(&changed != v);
+    }
+
+    #[test]
+    fn test_map_insert_get_and_remove() {
+        let mut map = PersistentMap::new();
+        for i in 0..1000 {
+            map = map.insert(Object::Integer(i), Object::Integer(i * i));
+        }
+        let updated = map.insert(Object::Integer(10), Object::Nil);
+
+        assert_eq!(map.len(), 1000);
+        assert_eq!(updated.len(), 1000);
+        assert_eq!(map.get(&Object::Integer(10)), Some(&Object::Integer(100)));
+        assert_eq!(updated.get(&Object::Integer(10)), Some(&Object::Nil));
+        assert_eq!(map.get(&Object::Integer(1000)), None);
+
+        let mut smaller = map.clone();
+        for i in (0..1000).step_by(2) {
+            smaller = smaller.remove(&Object::Integer(i));
+        }
+        assert_eq!(smaller.len(), 500);
+        assert_eq!(smaller.get(&Object::Integer(2)), None);
+        assert_eq!(smaller.get(&Object::Integer(3)), Some(&Object::Integer(9)));
+        assert_eq!(smaller.remove(&Object::Integer(2)).len(), 500);
+    }
+
+    #[test]
+    fn test_structural_equality() {
+        let forwards = (0..100).fold(PersistentMap::new(), |m, i| {
+            m.insert(Object::Integer(i), Object::Nil)
+        });
+        let backwards = (0..100).rev().fold(PersistentMap::new(), |m, i| {
+            m.insert(Object::Integer(i), Object::Nil)
+        });
+        assert!(forwards == backwards);
+        assert_eq!(hash_of(&forwards), hash_of(&backwards));
+        assert!(forwards != backwards.remove(&Object::Integer(5)));
+
+        assert_eq!(read_one("{a 1 b [2 3]}"), read_one("{b [2 3] a 1}"));
+        assert_ne!(read_one("[1 2]"), read_one("(1 2)"));
+    }
+
+    #[test]
+    fn test_keywords_as_functions() {
+        assert_eval!(
+            "(define m (assoc {} :name \"risp\"))
+            (list (:name m) (:version m) (:version m 1) :name (keyword? :name))",
+            Ok(Object::List(vec![
+                Object::String(String::from("risp")),
+                Object::Nil,
+                Object::Integer(1),
+                Object::keyword("name"),
+                Object::Boolean(true)
+            ]))
+        );
+        assert_eval!(
+            "(define h (make-hash-table))
+            (hash-set! h :name \"risp\")
+            (list (:name h) (:version h) (:version h 1))",
+            Ok(Object::List(vec![
+                Object::String(String::from("risp")),
+                Object::Nil,
+                Object::Integer(1)
+            ]))
+        );
+        assert_eval!(
+            "(:name '(1 2))",
+            Err(Object::type_error(
+                ":name: argument is not a map",
+                &read_one("(1 2)")
+            ))
+        );
+        assert_eval!(
+            "(:name)",
+            Err(Object::new_error(
+                ErrorKind::Arity,
+                ":name: wrong number of arguments (expected 1 to 2, got 0)"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_assoc_dissoc_conj_and_get() {
+        assert_eval!(
+            "(define m {:a 1})
+            (define n (assoc m :b 2 :a 3))
+            (list (get m :a) (get n :a) (get n :b) (get m :b) (get m :b 0))",
+            Ok(Object::List(vec![
+                Object::Integer(1),
+                Object::Integer(3),
+                Object::Integer(2),
+                Object::Nil,
+                Object::Integer(0)
+            ]))
+        );
+        assert_eval!("(dissoc {:a 1 :b 2} :a :c)", Ok(read_one("{:b 2}")));
+        assert_eval!(
+            "(conj {:a 1} [:b 2] (list :c 3))",
+            Ok(read_one("{:a 1 :b 2 :c 3}"))
+        );
+        assert_eval!("(conj [1 2] 3 4)", Ok(read_one("[1 2 3 4]")));
+        assert_eval!("(assoc [1 2] 0 'x 2 'y)", Ok(read_one("[x 2 y]")));
+        assert_eval!("(get [1 2 3] 2)", Ok(Object::Integer(3)));
+        assert_eval!("(get {[1 2] pair} [1 2])", Ok(read_one("pair")));
+        assert_eval!(
+            "(assoc [1 2] 5 'x)",
+            Err(Object::new_error_with(
+                ErrorKind::Runtime,
+                "assoc: index out of range",
+                vec![Object::Integer(5)]
+            ))
+        );
+        assert_eval!(
+            "(assoc {} #(1) 'x)",
+            Err(Object::type_error(
+                "assoc: a vector cannot be used as a key",
+                &read_one("#(1)")
+            ))
+        );
+    }
+}
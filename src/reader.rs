@@ -1,34 +1,216 @@
+use std::cell::Cell;
 use std::iter::Peekable;
+use std::mem;
+use std::rc::Rc;
 
+use crate::error::Span;
 use crate::object::Object;
+use crate::persistent::PersistentMap;
+use crate::text::parse_char;
+use crate::vector::new_vector;
+
+/// Wraps a char iterator and records the position of the last char it
+/// yielded, which `Peekable` would otherwise hide from us.
+struct Tracked<I> {
+    chars: I,
+    next: Span,
+    last: Rc<Cell<Span>>,
+}
 
-fn read_integer<T: Iterator<Item = char>>(lexer: &mut Peekable<T>) -> Result<Object, String> {
-    let c = lexer.next().unwrap();
+/// Where a form starts and, if it is a list, where each of its items
+/// start, so that compiled code can be mapped back to source lines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpanTree {
+    pub span: Span,
+    pub items: Vec<SpanTree>,
+}
 
-    let mut number = match c.to_string().parse::<i64>() {
-        Ok(number) => number,
-        Err(e) => {
-            return Err(format!("error parsing number: {:?}", e));
+impl SpanTree {
+    pub(crate) fn at(span: Span) -> SpanTree {
+        SpanTree {
+            span,
+            items: Vec::new(),
         }
-    };
+    }
+}
+
+struct Lexer<I: Iterator<Item = char>> {
+    chars: Peekable<Tracked<I>>,
+    last: Rc<Cell<Span>>,
+    /// The spans of the items of the collection read last, for the caller
+    /// to take.
+    items: Vec<SpanTree>,
+}
+
+impl<I: Iterator<Item = char>> Lexer<I> {
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        self.chars.next()
+    }
+
+    /// Where the next char is; only accurate once it has been peeked at.
+    fn position(&self) -> Span {
+        self.last.get()
+    }
+}
+
+impl<I: Iterator<Item = char>> Iterator for Tracked<I> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.last.set(self.next);
+        if c == '\n' {
+            self.next = Span {
+                line: self.next.line + 1,
+                column: 1,
+            };
+        } else {
+            self.next.column += 1;
+        }
+        Some(c)
+    }
+}
+
+fn read_number<T: Iterator<Item = char>>(lexer: &mut Lexer<T>) -> Result<Object, String> {
+    let mut digits = String::new();
+
+    while let Some(&c) = lexer.peek() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        digits.push(c);
+        lexer.next();
+    }
+
+    if lexer.peek() != Some(&'.') {
+        return match digits.parse::<i64>() {
+            Ok(number) => Ok(Object::Integer(number)),
+            Err(e) => Err(format!("error parsing number: {:?}", e)),
+        };
+    }
+
+    digits.push('.');
+    lexer.next();
+    while let Some(&c) = lexer.peek() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        digits.push(c);
+        lexer.next();
+    }
+
+    match digits.parse::<f64>() {
+        Ok(number) => Ok(Object::Float(number)),
+        Err(e) => Err(format!("error parsing number: {:?}", e)),
+    }
+}
+
+fn read_string<T: Iterator<Item = char>>(lexer: &mut Lexer<T>) -> Result<Object, String> {
+    let mut result = String::new();
 
-    while let Some(Ok(digit)) = lexer.peek().map(|c| c.to_string().parse::<i64>()) {
-        number = number * 10 + digit;
+    lexer.next();
+
+    loop {
+        match lexer.next() {
+            Some('"') => return Ok(Object::String(result)),
+            Some('\\') => match lexer.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(c @ '"') | Some(c @ '\\') => result.push(c),
+                Some(c) => return Err(format!("unknown escape sequence: \\{}", c)),
+                None => return Err(String::from("unterminated string")),
+            },
+            Some(c) => result.push(c),
+            None => return Err(String::from("unterminated string")),
+        }
+    }
+}
+
+fn read_quote<T: Iterator<Item = char>>(lexer: &mut Lexer<T>) -> Result<Object, String> {
+    lexer.next();
+
+    match lexer.peek() {
+        Some(_) => {
+            let span = lexer.position();
+            let quoted = read_object(lexer)?;
+            let quoted_span = SpanTree {
+                span,
+                items: mem::take(&mut lexer.items),
+            };
+            lexer.items = vec![SpanTree::at(span), quoted_span];
+            Ok(Object::List(vec![Object::symbol("quote"), quoted]))
+        }
+        None => Err(String::from("unexpected end of input after quote")),
+    }
+}
+
+fn read_hash<T: Iterator<Item = char>>(lexer: &mut Lexer<T>) -> Result<Object, String> {
+    lexer.next();
+
+    if lexer.peek() == Some(&'(') {
+        return Ok(new_vector(read_items(lexer, ')')?));
+    }
+    if lexer.peek() == Some(&'\\') {
         lexer.next();
+        return read_char(lexer);
     }
 
-    Ok(Object::Integer(number))
+    let mut name = String::new();
+    while let Some(&c) = lexer.peek() {
+        if !valid_symbol_char(&c) {
+            break;
+        }
+        name.push(c);
+        lexer.next();
+    }
+
+    match name.as_str() {
+        "t" | "true" => Ok(Object::Boolean(true)),
+        "f" | "false" => Ok(Object::Boolean(false)),
+        // `#:key` and `#:optional` mark sections of a lambda list.
+        _ if name.starts_with(':') => Ok(Object::symbol(&format!("#{}", name))),
+        _ => Err(format!("unknown syntax: #{}", name)),
+    }
+}
+
+/// Reads a character after `#\\`. The first character is taken as is, so
+/// that `#\\(` and `#\\ ` work, and a name like `space` may follow.
+fn read_char<T: Iterator<Item = char>>(lexer: &mut Lexer<T>) -> Result<Object, String> {
+    let first = match lexer.next() {
+        Some(c) => c,
+        None => return Err(String::from("unexpected end of input after #\\")),
+    };
+
+    let mut name = first.to_string();
+    if valid_symbol_char(&first) {
+        while let Some(&c) = lexer.peek() {
+            if !valid_symbol_char(&c) {
+                break;
+            }
+            name.push(c);
+            lexer.next();
+        }
+    }
+
+    match parse_char(&name) {
+        Some(c) => Ok(Object::Char(c)),
+        None => Err(format!("unknown character: #\\{}", name)),
+    }
 }
 
-fn valid_symbol_char(c: &char) -> bool {
-    if *c == '(' || *c == ')' {
+pub fn valid_symbol_char(c: &char) -> bool {
+    if matches!(c, '(' | ')' | '[' | ']' | '{' | '}' | '"') {
         return false;
     }
 
-    c.is_ascii_alphanumeric() || c.is_ascii_punctuation()
+    c.is_alphanumeric() || c.is_ascii_punctuation()
 }
 
-fn read_symbol<T: Iterator<Item = char>>(lexer: &mut Peekable<T>) -> Result<Object, String> {
+fn read_symbol<T: Iterator<Item = char>>(lexer: &mut Lexer<T>) -> Result<Object, String> {
     let c = lexer.next().unwrap();
     let mut result = c.to_string();
 
@@ -40,16 +222,24 @@ fn read_symbol<T: Iterator<Item = char>>(lexer: &mut Peekable<T>) -> Result<Obje
         result.push(c);
     }
 
-    Ok(Object::Symbol(result))
+    match result.strip_prefix(':') {
+        Some(name) if !name.is_empty() => Ok(Object::keyword(name)),
+        _ => Ok(Object::symbol(&result)),
+    }
 }
 
-fn read_list<T: Iterator<Item = char>>(lexer: &mut Peekable<T>) -> Result<Object, String> {
+/// Reads the forms between an opening bracket and `close`.
+fn read_items<T: Iterator<Item = char>>(
+    lexer: &mut Lexer<T>,
+    close: char,
+) -> Result<Vec<Object>, String> {
     let mut elems = vec![];
+    let mut spans = vec![];
 
     lexer.next();
 
     while let Some(&c) = lexer.peek() {
-        if c == ')' {
+        if c == close {
             lexer.next();
             break;
         }
@@ -58,29 +248,90 @@ fn read_list<T: Iterator<Item = char>>(lexer: &mut Peekable<T>) -> Result<Object
             continue;
         }
 
-        let element = if c == '(' {
-            read_list(lexer)?
-        } else {
-            read_object(lexer)?
-        };
+        let span = lexer.position();
+        elems.push(read_object(lexer)?);
+        spans.push(SpanTree {
+            span,
+            items: mem::take(&mut lexer.items),
+        });
+    }
+
+    lexer.items = spans;
+    Ok(elems)
+}
+
+fn read_list<T: Iterator<Item = char>>(lexer: &mut Lexer<T>) -> Result<Object, String> {
+    Ok(Object::List(read_items(lexer, ')')?))
+}
+
+/// Like `#(...)`, `[...]` and `{...}` literals are self-evaluating, so their
+/// elements are data rather than expressions.
+fn read_persistent_vector<T: Iterator<Item = char>>(
+    lexer: &mut Lexer<T>,
+) -> Result<Object, String> {
+    let items = read_items(lexer, ']')?;
+    Ok(Object::PersistentVector(items.into_iter().collect()))
+}
 
-        elems.push(element);
+fn read_persistent_map<T: Iterator<Item = char>>(lexer: &mut Lexer<T>) -> Result<Object, String> {
+    let items = read_items(lexer, '}')?;
+    if !items.len().is_multiple_of(2) {
+        return Err(String::from("map literal has a key without a value"));
     }
 
-    Ok(Object::List(elems))
+    let mut map = PersistentMap::new();
+    for pair in items.chunks(2) {
+        if !pair[0].is_hashable() {
+            return Err(format!("map literal has an unhashable key: {}", pair[0]));
+        }
+        map = map.insert(pair[0].clone(), pair[1].clone());
+    }
+    Ok(Object::PersistentMap(map))
 }
 
-fn read_object<T: Iterator<Item = char>>(lexer: &mut Peekable<T>) -> Result<Object, String> {
+fn read_object<T: Iterator<Item = char>>(lexer: &mut Lexer<T>) -> Result<Object, String> {
     match lexer.peek() {
-        Some('0'...'9') => read_integer(lexer),
+        Some('0'..='9') => read_number(lexer),
         Some('(') => read_list(lexer),
+        Some('[') => read_persistent_vector(lexer),
+        Some('{') => read_persistent_map(lexer),
+        Some('"') => read_string(lexer),
+        Some('#') => read_hash(lexer),
+        Some('\'') => read_quote(lexer),
         Some(c) if valid_symbol_char(c) => read_symbol(lexer),
         c => Err(format!("unexpected character: {:?}", c)),
     }
 }
 
 pub fn read(code: &str) -> Result<Vec<Object>, String> {
-    let mut lexer = code.chars().peekable();
+    let objects = read_with_spans(code)?;
+    Ok(objects.into_iter().map(|(object, _)| object).collect())
+}
+
+/// Reads all top-level forms in `code` along with where each one starts.
+pub fn read_with_spans(code: &str) -> Result<Vec<(Object, Span)>, String> {
+    let objects = read_with_span_trees(code)?;
+    Ok(objects
+        .into_iter()
+        .map(|(object, spans)| (object, spans.span))
+        .collect())
+}
+
+/// Reads all top-level forms in `code` along with where each one, and each
+/// list within it, starts.
+pub fn read_with_span_trees(code: &str) -> Result<Vec<(Object, SpanTree)>, String> {
+    let start = Span { line: 1, column: 1 };
+    let last = Rc::new(Cell::new(start));
+    let tracked = Tracked {
+        chars: code.chars(),
+        next: start,
+        last: last.clone(),
+    };
+    let mut lexer = Lexer {
+        chars: tracked.peekable(),
+        last,
+        items: Vec::new(),
+    };
     let mut objects = Vec::new();
 
     while let Some(&c) = lexer.peek() {
@@ -89,16 +340,23 @@ pub fn read(code: &str) -> Result<Vec<Object>, String> {
             continue;
         }
 
+        let span = lexer.position();
         let object = read_object(&mut lexer)?;
-        objects.push(object);
+        let spans = SpanTree {
+            span,
+            items: mem::take(&mut lexer.items),
+        };
+        objects.push((object, spans));
     }
 
     Ok(objects)
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants, noop_method_call)] // the number tests match on deref()
 mod tests {
     use super::*;
+    use crate::persistent::PersistentVector;
     use std::ops::Deref;
 
     #[test]
@@ -135,6 +393,123 @@ mod tests {
         }
     }
 
+    #[test]
+    fn reading_floats() {
+        let objects = read("2.5 10.0 3.").unwrap();
+        assert_eq!(
+            objects,
+            vec![Object::Float(2.5), Object::Float(10.0), Object::Float(3.0)]
+        );
+    }
+
+    #[test]
+    fn reading_strings() {
+        let objects = read(r#"("foo bar" "a\"b\\c\n")"#).unwrap();
+        assert_eq!(
+            objects.first().unwrap(),
+            &Object::List(vec![
+                Object::String(String::from("foo bar")),
+                Object::String(String::from("a\"b\\c\n")),
+            ])
+        );
+
+        assert_eq!(read("\"foo"), Err(String::from("unterminated string")));
+    }
+
+    #[test]
+    fn reading_booleans() {
+        let objects = read("#t #f #true #false").unwrap();
+        assert_eq!(
+            objects,
+            vec![
+                Object::Boolean(true),
+                Object::Boolean(false),
+                Object::Boolean(true),
+                Object::Boolean(false)
+            ]
+        );
+
+        assert_eq!(read("#foo"), Err(String::from("unknown syntax: #foo")));
+        assert_eq!(read("#:key").unwrap(), vec![Object::symbol("#:key")]);
+    }
+
+    #[test]
+    fn reading_chars() {
+        let objects = read("#\\a #\\space #\\x41 #\\( #\\λ (#\\))").unwrap();
+        assert_eq!(
+            objects,
+            vec![
+                Object::Char('a'),
+                Object::Char(' '),
+                Object::Char('A'),
+                Object::Char('('),
+                Object::Char('λ'),
+                Object::List(vec![Object::Char(')')]),
+            ]
+        );
+        assert_eq!(format!("{}", objects[1]), "#\\space");
+
+        assert_eq!(
+            read("#\\foo"),
+            Err(String::from("unknown character: #\\foo"))
+        );
+        assert_eq!(
+            read("#\\"),
+            Err(String::from("unexpected end of input after #\\"))
+        );
+    }
+
+    #[test]
+    fn reading_vectors() {
+        let objects = read("#(1 (2) #())").unwrap();
+        assert_eq!(
+            objects,
+            vec![new_vector(vec![
+                Object::Integer(1),
+                Object::List(vec![Object::Integer(2)]),
+                new_vector(Vec::new()),
+            ])]
+        );
+        assert_eq!(format!("{}", objects[0]), "#(1 (2) #())");
+    }
+
+    #[test]
+    fn reading_persistent_collections() {
+        let objects = read("[1 [a] []] {a 1 \"b\" [2]}").unwrap();
+        let vector: PersistentVector = vec![
+            Object::Integer(1),
+            Object::PersistentVector(vec![Object::symbol("a")].into_iter().collect()),
+            Object::PersistentVector(PersistentVector::new()),
+        ]
+        .into_iter()
+        .collect();
+        let map = PersistentMap::new()
+            .insert(Object::symbol("a"), Object::Integer(1))
+            .insert(
+                Object::String(String::from("b")),
+                Object::PersistentVector(vec![Object::Integer(2)].into_iter().collect()),
+            );
+        assert_eq!(
+            objects,
+            vec![Object::PersistentVector(vector), Object::PersistentMap(map)]
+        );
+        assert_eq!(format!("{}", objects[0]), "[1 [a] []]");
+        assert_eq!(format!("{}", read("{}").unwrap()[0]), "{}");
+
+        assert_eq!(
+            read("{a}"),
+            Err(String::from("map literal has a key without a value"))
+        );
+        assert_eq!(
+            read("{#(1) 2}"),
+            Err(String::from("map literal has an unhashable key: #(1)"))
+        );
+        assert_eq!(
+            read("(1 2]"),
+            Err(String::from("unexpected character: Some(']')"))
+        );
+    }
+
     #[test]
     fn reading_lists() {
         let objects = read("(1 2 3)").unwrap();
@@ -177,7 +552,11 @@ mod tests {
         assert!(valid_symbol_char(&'!'));
         assert!(valid_symbol_char(&'+'));
 
+        assert!(valid_symbol_char(&'λ'));
+        assert!(valid_symbol_char(&'ß'));
+
         assert!(!valid_symbol_char(&' '));
+        assert!(!valid_symbol_char(&'['));
     }
 
     #[test]
@@ -186,14 +565,14 @@ mod tests {
         assert_eq!(objects.len(), 1);
         assert_eq!(
             objects.first().unwrap(),
-            &Object::List(vec![Object::Symbol(String::from("list"))])
+            &Object::List(vec![Object::symbol("list")])
         );
 
         let objects = read("(list-one)").unwrap();
         assert_eq!(objects.len(), 1);
         assert_eq!(
             objects.first().unwrap(),
-            &Object::List(vec![Object::Symbol(String::from("list-one"))])
+            &Object::List(vec![Object::symbol("list-one")])
         );
 
         let objects = read("(+ 1 2 3)").unwrap();
@@ -201,7 +580,7 @@ mod tests {
         assert_eq!(
             objects.first().unwrap(),
             &Object::List(vec![
-                Object::Symbol(String::from("+")),
+                Object::symbol("+"),
                 Object::Integer(1),
                 Object::Integer(2),
                 Object::Integer(3)
@@ -209,6 +588,64 @@ mod tests {
         );
     }
 
+    #[test]
+    fn reading_spans() {
+        let objects = read_with_spans("(1)\n  foo (bar\n baz)\n7").unwrap();
+        let spans: Vec<Span> = objects.into_iter().map(|(_, span)| span).collect();
+        assert_eq!(
+            spans,
+            vec![
+                Span { line: 1, column: 1 },
+                Span { line: 2, column: 3 },
+                Span { line: 2, column: 7 },
+                Span { line: 4, column: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn reading_span_trees() {
+        let (_, spans) = read_with_span_trees("(f 1\n  (g 'x))").unwrap().remove(0);
+        let span = |line, column| Span { line, column };
+        assert_eq!(spans.span, span(1, 1));
+        assert_eq!(spans.items.len(), 3);
+        assert_eq!(spans.items[1].span, span(1, 4));
+        let inner = &spans.items[2];
+        assert_eq!(inner.span, span(2, 3));
+        assert_eq!(inner.items[1].span, span(2, 6));
+        assert_eq!(inner.items[1].items[1].span, span(2, 7));
+    }
+
+    #[test]
+    fn reading_keywords() {
+        let objects = read("(:name : a:b)").unwrap();
+        assert_eq!(
+            objects,
+            vec![Object::List(vec![
+                Object::keyword("name"),
+                Object::symbol(":"),
+                Object::symbol("a:b"),
+            ])]
+        );
+        assert_ne!(Object::keyword("name"), Object::symbol("name"));
+        assert_eq!(format!("{}", objects[0]), "(:name : a:b)");
+    }
+
+    #[test]
+    fn reading_quotes() {
+        let objects = read("'foo '(1 2)").unwrap();
+        assert_eq!(
+            objects,
+            vec![
+                Object::List(vec![Object::symbol("quote"), Object::symbol("foo")]),
+                Object::List(vec![
+                    Object::symbol("quote"),
+                    Object::List(vec![Object::Integer(1), Object::Integer(2)])
+                ]),
+            ]
+        );
+    }
+
     #[test]
     fn reading_multiple_lists() {
         let input = "(1)
@@ -219,11 +656,7 @@ mod tests {
         assert_eq!(objects.len(), 3);
 
         assert_eq!(objects[0], Object::List(vec![Object::Integer(1)]));
-        assert_eq!(
-            objects[1],
-            Object::List(vec![Object::Symbol(String::from("foobar"))])
-        );
+        assert_eq!(objects[1], Object::List(vec![Object::symbol("foobar")]));
         assert_eq!(objects[2], Object::List(vec![Object::Integer(7)]));
     }
-
 }
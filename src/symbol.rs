@@ -0,0 +1,299 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::marker::PhantomData;
+
+use crate::object::{Arity, EnvRef, Object};
+
+/// An interned name. Equal names share an id, so comparing and hashing
+/// symbols never looks at their text.
+///
+/// A gensym is the id of its prefix and a serial number, so making one does
+/// not add to the table; its name is only spelled out when it is displayed.
+/// Ids index a table of the current thread, so symbols cannot be sent to
+/// another one.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol {
+    id: u32,
+    /// Zero for interned names. Too wide to run out.
+    serial: u64,
+    thread: PhantomData<*const ()>,
+}
+
+/// Defines constants for the names the evaluator dispatches on. They are
+/// interned first, in order, so their ids are known at compile time.
+macro_rules! well_known {
+    ($($constant:ident = $name:expr,)*) => {
+        const WELL_KNOWN: &[&str] = &[$($name),*];
+        well_known!(@define 0, $($constant,)*);
+    };
+    (@define $id:expr, $constant:ident, $($rest:ident,)*) => {
+        pub const $constant: Symbol = Symbol {
+            id: $id,
+            serial: 0,
+            thread: PhantomData,
+        };
+        well_known!(@define $id + 1, $($rest,)*);
+    };
+    (@define $id:expr,) => {};
+}
+
+well_known! {
+    QUOTE = "quote",
+    DEFINE = "define",
+    SET = "set!",
+    LAMBDA = "lambda",
+    IF = "if",
+    BEGIN = "begin",
+    GUARD = "guard",
+    HANDLER_BIND = "handler-bind",
+    RESTART_CASE = "restart-case",
+    RESET = "reset",
+    SHIFT = "shift",
+    DELAY = "delay",
+    DELAY_FORCE = "delay-force",
+    STREAM_CONS = "stream-cons",
+    ELSE = "else",
+    ARROW = "=>",
+}
+
+struct Interner {
+    /// Names are leaked so that `Symbol::as_str` can hand them out without
+    /// holding a borrow of the table. There is one per distinct name, which
+    /// lives as long as the program anyway.
+    names: Vec<&'static str>,
+    ids: HashMap<&'static str, Symbol>,
+    gensyms: u64,
+}
+
+impl Interner {
+    fn new() -> Interner {
+        let mut interner = Interner {
+            names: Vec::new(),
+            ids: HashMap::new(),
+            gensyms: 0,
+        };
+        for name in WELL_KNOWN {
+            interner.intern(name);
+        }
+        interner
+    }
+
+    fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(&symbol) = self.ids.get(name) {
+            return symbol;
+        }
+
+        let symbol = Symbol {
+            id: self.names.len() as u32,
+            serial: 0,
+            thread: PhantomData,
+        };
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        self.names.push(name);
+        self.ids.insert(name, symbol);
+        symbol
+    }
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::new());
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        INTERNER.with(|interner| interner.borrow_mut().intern(name))
+    }
+
+    /// Makes a symbol that is not equal to any other, even one with the same
+    /// name, for names that generated code must not capture.
+    pub fn gensym(prefix: &str) -> Symbol {
+        INTERNER.with(|interner| {
+            let mut interner = interner.borrow_mut();
+            let prefix = interner.intern(prefix);
+            interner.gensyms += 1;
+            Symbol {
+                serial: interner.gensyms,
+                ..prefix
+            }
+        })
+    }
+
+    /// The name the symbol was interned with. A gensym has no name of its
+    /// own, so this is its prefix; use `to_string` for its whole name.
+    pub fn as_str(self) -> &'static str {
+        INTERNER.with(|interner| interner.borrow().names[self.id as usize])
+    }
+
+    pub fn is_gensym(self) -> bool {
+        self.serial != 0
+    }
+}
+
+/// Hashes a symbol by its id, which is unique already, with a single
+/// multiplication instead of SipHash. Environments look symbols up on every
+/// variable reference that was not resolved to a slot.
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u32(byte as u32);
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Symbol {
+        Symbol::intern(name)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Symbol {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Symbol {
+        Symbol::intern(&name)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.serial {
+            0 => write!(f, "{}", self.as_str()),
+            serial => write!(f, "{}{}", self.as_str(), serial),
+        }
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Symbol({})", self)
+    }
+}
+
+pub fn is_symbol(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("symbol?", args)?;
+    Ok(Object::Boolean(matches!(args[0], Object::Symbol(_))))
+}
+
+pub fn is_keyword(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("keyword?", args)?;
+    Ok(Object::Boolean(matches!(args[0], Object::Keyword(_))))
+}
+
+/// `(gensym [prefix])`
+pub fn gensym(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Range(0, 1).check("gensym", args)?;
+
+    match args.first() {
+        None => Ok(Object::Symbol(Symbol::gensym("g"))),
+        Some(Object::String(prefix)) => Ok(Object::Symbol(Symbol::gensym(prefix))),
+        Some(other) => Err(Object::type_error("gensym: prefix is not a string", other)),
+    }
+}
+
+pub fn symbol_to_string(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("symbol->string", args)?;
+
+    match &args[0] {
+        Object::Symbol(symbol) => Ok(Object::String(symbol.to_string())),
+        other => Err(Object::type_error(
+            "symbol->string: argument is not a symbol",
+            other,
+        )),
+    }
+}
+
+pub fn string_to_symbol(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("string->symbol", args)?;
+
+    match &args[0] {
+        Object::String(name) => Ok(Object::Symbol(Symbol::intern(name))),
+        other => Err(Object::type_error(
+            "string->symbol: argument is not a string",
+            other,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::test_util::assert_eval;
+
+    #[test]
+    fn test_interning() {
+        assert_eq!(Symbol::intern("foo"), Symbol::intern("foo"));
+        assert_ne!(Symbol::intern("foo"), Symbol::intern("bar"));
+        assert_eq!(Symbol::intern("foo").as_str(), "foo");
+        assert_eq!(Symbol::intern("lambda"), LAMBDA);
+        assert_eq!(ARROW.as_str(), "=>");
+    }
+
+    #[test]
+    fn test_gensyms_are_unique() {
+        let a = Symbol::gensym("x");
+        let b = Symbol::gensym("x");
+        assert_ne!(a, b);
+        assert_ne!(Symbol::intern(&a.to_string()), a);
+        assert_eq!(a.as_str(), "x");
+        assert!(a.to_string().starts_with('x'));
+    }
+
+    #[test]
+    fn test_gensyms_do_not_grow_the_table() {
+        let before = INTERNER.with(|interner| interner.borrow().names.len());
+        for _ in 0..1000 {
+            Symbol::gensym("tmp");
+        }
+        let after = INTERNER.with(|interner| interner.borrow().names.len());
+        assert!(after <= before + 1);
+    }
+
+    #[test]
+    fn test_symbol_natives() {
+        assert_eval!(
+            "(symbol->string 'foo)",
+            Ok(Object::String(String::from("foo")))
+        );
+        assert_eval!("(string->symbol \"foo\")", Ok(Object::symbol("foo")));
+        assert_eval!(
+            "(list (symbol? (gensym)) (symbol? \"g\"))",
+            Ok(Object::List(vec![
+                Object::Boolean(true),
+                Object::Boolean(false)
+            ]))
+        );
+        assert_eval!(
+            "(define g (gensym \"tmp\"))
+            (define t (make-hash-table))
+            (hash-set! t g 'found)
+            (list (hash-ref t g) (hash-ref t (string->symbol (symbol->string g)) 'missing))",
+            Ok(Object::List(vec![
+                Object::symbol("found"),
+                Object::symbol("missing")
+            ]))
+        );
+    }
+}
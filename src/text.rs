@@ -0,0 +1,365 @@
+use std::convert::TryFrom;
+
+use crate::error::ErrorKind;
+use crate::object::{Arity, EnvRef, Object};
+
+/// Character names accepted after `#\`, and used to print characters that
+/// would otherwise be invisible.
+pub const CHAR_NAMES: &[(&str, char)] = &[
+    ("space", ' '),
+    ("newline", '\n'),
+    ("tab", '\t'),
+    ("return", '\r'),
+    ("null", '\0'),
+    ("alarm", '\u{7}'),
+    ("backspace", '\u{8}'),
+    ("delete", '\u{7f}'),
+    ("escape", '\u{1b}'),
+];
+
+/// Parses what follows `#\`: a single character, a name from `CHAR_NAMES`,
+/// or `x` and a hex scalar value.
+pub fn parse_char(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+
+    if let Some(&(_, c)) = CHAR_NAMES.iter().find(|(n, _)| *n == name) {
+        return Some(c);
+    }
+    let hex = name.strip_prefix('x')?;
+    char::from_u32(u32::from_str_radix(hex, 16).ok()?)
+}
+
+/// Writes a character the way the reader accepts it.
+pub fn char_literal(c: char) -> String {
+    match CHAR_NAMES.iter().find(|(_, named)| *named == c) {
+        Some((name, _)) => format!("#\\{}", name),
+        None if c.is_control() => format!("#\\x{:x}", c as u32),
+        None => format!("#\\{}", c),
+    }
+}
+
+const ZWJ: char = '\u{200d}';
+
+/// Whether `c` extends the grapheme before it: combining marks, variation
+/// selectors, emoji modifiers and tags. Without the Unicode tables this
+/// covers the common blocks rather than the whole `Extend` property.
+fn is_extend(c: char) -> bool {
+    matches!(c,
+        '\u{300}'..='\u{36f}'
+        | '\u{483}'..='\u{489}'
+        | '\u{591}'..='\u{5bd}'
+        | '\u{610}'..='\u{61a}'
+        | '\u{64b}'..='\u{65f}'
+        | '\u{670}'
+        | '\u{6d6}'..='\u{6dc}'
+        | '\u{6df}'..='\u{6e4}'
+        | '\u{900}'..='\u{903}'
+        | '\u{93a}'..='\u{94f}'
+        | '\u{951}'..='\u{957}'
+        | '\u{962}'..='\u{963}'
+        | '\u{e31}'
+        | '\u{e34}'..='\u{e3a}'
+        | '\u{e47}'..='\u{e4e}'
+        | '\u{1ab0}'..='\u{1aff}'
+        | '\u{1dc0}'..='\u{1dff}'
+        | '\u{200c}'
+        | '\u{20d0}'..='\u{20ff}'
+        | '\u{302a}'..='\u{302f}'
+        | '\u{3099}'..='\u{309a}'
+        | '\u{fe00}'..='\u{fe0f}'
+        | '\u{fe20}'..='\u{fe2f}'
+        | '\u{1f3fb}'..='\u{1f3ff}'
+        | '\u{e0020}'..='\u{e007f}'
+        | '\u{e0100}'..='\u{e01ef}')
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c, '\u{1f1e6}'..='\u{1f1ff}')
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Hangul {
+    Leading,
+    Vowel,
+    Trailing,
+    Syllable,
+    SyllableWithTrailing,
+}
+
+fn hangul(c: char) -> Option<Hangul> {
+    match c {
+        '\u{1100}'..='\u{115f}' | '\u{a960}'..='\u{a97c}' => Some(Hangul::Leading),
+        '\u{1160}'..='\u{11a7}' | '\u{d7b0}'..='\u{d7c6}' => Some(Hangul::Vowel),
+        '\u{11a8}'..='\u{11ff}' | '\u{d7cb}'..='\u{d7fb}' => Some(Hangul::Trailing),
+        '\u{ac00}'..='\u{d7a3}' if (c as u32 - 0xac00).is_multiple_of(28) => Some(Hangul::Syllable),
+        '\u{ac00}'..='\u{d7a3}' => Some(Hangul::SyllableWithTrailing),
+        _ => None,
+    }
+}
+
+/// Whether a grapheme boundary falls between `prev` and `c`, where `prev`
+/// ends a run of `regional` regional indicators.
+fn is_boundary(prev: char, c: char, regional: usize) -> bool {
+    use Hangul::*;
+
+    if prev == '\r' && c == '\n' {
+        return false;
+    }
+    if prev.is_control() || c.is_control() {
+        return true;
+    }
+    match (hangul(prev), hangul(c)) {
+        (Some(Leading), Some(Leading | Vowel | Syllable | SyllableWithTrailing))
+        | (Some(Syllable | Vowel), Some(Vowel | Trailing))
+        | (Some(SyllableWithTrailing | Trailing), Some(Trailing)) => return false,
+        _ => {}
+    }
+    if is_extend(c) || c == ZWJ || prev == ZWJ {
+        return false;
+    }
+    !(is_regional_indicator(prev) && is_regional_indicator(c) && !regional.is_multiple_of(2))
+}
+
+/// Splits `s` into user-perceived characters, following the extended
+/// grapheme cluster rules of UAX #29 closely enough for common text: marks
+/// attach to their base, emoji sequences joined by ZWJ and flags stay whole,
+/// and Hangul jamo combine into syllables.
+pub fn graphemes(s: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut prev = None;
+    let mut regional = 0;
+
+    for (i, c) in s.char_indices() {
+        if let Some(prev) = prev {
+            if is_boundary(prev, c, regional) {
+                result.push(&s[start..i]);
+                start = i;
+            }
+        }
+        regional = if is_regional_indicator(c) {
+            regional + 1
+        } else {
+            0
+        };
+        prev = Some(c);
+    }
+
+    if start < s.len() {
+        result.push(&s[start..]);
+    }
+    result
+}
+
+fn char_arg(name: &str, arg: &Object) -> Result<char, Object> {
+    match arg {
+        Object::Char(c) => Ok(*c),
+        other => Err(Object::type_error(
+            &format!("{}: argument is not a character", name),
+            other,
+        )),
+    }
+}
+
+fn string_arg<'a>(name: &str, arg: &'a Object) -> Result<&'a str, Object> {
+    match arg {
+        Object::String(s) => Ok(s),
+        other => Err(Object::type_error(
+            &format!("{}: argument is not a string", name),
+            other,
+        )),
+    }
+}
+
+pub fn is_char(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("char?", args)?;
+    Ok(Object::Boolean(matches!(args[0], Object::Char(_))))
+}
+
+pub fn char_to_integer(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("char->integer", args)?;
+    let c = char_arg("char->integer", &args[0])?;
+    Ok(Object::Integer(c as i64))
+}
+
+pub fn integer_to_char(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("integer->char", args)?;
+
+    let c = match args[0] {
+        Object::Integer(n) => u32::try_from(n).ok().and_then(char::from_u32),
+        ref other => {
+            return Err(Object::type_error(
+                "integer->char: argument is not an integer",
+                other,
+            ))
+        }
+    };
+    c.map(Object::Char).ok_or_else(|| {
+        Object::new_error_with(
+            ErrorKind::Runtime,
+            "integer->char: not a Unicode scalar value",
+            vec![args[0].clone()],
+        )
+    })
+}
+
+/// Maps a character with `f` when that gives a single character, and leaves
+/// it alone otherwise, as for `ß`, whose uppercase is `SS`.
+fn map_case<I: Iterator<Item = char>>(c: char, f: fn(char) -> I) -> char {
+    let mut mapped = f(c);
+    match (mapped.next(), mapped.next()) {
+        (Some(m), None) => m,
+        _ => c,
+    }
+}
+
+pub fn char_upcase(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("char-upcase", args)?;
+    let c = char_arg("char-upcase", &args[0])?;
+    Ok(Object::Char(map_case(c, char::to_uppercase)))
+}
+
+pub fn char_downcase(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("char-downcase", args)?;
+    let c = char_arg("char-downcase", &args[0])?;
+    Ok(Object::Char(map_case(c, char::to_lowercase)))
+}
+
+pub fn is_char_alphabetic(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("char-alphabetic?", args)?;
+    let c = char_arg("char-alphabetic?", &args[0])?;
+    Ok(Object::Boolean(c.is_alphabetic()))
+}
+
+/// `(string-length s)` counts graphemes, so `"e\u301"` has length 1.
+pub fn string_length(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("string-length", args)?;
+    let s = string_arg("string-length", &args[0])?;
+    Ok(Object::Integer(graphemes(s).len() as i64))
+}
+
+/// `(string-ref s k)` returns the `k`th grapheme as a string, since a
+/// grapheme can be more than one character.
+pub fn string_ref(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(2).check("string-ref", args)?;
+    let s = string_arg("string-ref", &args[0])?;
+    let graphemes = graphemes(s);
+
+    let grapheme = match args[1] {
+        Object::Integer(k) if k >= 0 => graphemes.get(k as usize),
+        Object::Integer(_) => None,
+        ref other => {
+            return Err(Object::type_error(
+                "string-ref: index is not an integer",
+                other,
+            ))
+        }
+    };
+    let grapheme = grapheme.ok_or_else(|| {
+        Object::new_error_with(
+            ErrorKind::Runtime,
+            "string-ref: index out of range",
+            vec![args[1].clone()],
+        )
+    })?;
+    Ok(Object::String(grapheme.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::test_util::assert_eval;
+
+    #[test]
+    fn test_graphemes() {
+        assert_eq!(graphemes("abc"), vec!["a", "b", "c"]);
+        assert_eq!(graphemes("e\u{301}x"), vec!["e\u{301}", "x"]);
+        assert_eq!(graphemes("a\r\nb"), vec!["a", "\r\n", "b"]);
+        // A family emoji joined by ZWJ, and two flags.
+        assert_eq!(
+            graphemes("👩\u{200d}👩\u{200d}👧!"),
+            vec!["👩\u{200d}👩\u{200d}👧", "!"]
+        );
+        assert_eq!(graphemes("🇳🇴🇸🇪"), vec!["🇳🇴", "🇸🇪"]);
+        assert_eq!(graphemes("👍🏽"), vec!["👍🏽"]);
+        assert_eq!(
+            graphemes("\u{1112}\u{1161}\u{11ab}"),
+            vec!["\u{1112}\u{1161}\u{11ab}"]
+        );
+        assert_eq!(graphemes(""), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_char_names() {
+        assert_eq!(parse_char("a"), Some('a'));
+        assert_eq!(parse_char("λ"), Some('λ'));
+        assert_eq!(parse_char("space"), Some(' '));
+        assert_eq!(parse_char("x41"), Some('A'));
+        assert_eq!(parse_char("x"), Some('x'));
+        assert_eq!(parse_char("xd800"), None);
+        assert_eq!(parse_char("nope"), None);
+
+        assert_eq!(char_literal('a'), "#\\a");
+        assert_eq!(char_literal(' '), "#\\space");
+        assert_eq!(char_literal('\u{1}'), "#\\x1");
+    }
+
+    #[test]
+    fn test_char_natives() {
+        assert_eval!("(char->integer #\\A)", Ok(Object::Integer(65)));
+        assert_eval!("(integer->char 955)", Ok(Object::Char('λ')));
+        assert_eval!("(char-upcase #\\ä)", Ok(Object::Char('Ä')));
+        assert_eval!("(char-upcase #\\ß)", Ok(Object::Char('ß')));
+        assert_eval!("(char-downcase #\\x41)", Ok(Object::Char('a')));
+        assert_eval!(
+            "(list (char-alphabetic? #\\λ) (char-alphabetic? #\\3) (char? #\\a) (char? \"a\"))",
+            Ok(Object::List(vec![
+                Object::Boolean(true),
+                Object::Boolean(false),
+                Object::Boolean(true),
+                Object::Boolean(false)
+            ]))
+        );
+        assert_eval!(
+            "(char->integer \"a\")",
+            Err(Object::type_error(
+                "char->integer: argument is not a character",
+                &Object::String(String::from("a"))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_string_ref_and_length() {
+        assert_eval!("(string-length \"héllo\")", Ok(Object::Integer(5)));
+        assert_eval!("(string-length \"e\u{301}\")", Ok(Object::Integer(1)));
+        assert_eval!(
+            "(string-ref \"añb\" 1)",
+            Ok(Object::String(String::from("ñ")))
+        );
+        assert_eval!(
+            "(string-ref \"🇳🇴!\" 0)",
+            Ok(Object::String(String::from("🇳🇴")))
+        );
+        assert_eval!(
+            "(string-ref \"ab\" 2)",
+            Err(Object::new_error_with(
+                ErrorKind::Runtime,
+                "string-ref: index out of range",
+                vec![Object::Integer(2)]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unicode_symbols() {
+        assert_eval!(
+            "(define λ (lambda (größe) (* größe 2)))
+            (λ 21)",
+            Ok(Object::Integer(42))
+        );
+    }
+}
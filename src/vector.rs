@@ -0,0 +1,270 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::error::ErrorKind;
+use crate::evaluator::apply;
+use crate::gc;
+use crate::object::{Arity, EnvRef, Object};
+
+pub type VectorRef = Rc<RefCell<Vec<Object>>>;
+
+pub fn new_vector(items: Vec<Object>) -> Object {
+    let vector = Rc::new(RefCell::new(items));
+    gc::track(&vector);
+    Object::Vector(vector)
+}
+
+fn vector_arg(name: &str, arg: &Object) -> Result<VectorRef, Object> {
+    match arg {
+        Object::Vector(items) => Ok(items.clone()),
+        other => Err(Object::type_error(
+            &format!("{}: argument is not a vector", name),
+            other,
+        )),
+    }
+}
+
+/// Reads an index argument, which may be one past the end when `inclusive`
+/// is set, as for the end of a range.
+fn index_arg(name: &str, arg: &Object, len: usize, inclusive: bool) -> Result<usize, Object> {
+    let index = match arg {
+        Object::Integer(index) => *index,
+        other => {
+            return Err(Object::type_error(
+                &format!("{}: index is not an integer", name),
+                other,
+            ))
+        }
+    };
+
+    let limit = if inclusive { len + 1 } else { len };
+    if index < 0 || index as usize >= limit {
+        return Err(Object::new_error_with(
+            ErrorKind::Runtime,
+            &format!("{}: index out of range", name),
+            vec![arg.clone()],
+        ));
+    }
+    Ok(index as usize)
+}
+
+pub fn vector(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Ok(new_vector(args.to_vec()))
+}
+
+pub fn is_vector(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("vector?", args)?;
+    Ok(Object::Boolean(matches!(args[0], Object::Vector(_))))
+}
+
+pub fn make_vector(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Range(1, 2).check("make-vector", args)?;
+
+    let len = match args[0] {
+        Object::Integer(len) if len >= 0 => len as usize,
+        ref other => {
+            return Err(Object::type_error(
+                "make-vector: length is not a non-negative integer",
+                other,
+            ))
+        }
+    };
+    let fill = args.get(1).cloned().unwrap_or(Object::Nil);
+    let mut items = Vec::new();
+    if items.try_reserve_exact(len).is_err() {
+        return Err(Object::new_error_with(
+            ErrorKind::Runtime,
+            "make-vector: not enough memory for the length",
+            vec![args[0].clone()],
+        ));
+    }
+    items.resize(len, fill);
+    Ok(new_vector(items))
+}
+
+pub fn vector_ref(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(2).check("vector-ref", args)?;
+
+    let items = vector_arg("vector-ref", &args[0])?;
+    let items = items.borrow();
+    let index = index_arg("vector-ref", &args[1], items.len(), false)?;
+    Ok(items[index].clone())
+}
+
+pub fn vector_set(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(3).check("vector-set!", args)?;
+
+    let items = vector_arg("vector-set!", &args[0])?;
+    let mut items = items.borrow_mut();
+    let index = index_arg("vector-set!", &args[1], items.len(), false)?;
+    items[index] = args[2].clone();
+    Ok(Object::Nil)
+}
+
+pub fn vector_length(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("vector-length", args)?;
+
+    let items = vector_arg("vector-length", &args[0])?;
+    let len = items.borrow().len();
+    Ok(Object::Integer(len as i64))
+}
+
+pub fn vector_to_list(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Exact(1).check("vector->list", args)?;
+
+    let items = vector_arg("vector->list", &args[0])?;
+    let items = items.borrow().clone();
+    Ok(Object::List(items))
+}
+
+/// `(vector-map f v...)` calls `f` with the elements at each index, up to
+/// the length of the shortest vector.
+pub fn vector_map(args: &[Object], env: EnvRef) -> Result<Object, Object> {
+    Arity::AtLeast(2).check("vector-map", args)?;
+
+    let vectors = args[1..]
+        .iter()
+        .map(|arg| vector_arg("vector-map", arg))
+        .collect::<Result<Vec<VectorRef>, Object>>()?;
+    let len = vectors.iter().map(|v| v.borrow().len()).min().unwrap_or(0);
+
+    let mut result = Vec::with_capacity(len);
+    for i in 0..len {
+        // Copied out first, so that `f` may mutate the vectors.
+        let elements: Vec<Object> = vectors.iter().map(|v| v.borrow()[i].clone()).collect();
+        result.push(apply(&args[0], &elements, env.clone())?);
+    }
+    Ok(new_vector(result))
+}
+
+/// `(vector-fill! v fill [start [end]])`
+pub fn vector_fill(args: &[Object], _env: EnvRef) -> Result<Object, Object> {
+    Arity::Range(2, 4).check("vector-fill!", args)?;
+
+    let items = vector_arg("vector-fill!", &args[0])?;
+    let mut items = items.borrow_mut();
+    let len = items.len();
+    let start = match args.get(2) {
+        Some(start) => index_arg("vector-fill!", start, len, true)?,
+        None => 0,
+    };
+    let end = match args.get(3) {
+        Some(end) => index_arg("vector-fill!", end, len, true)?,
+        None => len,
+    };
+
+    for item in items.iter_mut().take(end).skip(start) {
+        *item = args[1].clone();
+    }
+    Ok(Object::Nil)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ErrorKind;
+    use crate::object::Object;
+    use crate::reader;
+    use crate::vm::test_util::{assert_eval, eval_all};
+
+    fn read_one(code: &str) -> Object {
+        reader::read(code).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_vector_literals() {
+        assert_eval!("#(1 2 3)", Ok(read_one("#(1 2 3)")));
+        assert_eval!("(vector 1 (+ 1 1) 3)", Ok(read_one("#(1 2 3)")));
+        assert_eval!("(vector-length #())", Ok(Object::Integer(0)));
+        assert_eval!(
+            "(list (vector? #(1)) (vector? '(1)))",
+            Ok(Object::List(vec![
+                Object::Boolean(true),
+                Object::Boolean(false)
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_vector_ref_and_set() {
+        assert_eval!("(vector-ref #(1 2 3) 1)", Ok(Object::Integer(2)));
+        assert_eval!(
+            "(define v (make-vector 3 0))
+            (vector-set! v 0 'a)
+            v",
+            Ok(read_one("#(a 0 0)"))
+        );
+        assert_eval!(
+            "(vector-ref #(1 2 3) 3)",
+            Err(Object::new_error_with(
+                ErrorKind::Runtime,
+                "vector-ref: index out of range",
+                vec![Object::Integer(3)]
+            ))
+        );
+        assert_eval!(
+            "(vector-ref '(1 2 3) 0)",
+            Err(Object::type_error(
+                "vector-ref: argument is not a vector",
+                &read_one("(1 2 3)")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_make_vector_too_long() {
+        assert_eval!(
+            "(make-vector 10000000000000 0)",
+            Err(Object::new_error_with(
+                ErrorKind::Runtime,
+                "make-vector: not enough memory for the length",
+                vec![Object::Integer(10000000000000)]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_vectors_are_shared() {
+        assert_eval!(
+            "(define v (vector 1 2))
+            (define w v)
+            (vector-set! w 1 5)
+            (vector->list v)",
+            Ok(read_one("(1 5)"))
+        );
+    }
+
+    #[test]
+    fn test_vectors_that_hold_themselves() {
+        let cycle = "(define v (vector 1 2)) (vector-set! v 0 v) v";
+        let v = eval_all(cycle).unwrap();
+        assert_eq!(v.to_string(), "#(#<cycle> 2)");
+        assert_eq!(format!("{:?}", v), "#(#<cycle> 2)");
+
+        assert_eq!(v, eval_all(cycle).unwrap());
+        assert_ne!(
+            v,
+            eval_all("(define v (vector 1 3)) (vector-set! v 0 v) v").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_vector_map_and_fill() {
+        assert_eval!(
+            "(vector-map (lambda (x) (* x x)) #(1 2 3))",
+            Ok(read_one("#(1 4 9)"))
+        );
+        assert_eval!("(vector-map + #(1 2 3) #(10 20))", Ok(read_one("#(11 22)")));
+        assert_eval!(
+            "(define v (make-vector 4 0))
+            (vector-fill! v 7 1 3)
+            v",
+            Ok(read_one("#(0 7 7 0)"))
+        );
+        assert_eval!(
+            "(define v (make-vector 2 0))
+            (vector-fill! v 'x)
+            v",
+            Ok(read_one("#(x x)"))
+        );
+    }
+}
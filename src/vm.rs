@@ -0,0 +1,676 @@
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use crate::bytecode::{self, Capture, Op, Proto};
+use crate::error::ErrorKind;
+use crate::evaluator;
+use crate::gc::{self, Kind, Trace, Tracer};
+use crate::object::{EnvRef, Function, Object};
+use crate::parameters::Argument;
+use crate::reader::SpanTree;
+use crate::symbol::Symbol;
+
+/// A variable that closures share: a local that is captured or assigned
+/// to. It is empty until its definition runs.
+pub type Cell = Rc<RefCell<Option<Object>>>;
+
+/// A procedure compiled to bytecode. Globals are looked up by name in
+/// `env`, like those of a lambda.
+pub struct Closure {
+    pub name: Option<Symbol>,
+    pub proto: Rc<Proto>,
+    pub upvalues: Vec<Cell>,
+    pub env: EnvRef,
+}
+
+impl Closure {
+    pub fn display_name(&self) -> &'static str {
+        self.name.map_or("<lambda>", Symbol::as_str)
+    }
+
+    /// The same closure under another name.
+    pub fn named(&self, name: Symbol) -> Closure {
+        Closure {
+            name: Some(name),
+            proto: self.proto.clone(),
+            upvalues: self.upvalues.clone(),
+            env: self.env.clone(),
+        }
+    }
+}
+
+/// Wraps a closure in an object, tracked by the collector.
+pub(crate) fn new_closure(closure: Closure) -> Object {
+    let closure = Rc::new(closure);
+    gc::track(&closure);
+    Object::Callable(Function::Compiled(closure))
+}
+
+fn new_cell(value: Option<Object>) -> Cell {
+    let cell = Rc::new(RefCell::new(value));
+    gc::track(&cell);
+    cell
+}
+
+impl Trace for Closure {
+    fn kind(&self) -> Kind {
+        Kind::Closure
+    }
+
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        self.upvalues.iter().for_each(|cell| tracer.edge(cell));
+        tracer.edge(&self.env);
+        true
+    }
+
+    /// A closure cannot be emptied, but every cycle through one also runs
+    /// through its cells or environment, which can.
+    fn clear(&self) {}
+}
+
+impl Trace for RefCell<Option<Object>> {
+    fn kind(&self) -> Kind {
+        Kind::Cell
+    }
+
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        match self.try_borrow() {
+            Ok(value) => value.iter().for_each(|value| tracer.object(value)),
+            Err(_) => return false,
+        }
+        true
+    }
+
+    fn clear(&self) {
+        if let Ok(mut value) = self.try_borrow_mut() {
+            *value = None;
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Slot {
+    Value(Object),
+    Cell(Cell),
+    /// A parameter whose default has not been evaluated yet, or a local
+    /// that is about to be boxed.
+    Unbound,
+}
+
+impl Slot {
+    fn value(&self) -> Option<Object> {
+        match self {
+            Slot::Value(value) => Some(value.clone()),
+            Slot::Cell(cell) => cell.borrow().clone(),
+            Slot::Unbound => None,
+        }
+    }
+
+    fn into_value(self) -> Object {
+        match self {
+            Slot::Value(value) => value,
+            other => other.value().unwrap_or(Object::Nil),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// Where the closure's locals start on the stack. The closure itself
+    /// is just below them.
+    base: usize,
+}
+
+/// The call frames and operand stack of compiled code. When it calls
+/// something that the evaluator has to apply, the evaluator keeps the
+/// state in one of its frames and resumes it with the result.
+#[derive(Clone, Default)]
+pub struct VmState {
+    frames: Vec<CallFrame>,
+    stack: Vec<Slot>,
+    /// Reused for the arguments of natives.
+    args: Vec<Object>,
+}
+
+/// The code of a procedure, and the offset of an instruction in it.
+type Location = (Option<Rc<Proto>>, usize);
+
+/// Why the VM stopped running.
+pub(crate) enum Exit {
+    Return(Object),
+    Apply(Object, Vec<Object>),
+    Error(Object),
+}
+
+enum Called {
+    /// A native's value is on the stack.
+    Value,
+    /// A closure's frame is on top.
+    Frame,
+    Exit(Exit),
+}
+
+fn unbound(name: Symbol) -> Object {
+    Object::new_error_with(
+        ErrorKind::Unbound,
+        "unbound variable",
+        vec![Object::Symbol(name)],
+    )
+}
+
+fn invalid(message: &str) -> Object {
+    Object::new_error(
+        ErrorKind::Runtime,
+        &format!("invalid bytecode: {}", message),
+    )
+}
+
+fn operand(code: &[u8], ip: &mut usize) -> usize {
+    let value = u16::from_le_bytes([code[*ip], code[*ip + 1]]);
+    *ip += 2;
+    value as usize
+}
+
+fn symbol_constant(proto: &Proto, index: usize) -> Result<Symbol, Object> {
+    match proto.constants.get(index) {
+        Some(Object::Symbol(name)) => Ok(*name),
+        _ => Err(invalid("expected a symbol constant")),
+    }
+}
+
+/// Looks up a variable that has no value in its slot, e.g. an internal
+/// definition that has not run yet, by name, as the evaluator does.
+fn lookup_global(closure: &Closure, name: Symbol) -> Result<Object, Object> {
+    closure
+        .env
+        .borrow()
+        .lookup(name)
+        .ok_or_else(|| unbound(name))
+}
+
+/// Assigns to a cell, or, while it is empty, to the variable of the same
+/// name further out.
+fn assign(cell: &Cell, closure: &Closure, name: Symbol, value: Object) -> Result<(), Object> {
+    let mut content = cell.borrow_mut();
+    match *content {
+        Some(_) => {
+            *content = Some(value);
+            Ok(())
+        }
+        None => closure.env.borrow_mut().set(name, value),
+    }
+}
+
+impl VmState {
+    /// Pushes a frame that calls `closure` with `args`.
+    pub(crate) fn enter(&mut self, closure: Rc<Closure>, args: Vec<Object>) -> Result<(), Object> {
+        if self.frames.len() >= evaluator::MAX_FRAMES {
+            return Err(evaluator::stack_overflow());
+        }
+        self.stack
+            .push(Slot::Value(Object::Callable(Function::Compiled(
+                closure.clone(),
+            ))));
+        let base = self.stack.len();
+        self.stack.extend(args.into_iter().map(Slot::Value));
+        self.frames.push(CallFrame {
+            closure: closure.clone(),
+            ip: 0,
+            base,
+        });
+        self.bind(&closure, base)
+    }
+
+    /// Continues with the value of a call that the evaluator made.
+    pub(crate) fn push(&mut self, value: Object) {
+        self.stack.push(Slot::Value(value));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// The names of the procedures being run, innermost first, for traces.
+    pub(crate) fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.frames
+            .iter()
+            .rev()
+            .filter(|frame| !frame.closure.proto.toplevel)
+            .map(|frame| frame.closure.display_name())
+    }
+
+    /// Matches the arguments above `base` against the closure's parameters,
+    /// leaving its locals in their place.
+    fn bind(&mut self, closure: &Closure, base: usize) -> Result<(), Object> {
+        let proto = &closure.proto;
+        let parameters = &proto.parameters;
+        let argc = self.stack.len() - base;
+        if parameters.is_simple() {
+            if argc != parameters.required.len() {
+                return Err(parameters.arity_error(closure.display_name(), argc));
+            }
+        } else {
+            let args = self.stack.drain(base..).map(Slot::into_value).collect();
+            for (_, argument) in parameters.bind(closure.display_name(), args)? {
+                self.stack.push(match argument {
+                    Argument::Given(value) => Slot::Value(value),
+                    Argument::Default(_) => Slot::Unbound,
+                });
+            }
+        }
+        self.stack.resize(base + proto.locals.len(), Slot::Unbound);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Object {
+        self.stack.pop().map_or(Object::Nil, Slot::into_value)
+    }
+
+    fn take_args(&mut self, from: usize) -> Vec<Object> {
+        self.stack.drain(from..).map(Slot::into_value).collect()
+    }
+
+    pub(crate) fn run(&mut self, env: &EnvRef) -> Exit {
+        let mut at = (None, 0);
+        match self.execute(env, &mut at) {
+            Ok(exit) => exit,
+            Err(error) => match at {
+                (Some(proto), offset) => match proto.span_at(offset) {
+                    Some(span) => Exit::Error(error.with_span(span)),
+                    None => Exit::Error(error),
+                },
+                (None, _) => Exit::Error(error),
+            },
+        }
+    }
+
+    /// Runs the frames on top, keeping `at` on the instruction being run,
+    /// so that an error can be located in the source.
+    fn execute(&mut self, env: &EnvRef, at: &mut Location) -> Result<Exit, Object> {
+        'frames: loop {
+            let (closure, mut ip, base) = match self.frames.last() {
+                Some(frame) => (frame.closure.clone(), frame.ip, frame.base),
+                None => return Ok(Exit::Return(Object::Nil)),
+            };
+            let proto = &*closure.proto;
+            let code = &proto.code[..];
+            at.0 = Some(closure.proto.clone());
+
+            loop {
+                at.1 = ip;
+                let op = match code.get(ip).and_then(|byte| Op::from_byte(*byte)) {
+                    Some(op) => op,
+                    None => return Err(invalid("unknown instruction")),
+                };
+                ip += 1;
+
+                match op {
+                    Op::Constant => {
+                        let index = operand(code, &mut ip);
+                        self.push(proto.constants[index].clone());
+                    }
+                    Op::GetGlobal => {
+                        let name = symbol_constant(proto, operand(code, &mut ip))?;
+                        let value = lookup_global(&closure, name)?;
+                        self.push(value);
+                    }
+                    Op::SetGlobal => {
+                        let name = symbol_constant(proto, operand(code, &mut ip))?;
+                        let value = self.pop();
+                        closure.env.borrow_mut().set(name, value)?;
+                        self.push(Object::Nil);
+                    }
+                    Op::DefineGlobal => {
+                        let name = symbol_constant(proto, operand(code, &mut ip))?;
+                        let value = evaluator::named(self.pop(), name);
+                        closure.env.borrow_mut().define(name, value).map_err(|e| {
+                            Object::new_error(ErrorKind::Runtime, "defining failed").with_cause(e)
+                        })?;
+                        self.push(Object::Nil);
+                    }
+                    Op::GetLocal | Op::GetCell => {
+                        let index = operand(code, &mut ip);
+                        let value = match self.stack[base + index].value() {
+                            Some(value) => value,
+                            None => lookup_global(&closure, proto.locals[index])?,
+                        };
+                        self.push(value);
+                    }
+                    Op::BindLocal => {
+                        let index = operand(code, &mut ip);
+                        let value = self.pop();
+                        match &mut self.stack[base + index] {
+                            Slot::Cell(cell) => *cell.borrow_mut() = Some(value),
+                            slot => *slot = Slot::Value(value),
+                        }
+                    }
+                    Op::MakeCell => {
+                        let slot = &mut self.stack[base + operand(code, &mut ip)];
+                        *slot = Slot::Cell(new_cell(slot.value()));
+                    }
+                    Op::SetCell => {
+                        let index = operand(code, &mut ip);
+                        let value = self.pop();
+                        match &self.stack[base + index] {
+                            Slot::Cell(cell) => assign(cell, &closure, proto.locals[index], value)?,
+                            _ => return Err(invalid("local is not boxed")),
+                        }
+                        self.push(Object::Nil);
+                    }
+                    Op::DefineCell => {
+                        let index = operand(code, &mut ip);
+                        let value = evaluator::named(self.pop(), proto.locals[index]);
+                        match &self.stack[base + index] {
+                            Slot::Cell(cell) => *cell.borrow_mut() = Some(value),
+                            _ => return Err(invalid("local is not boxed")),
+                        }
+                        self.push(Object::Nil);
+                    }
+                    Op::GetUpvalue => {
+                        let index = operand(code, &mut ip);
+                        let value = closure.upvalues[index].borrow().clone();
+                        let value = match value {
+                            Some(value) => value,
+                            None => lookup_global(&closure, proto.upvalues[index].0)?,
+                        };
+                        self.push(value);
+                    }
+                    Op::SetUpvalue => {
+                        let index = operand(code, &mut ip);
+                        let value = self.pop();
+                        let name = proto.upvalues[index].0;
+                        assign(&closure.upvalues[index], &closure, name, value)?;
+                        self.push(Object::Nil);
+                    }
+                    Op::Closure => {
+                        let inner = proto.protos[operand(code, &mut ip)].clone();
+                        let upvalues = inner
+                            .upvalues
+                            .iter()
+                            .map(|(_, capture)| match *capture {
+                                Capture::Local(index) => match &self.stack[base + index as usize] {
+                                    Slot::Cell(cell) => cell.clone(),
+                                    slot => new_cell(slot.value()),
+                                },
+                                Capture::Upvalue(index) => closure.upvalues[index as usize].clone(),
+                            })
+                            .collect();
+                        let made = Closure {
+                            name: None,
+                            proto: inner,
+                            upvalues,
+                            env: closure.env.clone(),
+                        };
+                        self.push(new_closure(made));
+                    }
+                    Op::Pop => {
+                        self.stack.pop();
+                    }
+                    Op::Jump => ip = operand(code, &mut ip),
+                    Op::JumpIfFalse => {
+                        let target = operand(code, &mut ip);
+                        if !self.pop().is_truthy() {
+                            ip = target;
+                        }
+                    }
+                    Op::JumpIfBound => {
+                        let index = operand(code, &mut ip);
+                        let target = operand(code, &mut ip);
+                        if self.stack[base + index].value().is_some() {
+                            ip = target;
+                        }
+                    }
+                    Op::Call | Op::TailCall => {
+                        let argc = code[ip] as usize;
+                        ip += 1;
+                        if let Some(frame) = self.frames.last_mut() {
+                            frame.ip = ip;
+                        }
+                        match self.call(argc, op == Op::TailCall, env)? {
+                            Called::Value => {}
+                            Called::Frame => continue 'frames,
+                            Called::Exit(exit) => return Ok(exit),
+                        }
+                    }
+                    Op::Return => {
+                        let value = self.pop();
+                        self.stack.truncate(base - 1);
+                        self.frames.pop();
+                        if self.frames.is_empty() {
+                            return Ok(Exit::Return(value));
+                        }
+                        self.push(value);
+                        continue 'frames;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls the procedure below the top `argc` values. Natives run on the
+    /// spot and closures get a frame of their own; anything else is handed
+    /// to the evaluator.
+    fn call(&mut self, argc: usize, tail: bool, env: &EnvRef) -> Result<Called, Object> {
+        let callee_slot = self.stack.len() - argc - 1;
+        let callee = self.stack[callee_slot].value().unwrap_or(Object::Nil);
+
+        match callee {
+            Object::Callable(Function::Compiled(closure)) => {
+                let base = if tail {
+                    let frame = self.frames.pop().unwrap();
+                    self.stack.drain(frame.base - 1..callee_slot);
+                    frame.base
+                } else if self.frames.len() >= evaluator::MAX_FRAMES {
+                    return Err(evaluator::stack_overflow());
+                } else {
+                    callee_slot + 1
+                };
+                self.frames.push(CallFrame {
+                    closure: closure.clone(),
+                    ip: 0,
+                    base,
+                });
+                self.bind(&closure, base)?;
+                Ok(Called::Frame)
+            }
+            Object::Callable(Function::Native(builtin)) => {
+                self.call_native(callee_slot, |args| builtin(args, env.clone()))
+            }
+            Object::Callable(Function::NativeClosure(native)) => {
+                self.call_native(callee_slot, |args| native.call(args, env.clone()))
+            }
+            other => {
+                let args = self.take_args(callee_slot + 1);
+                self.stack.pop();
+                // A lambda replaces the caller in its traces, as it would
+                // in the evaluator.
+                if tail && matches!(other, Object::Callable(Function::Lambda(_))) {
+                    if let Some(frame) = self.frames.pop() {
+                        self.stack.truncate(frame.base - 1);
+                    }
+                }
+                Ok(Called::Exit(Exit::Apply(other, args)))
+            }
+        }
+    }
+
+    /// Calls a native with the arguments above `callee_slot`, collected in
+    /// a buffer that is reused from call to call.
+    fn call_native<F>(&mut self, callee_slot: usize, native: F) -> Result<Called, Object>
+    where
+        F: FnOnce(&[Object]) -> Result<Object, Object>,
+    {
+        let mut args = mem::take(&mut self.args);
+        args.extend(self.stack.drain(callee_slot + 1..).map(Slot::into_value));
+        self.stack.pop();
+        let result = native(&args);
+        args.clear();
+        self.args = args;
+        self.push(result?);
+        Ok(Called::Value)
+    }
+}
+
+/// Compiles `exp` to bytecode and runs it, or, if it uses a form that the
+/// compiler leaves to the evaluator, evaluates it with `evaluator::eval`.
+pub fn eval(exp: Object, env: EnvRef) -> Result<Object, Object> {
+    match bytecode::compile(&exp) {
+        Some(proto) => run(proto, env),
+        None => evaluator::eval(exp, env),
+    }
+}
+
+/// Like `eval`, but records which source line each instruction comes from.
+pub fn eval_with_spans(exp: Object, spans: &SpanTree, env: EnvRef) -> Result<Object, Object> {
+    match bytecode::compile_with_spans(&exp, spans) {
+        Some(proto) => run(proto, env),
+        None => evaluator::eval(exp, env),
+    }
+}
+
+/// Runs a compiled top-level form.
+pub fn run(proto: Rc<Proto>, env: EnvRef) -> Result<Object, Object> {
+    let closure = Closure {
+        name: None,
+        proto,
+        upvalues: Vec::new(),
+        env: env.clone(),
+    };
+    evaluator::apply(&new_closure(closure), &[], env)
+}
+
+#[cfg(test)]
+pub(crate) type Eval = fn(Object, EnvRef) -> Result<Object, Object>;
+
+/// The ways of evaluating a form that tests run every program through, so
+/// the VM is checked against the evaluator.
+#[cfg(test)]
+pub(crate) const ENGINES: [(&str, Eval); 2] = [("evaluator", evaluator::eval), ("vm", eval)];
+
+/// Helpers that the tests of every module share.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::ENGINES;
+    use crate::object::{Environment, Object};
+    use crate::reader;
+
+    /// Runs `$input` through every engine, each in a fresh environment, and
+    /// checks that the last form evaluates to `$expected`.
+    macro_rules! assert_eval {
+        ( $input:expr, $expected:expr ) => {{
+            for (engine, eval) in $crate::vm::ENGINES.iter() {
+                let env = $crate::object::Environment::new();
+
+                let objects = $crate::reader::read($input).unwrap();
+
+                let mut result: Result<$crate::object::Object, $crate::object::Object> =
+                    Ok($crate::object::Object::Nil);
+                for exp in objects.into_iter() {
+                    result = eval(exp, env.clone())
+                }
+
+                assert_eq!(result, $expected, "{}", engine);
+            }
+        }};
+    }
+
+    pub(crate) use assert_eval;
+
+    /// Runs `input` through every engine, checking that they agree.
+    pub(crate) fn eval_all(input: &str) -> Result<Object, Object> {
+        let results: Vec<Result<Object, Object>> = ENGINES
+            .iter()
+            .map(|(_, eval)| {
+                let env = Environment::new();
+                let mut result = Ok(Object::Nil);
+                for exp in reader::read(input).unwrap() {
+                    result = eval(exp, env.clone());
+                }
+                result
+            })
+            .collect();
+        assert_eq!(results[0], results[1]);
+        results[0].clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::assert_eval;
+    use super::*;
+    use crate::object::Environment;
+    use crate::reader;
+
+    #[test]
+    fn test_programs_are_compiled() {
+        let env = Environment::new();
+        let code = "(define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))";
+        eval(reader::read(code).unwrap().remove(0), env.clone()).unwrap();
+        let fib = env.borrow().get("fib");
+        match fib {
+            Object::Callable(Function::Compiled(closure)) => {
+                assert_eq!(closure.display_name(), "fib")
+            }
+            other => panic!("expected a compiled closure, got {}", other),
+        }
+    }
+
+    #[test]
+    fn test_closures_share_variables() {
+        assert_eval!(
+            "(define make-counter
+               (lambda ()
+                 (define n 0)
+                 (list (lambda () (set! n (+ n 1)) n) (lambda () n))))
+             (define counter (make-counter))
+             ((car counter))
+             ((car counter))
+             ((car (cdr counter)))",
+            Ok(Object::Integer(2))
+        );
+    }
+
+    #[test]
+    fn test_tail_calls_run_in_constant_space() {
+        assert_eval!(
+            "(define loop (lambda (n acc) (if (= n 0) acc (loop (- n 1) (+ acc 1)))))
+             (loop 100000 0)",
+            Ok(Object::Integer(100000))
+        );
+    }
+
+    #[test]
+    fn test_mixes_with_evaluated_code() {
+        assert_eval!(
+            "(define safe-sub
+               (lambda (a b) (guard (e (#t 'error)) (if (= b 0) (raise 'oops) (- a b)))))
+             (define twice (lambda (f x) (f (f x))))
+             (list (twice (lambda (x) (safe-sub x 2)) 8) (safe-sub 1 0))",
+            Ok(Object::List(vec![
+                Object::Integer(4),
+                Object::symbol("error")
+            ]))
+        );
+        assert_eval!(
+            "(define f (lambda (x) (+ x (call/cc (lambda (k) (* 10 (k 2)))))))
+             (f 1)",
+            Ok(Object::Integer(3))
+        );
+    }
+
+    #[test]
+    fn test_unbound_locals_are_looked_up_by_name() {
+        assert_eval!(
+            "(define x 'global)
+             (define f (lambda () (define y x) (define x 'local) (list y x)))
+             (f)",
+            Ok(Object::List(vec![
+                Object::symbol("global"),
+                Object::symbol("local")
+            ]))
+        );
+    }
+}